@@ -0,0 +1,439 @@
+use crate::billing::Bill;
+use crate::costing::{StockTotals, stock_totals_at};
+use crate::dates::{end_of_day, start_of_day, today};
+use crate::db::DB;
+use crate::inventory::InventoryItem;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::sql::Datetime;
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AbcMeasure {
+    #[default]
+    Revenue,
+    Margin,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AbcClass {
+    A,
+    B,
+    C,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TurnoverGrouping {
+    #[default]
+    Item,
+    Category,
+}
+
+#[derive(Deserialize)]
+pub struct PeriodQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+#[derive(Deserialize)]
+pub struct AbcQuery {
+    #[serde(default)]
+    pub by: AbcMeasure,
+    // Cumulative share of the total, in percent, where class A and B end
+    pub a_threshold: Option<f64>,
+    pub b_threshold: Option<f64>,
+}
+
+#[derive(Deserialize)]
+pub struct DeadStockQuery {
+    pub days: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct TurnoverQuery {
+    #[serde(default)]
+    pub group_by: TurnoverGrouping,
+}
+
+#[derive(Serialize)]
+pub struct AbcLine {
+    pub item_id: String,
+    pub item_name: String,
+    pub quantity: i32,
+    pub revenue: f64,
+    pub margin: f64,
+    pub share: f64,
+    pub cumulative_share: f64,
+    pub class: AbcClass,
+}
+
+#[derive(Serialize)]
+pub struct DeadStockLine {
+    pub item_id: String,
+    pub item_name: String,
+    pub category: Option<String>,
+    pub quantity: i32,
+    pub stock_value: f64,
+    pub last_sale: Option<DateTime<Utc>>,
+    // None when the item has never been sold
+    pub days_since_last_sale: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct TurnoverLine {
+    // Item id, or the category name when grouped by category
+    pub key: String,
+    pub name: String,
+    pub units_sold: i32,
+    pub cost_of_sales: f64,
+    pub average_inventory_value: f64,
+    pub turnover: f64,
+    // Days current stock lasts at the period's rate of sale
+    pub days_of_stock: Option<f64>,
+}
+
+#[derive(Default)]
+struct TurnoverTotals {
+    name: String,
+    units_sold: i32,
+    cost_of_sales: f64,
+    average_inventory_value: f64,
+    // Current stock at cost
+    on_hand_value: f64,
+}
+
+#[derive(Deserialize)]
+struct LastSale {
+    item_id: String,
+    #[serde(with = "crate::dates::datetime")]
+    last_sale: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct UnitsSold {
+    item_id: String,
+    quantity: i32,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+/// The requested date range, defaulting to the year up to today.
+fn period(query: &PeriodQuery) -> Result<(NaiveDate, NaiveDate), String> {
+    let to = query.to.unwrap_or_else(today);
+    let from = query.from.unwrap_or(to - Duration::days(365));
+    if from > to {
+        return Err("Period start must not be after its end".to_string());
+    }
+    Ok((from, to))
+}
+
+async fn load_items(
+    db: &Surreal<Client>,
+) -> Result<HashMap<String, InventoryItem>, surrealdb::Error> {
+    let res = db.query("SELECT * FROM inventory").await?;
+    Ok(res
+        .get(0)
+        .and_then(|r| r.result::<Vec<InventoryItem>>().ok())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|item| (item.id.clone(), item))
+        .collect())
+}
+
+/// Quantity and value of every item's stock at the end of `as_of`.
+async fn stock_at(
+    db: &Surreal<Client>,
+    as_of: DateTime<Utc>,
+) -> Result<HashMap<String, StockTotals>, surrealdb::Error> {
+    Ok(stock_totals_at(db, as_of)
+        .await?
+        .into_iter()
+        .map(|totals| (totals.item_id.clone(), totals))
+        .collect())
+}
+
+/// Ranks sold items by revenue or margin; A items make up the first 80% of
+/// the total, B the next 15% and C the rest, including items with no sales.
+pub async fn abc_analysis(
+    period_query: web::Query<PeriodQuery>,
+    query: web::Query<AbcQuery>,
+) -> impl Responder {
+    let db = get_db().await;
+    let (from, to) = match period(&period_query) {
+        Ok(period) => period,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    let a_threshold = query.a_threshold.unwrap_or(80.0);
+    let b_threshold = query.b_threshold.unwrap_or(95.0);
+    if !(0.0 < a_threshold && a_threshold <= b_threshold && b_threshold <= 100.0) {
+        return HttpResponse::BadRequest()
+            .body("Thresholds must satisfy 0 < a_threshold <= b_threshold <= 100");
+    }
+
+    let sql = "SELECT * FROM bill WHERE date >= $from AND date <= $to AND status NOT IN ['draft', 'void']";
+    let bills = match db.query(sql).bind(("from", from)).bind(("to", to)).await {
+        Ok(res) => match res.get(0).map(|r| r.result::<Vec<Bill>>()) {
+            Some(Ok(bills)) => bills,
+            Some(Err(e)) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Failed to read bills: {}", e));
+            }
+            None => Vec::new(),
+        },
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to list bills: {}", e));
+        }
+    };
+    let items = match load_items(db).await {
+        Ok(items) => items,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to list items: {}", e));
+        }
+    };
+
+    let mut totals: HashMap<String, (i32, f64, f64)> =
+        items.keys().map(|id| (id.clone(), (0, 0.0, 0.0))).collect();
+    for bill in &bills {
+        for line in &bill.items {
+            // Costs are in the base currency, so revenue must be too
+            let revenue = line.net_amount() * bill.exchange_rate;
+            let entry = totals.entry(line.item_id.clone()).or_insert((0, 0.0, 0.0));
+            entry.0 += line.quantity;
+            entry.1 += revenue;
+            entry.2 += revenue - line.cogs;
+        }
+    }
+    let measure = |revenue: f64, margin: f64| match query.by {
+        AbcMeasure::Revenue => revenue,
+        AbcMeasure::Margin => margin,
+    };
+    let mut ranked: Vec<(String, (i32, f64, f64))> = totals.into_iter().collect();
+    ranked.sort_by(|a, b| {
+        measure(b.1.1, b.1.2)
+            .partial_cmp(&measure(a.1.1, a.1.2))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    // Loss-making items would shrink the total, so only positive values count
+    let total: f64 = ranked
+        .iter()
+        .map(|(_, (_, revenue, margin))| measure(*revenue, *margin).max(0.0))
+        .sum();
+
+    let mut cumulative = 0.0;
+    let mut lines = Vec::new();
+    for (item_id, (quantity, revenue, margin)) in ranked {
+        let value = measure(revenue, margin).max(0.0);
+        let share = if total > 0.0 {
+            value / total * 100.0
+        } else {
+            0.0
+        };
+        // An item is classed by where its share starts, so the top seller is
+        // always A even when it alone exceeds the threshold
+        let class = if value <= 0.0 {
+            AbcClass::C
+        } else if cumulative < a_threshold {
+            AbcClass::A
+        } else if cumulative < b_threshold {
+            AbcClass::B
+        } else {
+            AbcClass::C
+        };
+        cumulative += share;
+        lines.push(AbcLine {
+            item_name: items
+                .get(&item_id)
+                .map_or_else(String::new, |item| item.name.clone()),
+            item_id,
+            quantity,
+            revenue,
+            margin,
+            share,
+            cumulative_share: cumulative,
+            class,
+        });
+    }
+    HttpResponse::Ok().json(lines)
+}
+
+/// Items with stock on hand and no sale in the last `days` (default 180),
+/// longest idle first.
+pub async fn dead_stock(query: web::Query<DeadStockQuery>) -> impl Responder {
+    let db = get_db().await;
+    let days = query.days.unwrap_or(180);
+    if days < 0 {
+        return HttpResponse::BadRequest().body("Days must not be negative");
+    }
+    let sql = "SELECT item_id, time::max(at) AS last_sale FROM stock_movement WHERE reason = 'sale' GROUP BY item_id";
+    let last_sales: HashMap<String, DateTime<Utc>> = match db.query(sql).await {
+        Ok(res) => match res.get(0).map(|r| r.result::<Vec<LastSale>>()) {
+            Some(Ok(sales)) => sales
+                .into_iter()
+                .map(|sale| (sale.item_id, sale.last_sale))
+                .collect(),
+            Some(Err(e)) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Failed to read sales: {}", e));
+            }
+            None => HashMap::new(),
+        },
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to load sales: {}", e));
+        }
+    };
+    let items = match load_items(db).await {
+        Ok(items) => items,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to list items: {}", e));
+        }
+    };
+
+    let now = Utc::now();
+    let mut lines: Vec<DeadStockLine> = items
+        .into_values()
+        .filter(|item| item.quantity > 0)
+        .filter_map(|item| {
+            let last_sale = last_sales.get(&item.id).copied();
+            let days_since_last_sale = last_sale.map(|at| (now - at).num_days());
+            if days_since_last_sale.is_some_and(|idle| idle < days) {
+                return None;
+            }
+            Some(DeadStockLine {
+                stock_value: item.quantity as f64 * item.average_cost,
+                item_id: item.id,
+                item_name: item.name,
+                category: item.category,
+                quantity: item.quantity,
+                last_sale,
+                days_since_last_sale,
+            })
+        })
+        .collect();
+    // Never sold comes first, then the longest idle
+    lines.sort_by_key(|line| std::cmp::Reverse(line.days_since_last_sale.unwrap_or(i64::MAX)));
+    HttpResponse::Ok().json(lines)
+}
+
+/// Cost of sales over average inventory value for the period, per item or
+/// per category, with how many days the current stock would last.
+pub async fn inventory_turnover(
+    period_query: web::Query<PeriodQuery>,
+    query: web::Query<TurnoverQuery>,
+) -> impl Responder {
+    let db = get_db().await;
+    let (from, to) = match period(&period_query) {
+        Ok(period) => period,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    let period_days = ((to - from).num_days() + 1) as f64;
+
+    let sql = "SELECT item_id, math::sum(quantity) AS quantity FROM stock_movement WHERE reason = 'sale' AND at >= $from AND at <= $to GROUP BY item_id";
+    let units_sold: HashMap<String, i32> = match db
+        .query(sql)
+        .bind(("from", Datetime::from(start_of_day(from))))
+        .bind(("to", Datetime::from(end_of_day(to))))
+        .await
+    {
+        // Sales are stored as negative movements
+        Ok(res) => res
+            .get(0)
+            .and_then(|r| r.result::<Vec<UnitsSold>>().ok())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|sold| (sold.item_id, -sold.quantity))
+            .collect(),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to load sales: {}", e));
+        }
+    };
+    let opening = stock_at(db, end_of_day(from - Duration::days(1))).await;
+    let closing = stock_at(db, end_of_day(to)).await;
+    let (opening, closing) = match (opening, closing) {
+        (Ok(opening), Ok(closing)) => (opening, closing),
+        (Err(e), _) | (_, Err(e)) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to compute stock value: {}", e));
+        }
+    };
+    let items = match load_items(db).await {
+        Ok(items) => items,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to list items: {}", e));
+        }
+    };
+
+    let mut groups: HashMap<String, TurnoverTotals> = HashMap::new();
+    for item in items.values().filter(|item| !item.is_bundle()) {
+        let opening = opening.get(&item.id);
+        let closing = closing.get(&item.id);
+        let opening_value = opening.map_or(0.0, |totals| totals.value);
+        let closing_value = closing.map_or(0.0, |totals| totals.value);
+        let units = opening.map_or(0, |totals| totals.quantity)
+            + closing.map_or(0, |totals| totals.quantity);
+        // Sales are costed at the period's average unit cost
+        let unit_cost = if units > 0 {
+            (opening_value + closing_value) / units as f64
+        } else {
+            item.average_cost
+        };
+        let sold = units_sold.get(&item.id).copied().unwrap_or(0);
+        let (key, name) = match query.group_by {
+            TurnoverGrouping::Item => (item.id.clone(), item.name.clone()),
+            TurnoverGrouping::Category => {
+                let category = item
+                    .category
+                    .clone()
+                    .unwrap_or_else(|| "Uncategorized".to_string());
+                (category.clone(), category)
+            }
+        };
+        let group = groups.entry(key).or_insert_with(|| TurnoverTotals {
+            name,
+            ..Default::default()
+        });
+        group.units_sold += sold;
+        group.cost_of_sales += sold as f64 * unit_cost;
+        group.average_inventory_value += (opening_value + closing_value) / 2.0;
+        group.on_hand_value += item.quantity.max(0) as f64 * unit_cost;
+    }
+
+    let mut lines: Vec<TurnoverLine> = groups
+        .into_iter()
+        .map(|(key, totals)| {
+            let daily_cost_of_sales = totals.cost_of_sales / period_days;
+            TurnoverLine {
+                key,
+                name: totals.name,
+                units_sold: totals.units_sold,
+                cost_of_sales: totals.cost_of_sales,
+                average_inventory_value: totals.average_inventory_value,
+                turnover: if totals.average_inventory_value > 0.0 {
+                    totals.cost_of_sales / totals.average_inventory_value
+                } else {
+                    0.0
+                },
+                days_of_stock: (daily_cost_of_sales > 0.0)
+                    .then(|| totals.on_hand_value / daily_cost_of_sales),
+            }
+        })
+        .collect();
+    lines.sort_by(|a, b| {
+        b.turnover
+            .partial_cmp(&a.turnover)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    HttpResponse::Ok().json(lines)
+}
@@ -0,0 +1,324 @@
+use crate::costing::{record_issue, record_receipt};
+use crate::db::DB;
+use crate::inventory::{
+    BundleComponent, InventoryItem, adjust_quantity, get_item, get_location_quantity,
+};
+use crate::reservations::{lock_stock, reserved_quantity};
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AssemblyStatus {
+    Open,
+    Completed,
+    Cancelled,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AssemblyComponent {
+    pub item_id: String,
+    pub quantity: i32, // total for the order
+    pub cost: f64,     // filled in when the order is completed
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AssemblyOrder {
+    pub id: String,
+    pub finished_item_id: String,
+    pub quantity: i32,
+    pub components: Vec<AssemblyComponent>,
+    pub location: Option<String>,
+    pub status: AssemblyStatus,
+    pub unit_cost: Option<f64>,
+    #[serde(with = "crate::dates::datetime")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default, with = "crate::dates::datetime::option")]
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateAssemblyOrderRequest {
+    pub finished_item_id: String,
+    pub quantity: i32,
+    // Component quantities needed for one finished unit
+    pub components: Vec<BundleComponent>,
+    pub location: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AssemblyOrderActionRequest {
+    pub assembly_order_id: String,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+async fn get_assembly_order(db: &Surreal<Client>, order_id: &str) -> Option<AssemblyOrder> {
+    let query = "SELECT * FROM type::thing('assembly_order', $id)";
+    let res = db.query(query).bind(("id", order_id)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<AssemblyOrder>>().ok()?;
+    result.into_iter().next()
+}
+
+async fn save_assembly_order(
+    db: &Surreal<Client>,
+    order: &AssemblyOrder,
+) -> Result<(), surrealdb::Error> {
+    let query = "UPDATE type::thing('assembly_order', $id) CONTENT $order";
+    db.query(query)
+        .bind(("id", &order.id))
+        .bind(("order", order))
+        .await?;
+    Ok(())
+}
+
+// Assembly moves plain stock only; tracked items need lot or serial numbers
+fn is_assemblable(item: &InventoryItem) -> bool {
+    !item.is_bundle() && !item.track_lots && !item.track_serials
+}
+
+pub async fn create_assembly_order(req: web::Json<CreateAssemblyOrderRequest>) -> impl Responder {
+    let db = get_db().await;
+    if req.quantity <= 0 {
+        return HttpResponse::BadRequest().body("Quantity must be positive");
+    }
+    if req.components.is_empty() {
+        return HttpResponse::BadRequest().body("Assembly order has no components");
+    }
+    match get_item(db, &req.finished_item_id).await {
+        Some(item) if is_assemblable(&item) => {}
+        Some(_) => {
+            return HttpResponse::BadRequest()
+                .body("Finished item cannot be a bundle or a lot/serial tracked item");
+        }
+        None => return HttpResponse::BadRequest().body("Finished item not found"),
+    }
+    let mut components: Vec<AssemblyComponent> = Vec::new();
+    for component in &req.components {
+        if component.quantity <= 0 || component.item_id == req.finished_item_id {
+            return HttpResponse::BadRequest()
+                .body("Components need a positive quantity and cannot be the finished item");
+        }
+        match get_item(db, &component.item_id).await {
+            Some(item) if is_assemblable(&item) => {}
+            Some(item) => {
+                return HttpResponse::BadRequest().body(format!(
+                    "Component {} cannot be a bundle or a lot/serial tracked item",
+                    item.name
+                ));
+            }
+            None => {
+                return HttpResponse::BadRequest()
+                    .body(format!("Component {} not found", component.item_id));
+            }
+        }
+        let Some(quantity) = component.quantity.checked_mul(req.quantity) else {
+            return HttpResponse::BadRequest()
+                .body(format!("Too many units of component {}", component.item_id));
+        };
+        // The same component listed twice is one requirement
+        match components
+            .iter_mut()
+            .find(|c| c.item_id == component.item_id)
+        {
+            Some(existing) => {
+                let Some(total) = existing.quantity.checked_add(quantity) else {
+                    return HttpResponse::BadRequest()
+                        .body(format!("Too many units of component {}", component.item_id));
+                };
+                existing.quantity = total;
+            }
+            None => components.push(AssemblyComponent {
+                item_id: component.item_id.clone(),
+                quantity,
+                cost: 0.0,
+            }),
+        }
+    }
+    let order = AssemblyOrder {
+        id: uuid::Uuid::new_v4().to_string(),
+        finished_item_id: req.finished_item_id.clone(),
+        quantity: req.quantity,
+        components,
+        location: req.location.clone(),
+        status: AssemblyStatus::Open,
+        unit_cost: None,
+        created_at: Utc::now(),
+        completed_at: None,
+    };
+    if let Err(e) = db
+        .create::<_, AssemblyOrder>("assembly_order")
+        .content(&order)
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to create assembly order: {}", e));
+    }
+    HttpResponse::Ok().json(order)
+}
+
+/// Consumes the components and books the finished goods into stock at the
+/// rolled-up component cost.
+pub async fn complete_assembly_order(req: web::Json<AssemblyOrderActionRequest>) -> impl Responder {
+    let db = get_db().await;
+    let _guard = lock_stock().await;
+    let Some(mut order) = get_assembly_order(db, &req.assembly_order_id).await else {
+        return HttpResponse::NotFound().body("Assembly order not found");
+    };
+    if order.status != AssemblyStatus::Open {
+        return HttpResponse::BadRequest().body("Assembly order is not open");
+    }
+    let Some(finished) = get_item(db, &order.finished_item_id).await else {
+        return HttpResponse::BadRequest().body("Finished item not found");
+    };
+
+    // Totalled per item in case an older order lists a component twice
+    let mut demand: HashMap<&str, i32> = HashMap::new();
+    for component in &order.components {
+        *demand.entry(component.item_id.as_str()).or_insert(0) += component.quantity;
+    }
+    for (item_id, needed) in demand {
+        let Some(item) = get_item(db, item_id).await else {
+            return HttpResponse::BadRequest().body(format!("Component {} not found", item_id));
+        };
+        let reserved = match reserved_quantity(db, &item.id, None).await {
+            Ok(reserved) => reserved,
+            Err(e) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Failed to check reservations: {}", e));
+            }
+        };
+        if item.quantity - reserved < needed {
+            return HttpResponse::Conflict().body(format!(
+                "Only {} units of {} are available",
+                (item.quantity - reserved).max(0),
+                item.name
+            ));
+        }
+        if let Some(location) = order.location.as_deref() {
+            let at_location = get_location_quantity(db, &item.id, location)
+                .await
+                .unwrap_or(0);
+            if at_location < needed {
+                return HttpResponse::Conflict().body(format!(
+                    "Only {} units of {} are at {}",
+                    at_location.max(0),
+                    item.name,
+                    location
+                ));
+            }
+        }
+    }
+    let mut component_items = Vec::new();
+    for component in &order.components {
+        let Some(item) = get_item(db, &component.item_id).await else {
+            return HttpResponse::BadRequest()
+                .body(format!("Component {} not found", component.item_id));
+        };
+        component_items.push(item);
+    }
+
+    let location = order.location.clone();
+    let mut total_cost = 0.0;
+    for (component, item) in order.components.iter_mut().zip(&component_items) {
+        match record_issue(db, item, component.quantity, &order.id).await {
+            Ok(cost) => {
+                component.cost = cost;
+                total_cost += cost;
+            }
+            Err(e) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Failed to cost component: {}", e));
+            }
+        }
+        if let Err(e) = adjust_quantity(
+            db,
+            &item.id,
+            location.as_deref(),
+            -component.quantity,
+            "assembly_consume",
+            &order.id,
+        )
+        .await
+        {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to consume component: {}", e));
+        }
+    }
+
+    let unit_cost = total_cost / order.quantity as f64;
+    if let Err(e) = record_receipt(db, &finished, order.quantity, unit_cost, &order.id).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to cost finished goods: {}", e));
+    }
+    if let Err(e) = adjust_quantity(
+        db,
+        &finished.id,
+        location.as_deref(),
+        order.quantity,
+        "assembly_produce",
+        &order.id,
+    )
+    .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to book finished goods: {}", e));
+    }
+
+    order.status = AssemblyStatus::Completed;
+    order.unit_cost = Some(unit_cost);
+    order.completed_at = Some(Utc::now());
+    if let Err(e) = save_assembly_order(db, &order).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to update assembly order: {}", e));
+    }
+    HttpResponse::Ok().json(order)
+}
+
+pub async fn cancel_assembly_order(req: web::Json<AssemblyOrderActionRequest>) -> impl Responder {
+    let db = get_db().await;
+    // Completion checks the status under the same lock
+    let _guard = lock_stock().await;
+    let Some(mut order) = get_assembly_order(db, &req.assembly_order_id).await else {
+        return HttpResponse::NotFound().body("Assembly order not found");
+    };
+    if order.status != AssemblyStatus::Open {
+        return HttpResponse::BadRequest().body("Assembly order is not open");
+    }
+    order.status = AssemblyStatus::Cancelled;
+    if let Err(e) = save_assembly_order(db, &order).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to update assembly order: {}", e));
+    }
+    HttpResponse::Ok().json(order)
+}
+
+pub async fn list_assembly_orders() -> impl Responder {
+    let db = get_db().await;
+    let query = "SELECT * FROM assembly_order ORDER BY created_at DESC";
+    match db.query(query).await {
+        Ok(res) => {
+            let orders = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<AssemblyOrder>>().ok())
+                .unwrap_or_default();
+            HttpResponse::Ok().json(orders)
+        }
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to list assembly orders: {}", e)),
+    }
+}
+
+pub async fn get_assembly_order_by_id(path: web::Path<String>) -> impl Responder {
+    let db = get_db().await;
+    match get_assembly_order(db, &path.into_inner()).await {
+        Some(order) => HttpResponse::Ok().json(order),
+        None => HttpResponse::NotFound().body("Assembly order not found"),
+    }
+}
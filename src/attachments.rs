@@ -0,0 +1,362 @@
+use crate::db::DB;
+use crate::storage::get_storage;
+use actix_multipart::Multipart;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+const MAX_FILE_SIZE: usize = 20 * 1024 * 1024;
+const THUMBNAIL_SIZE: u32 = 256;
+// Types a browser may show in place; anything else is always downloaded so
+// an uploaded HTML or SVG file can't run in the app's origin
+const INLINE_TYPES: [&str; 5] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "application/pdf",
+];
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OwnerType {
+    Item,   // product images, datasheets, certificates
+    Bill,   // signed delivery notes
+    Ledger, // receipts
+}
+
+impl OwnerType {
+    fn table(self) -> &'static str {
+        match self {
+            OwnerType::Item => "inventory",
+            OwnerType::Bill => "bill",
+            OwnerType::Ledger => "ledger",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: String,
+    pub owner_type: OwnerType,
+    pub owner_id: String,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: usize,
+    pub storage_key: String,
+    pub thumbnail_key: Option<String>,
+    #[serde(with = "crate::dates::datetime")]
+    pub uploaded_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct AttachmentOwnerQuery {
+    pub owner_type: OwnerType,
+    pub owner_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAttachmentRequest {
+    pub attachment_id: String,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+async fn get_attachment(db: &Surreal<Client>, attachment_id: &str) -> Option<Attachment> {
+    let query = "SELECT * FROM type::thing('attachment', $id)";
+    let res = db.query(query).bind(("id", attachment_id)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<Attachment>>().ok()?;
+    result.into_iter().next()
+}
+
+async fn owner_exists(db: &Surreal<Client>, owner_type: OwnerType, owner_id: &str) -> bool {
+    let query = "SELECT * FROM type::thing($table, $id)";
+    let Ok(res) = db
+        .query(query)
+        .bind(("table", owner_type.table()))
+        .bind(("id", owner_id))
+        .await
+    else {
+        return false;
+    };
+    res.get(0)
+        .and_then(|r| r.result::<Vec<serde_json::Value>>().ok())
+        .is_some_and(|rows| !rows.is_empty())
+}
+
+async fn remove_attachment_record(
+    db: &Surreal<Client>,
+    attachment: &Attachment,
+) -> Result<(), surrealdb::Error> {
+    let query = "UPDATE type::thing($table, $owner_id) SET attachments -= $attachment_id; DELETE type::thing('attachment', $attachment_id);";
+    db.query(query)
+        .bind(("table", attachment.owner_type.table()))
+        .bind(("owner_id", &attachment.owner_id))
+        .bind(("attachment_id", &attachment.id))
+        .await?;
+    Ok(())
+}
+
+fn sanitize_file_name(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    match cleaned.trim_matches('.') {
+        "" => "file".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+fn make_thumbnail(data: &[u8]) -> Option<Vec<u8>> {
+    let image = image::load_from_memory(data).ok()?;
+    let mut out = Vec::new();
+    image
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+        .ok()?;
+    Some(out)
+}
+
+/// Stores and records the files of an upload. Every storage key written and
+/// attachment saved is pushed as it happens so a failure can undo them.
+async fn store_upload(
+    db: &Surreal<Client>,
+    owner: &AttachmentOwnerQuery,
+    mut payload: Multipart,
+    stored_keys: &mut Vec<String>,
+    saved: &mut Vec<Attachment>,
+) -> Result<(), HttpResponse> {
+    let storage = get_storage();
+
+    let mut uploaded = Vec::new();
+    loop {
+        let mut field = match payload.try_next().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return Err(HttpResponse::BadRequest().body(format!("Invalid upload: {}", e))),
+        };
+        let file_name = sanitize_file_name(
+            field
+                .content_disposition()
+                .and_then(|cd| cd.get_filename())
+                .unwrap_or("file"),
+        );
+        let content_type = field.content_type().map_or_else(
+            || "application/octet-stream".to_string(),
+            |mime| mime.to_string(),
+        );
+        let mut data = Vec::new();
+        loop {
+            match field.try_next().await {
+                Ok(Some(chunk)) => {
+                    if data.len() + chunk.len() > MAX_FILE_SIZE {
+                        return Err(HttpResponse::PayloadTooLarge()
+                            .body(format!("{} is larger than 20 MB", file_name)));
+                    }
+                    data.extend_from_slice(&chunk);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    return Err(HttpResponse::BadRequest().body(format!("Invalid upload: {}", e)));
+                }
+            }
+        }
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let storage_key = format!("{}/{}", id, file_name);
+        if let Err(e) = storage.put(&storage_key, &data, &content_type).await {
+            return Err(HttpResponse::InternalServerError()
+                .body(format!("Failed to store {}: {}", file_name, e)));
+        }
+        stored_keys.push(storage_key.clone());
+        let size = data.len();
+        let mut thumbnail_key = None;
+        if content_type.starts_with("image/") {
+            let thumbnail = web::block(move || make_thumbnail(&data))
+                .await
+                .ok()
+                .flatten();
+            if let Some(thumbnail) = thumbnail {
+                let key = format!("{}/thumbnail.png", id);
+                if storage.put(&key, &thumbnail, "image/png").await.is_ok() {
+                    stored_keys.push(key.clone());
+                    thumbnail_key = Some(key);
+                }
+            }
+        }
+        uploaded.push((
+            id,
+            file_name,
+            content_type,
+            size,
+            storage_key,
+            thumbnail_key,
+        ));
+    }
+    if uploaded.is_empty() {
+        return Err(HttpResponse::BadRequest().body("No files in upload"));
+    }
+
+    for (id, file_name, content_type, size, storage_key, thumbnail_key) in uploaded {
+        let attachment = Attachment {
+            id,
+            owner_type: owner.owner_type,
+            owner_id: owner.owner_id.clone(),
+            file_name,
+            content_type,
+            size,
+            storage_key,
+            thumbnail_key,
+            uploaded_at: Utc::now(),
+        };
+        if let Err(e) = db
+            .create::<_, Attachment>("attachment")
+            .content(&attachment)
+            .await
+        {
+            return Err(HttpResponse::InternalServerError()
+                .body(format!("Failed to save attachment: {}", e)));
+        }
+        saved.push(attachment);
+        let attachment = &saved[saved.len() - 1];
+        let query = "UPDATE type::thing($table, $owner_id) SET attachments += $attachment_id";
+        if let Err(e) = db
+            .query(query)
+            .bind(("table", owner.owner_type.table()))
+            .bind(("owner_id", &owner.owner_id))
+            .bind(("attachment_id", &attachment.id))
+            .await
+        {
+            return Err(HttpResponse::InternalServerError()
+                .body(format!("Failed to link attachment: {}", e)));
+        }
+    }
+    Ok(())
+}
+
+/// Stores every file in a multipart upload against the given item, bill or
+/// ledger entry, with a PNG thumbnail for images. An upload is kept whole or
+/// not at all.
+pub async fn upload_attachments(
+    owner: web::Query<AttachmentOwnerQuery>,
+    payload: Multipart,
+) -> impl Responder {
+    let db = get_db().await;
+    if !owner_exists(db, owner.owner_type, &owner.owner_id).await {
+        return HttpResponse::NotFound().body("Attachment owner not found");
+    }
+    let mut stored_keys = Vec::new();
+    let mut saved = Vec::new();
+    if let Err(response) = store_upload(db, &owner, payload, &mut stored_keys, &mut saved).await {
+        for attachment in &saved {
+            if let Err(e) = remove_attachment_record(db, attachment).await {
+                eprintln!("Failed to remove attachment {}: {e}", attachment.id);
+            }
+        }
+        let storage = get_storage();
+        for key in &stored_keys {
+            if let Err(e) = storage.delete(key).await {
+                eprintln!("Failed to delete attachment file {key}: {e}");
+            }
+        }
+        return response;
+    }
+    HttpResponse::Ok().json(saved)
+}
+
+pub async fn list_attachments(owner: web::Query<AttachmentOwnerQuery>) -> impl Responder {
+    let db = get_db().await;
+    let query = "SELECT * FROM attachment WHERE owner_type = $owner_type AND owner_id = $owner_id ORDER BY uploaded_at ASC";
+    match db
+        .query(query)
+        .bind(("owner_type", owner.owner_type))
+        .bind(("owner_id", &owner.owner_id))
+        .await
+    {
+        Ok(res) => {
+            let attachments = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<Attachment>>().ok())
+                .unwrap_or_default();
+            HttpResponse::Ok().json(attachments)
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list attachments: {}", e))
+        }
+    }
+}
+
+pub async fn download_attachment(path: web::Path<String>) -> impl Responder {
+    let db = get_db().await;
+    let Some(attachment) = get_attachment(db, &path.into_inner()).await else {
+        return HttpResponse::NotFound().body("Attachment not found");
+    };
+    let (content_type, disposition) = if INLINE_TYPES.contains(&attachment.content_type.as_str()) {
+        (attachment.content_type.as_str(), "inline")
+    } else {
+        ("application/octet-stream", "attachment")
+    };
+    match get_storage().get(&attachment.storage_key).await {
+        Ok(data) => HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header(("X-Content-Type-Options", "nosniff"))
+            .insert_header((
+                "Content-Disposition",
+                format!("{}; filename=\"{}\"", disposition, attachment.file_name),
+            ))
+            .body(data),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to read attachment: {}", e))
+        }
+    }
+}
+
+pub async fn download_thumbnail(path: web::Path<String>) -> impl Responder {
+    let db = get_db().await;
+    let Some(key) = get_attachment(db, &path.into_inner())
+        .await
+        .and_then(|attachment| attachment.thumbnail_key)
+    else {
+        return HttpResponse::NotFound().body("Thumbnail not found");
+    };
+    match get_storage().get(&key).await {
+        Ok(data) => HttpResponse::Ok().content_type("image/png").body(data),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to read thumbnail: {}", e))
+        }
+    }
+}
+
+pub async fn delete_attachment(req: web::Json<DeleteAttachmentRequest>) -> impl Responder {
+    let db = get_db().await;
+    let Some(attachment) = get_attachment(db, &req.attachment_id).await else {
+        return HttpResponse::NotFound().body("Attachment not found");
+    };
+    if let Err(e) = remove_attachment_record(db, &attachment).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to delete attachment: {}", e));
+    }
+    let storage = get_storage();
+    // The record is gone, so a leftover file is only wasted space
+    if let Err(e) = storage.delete(&attachment.storage_key).await {
+        eprintln!("Failed to delete attachment file: {e}");
+    }
+    if let Some(key) = &attachment.thumbnail_key {
+        let _ = storage.delete(key).await;
+    }
+    HttpResponse::Ok().body("Attachment deleted")
+}
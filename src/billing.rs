@@ -1,67 +1,1459 @@
+use crate::costing::{record_issue, record_receipt};
+use crate::currency::{base_currency, normalize_currency, rate_on, unit_rate};
+use crate::dates::today;
 use crate::db::DB;
+use crate::inventory::{BundleComponent, adjust_quantity, book_damaged, get_item};
+use crate::invoice_series::{create_numbered_bill, find_series};
+use crate::listing::{ListParams, ListQuery, Page};
+use crate::lots::{LotAllocation, consume_lots, plan_fefo, return_to_lots};
+use crate::pdf::render_text_pdf;
+use crate::pricing::resolve_price;
+use crate::promotions::{
+    AppliedDiscount, Discount, active_promotions, best_promotion, claim_coupon, find_coupon,
+    release_coupon, round_cents,
+};
+use crate::reservations::{
+    consume_reservations, lock_stock, release_reservations, reserved_quantity, restore_reservations,
+};
+use crate::serials::{SerialStatus, get_serial, mark_sold, return_serials, validate_for_sale};
 use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use surrealdb::Surreal;
 use surrealdb::engine::remote::ws::Client;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BillStatus {
+    Draft, // editable, no stock taken and no invoice number yet
+    #[default]
+    Issued,
+    Void,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Bill {
     pub id: String,
+    #[serde(default)]
+    pub status: BillStatus,
+    // Goes up by one with every amendment
+    #[serde(default = "first_version")]
+    pub version: u32,
     pub items: Vec<BillItem>,
+    // Includes tax
     pub total_amount: f64,
+    #[serde(default)]
+    pub tax_amount: f64,
+    // Requested manual discount on the whole bill
+    #[serde(default)]
+    pub discount: Option<Discount>,
+    #[serde(default)]
+    pub coupon_code: Option<String>,
+    #[serde(default)]
+    pub discount_amount: f64,
+    // Every promotion, coupon and manual discount applied, itemised
+    #[serde(default)]
+    pub discounts: Vec<AppliedDiscount>,
+    // Every amount on the bill is in this currency
+    #[serde(default = "base_currency")]
+    pub currency: String,
+    // Base-currency units per unit of `currency` on the bill date
+    #[serde(default = "unit_rate")]
+    pub exchange_rate: f64,
+    // Totals in the base currency; None on bills from before currencies
+    // were tracked, whose amounts are already in the base currency
+    #[serde(default)]
+    pub base_total_amount: Option<f64>,
+    #[serde(default)]
+    pub base_tax_amount: Option<f64>,
+    // Received so far, in the bill's currency, less anything refunded
+    #[serde(default)]
+    pub amount_paid: f64,
+    // Total of the credit notes raised against the bill; what is still owed
+    // is `total_amount - credited_amount - amount_paid`
+    #[serde(default)]
+    pub credited_amount: f64,
     pub customer_name: String,
-    pub date: String,
+    // Business date the bill is dated
+    pub date: NaiveDate,
+    // When the bill was first saved; None on bills from before it was recorded
+    #[serde(default, with = "crate::dates::datetime::option")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub branch: Option<String>,
+    // Where the stock was taken from
+    #[serde(default)]
+    pub location: Option<String>,
+    #[serde(default)]
+    pub reservation_reference: Option<String>,
+    #[serde(default)]
+    pub void_reason: Option<String>,
+    // Set when the bill invoices goods already shipped on delivery notes
+    #[serde(default)]
+    pub delivery_note_ids: Vec<String>,
+    // Set when the bill was generated from a recurring template
+    #[serde(default)]
+    pub recurring_bill_id: Option<String>,
+    // Legal invoice number, allocated from the series when the bill is stored
+    #[serde(default)]
+    pub invoice_series_id: Option<String>,
+    #[serde(default)]
+    pub invoice_number: Option<String>,
+    #[serde(default)]
+    pub attachments: Vec<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct BillItem {
     pub item_id: String,
     pub quantity: i32,
+    // Unit price, resolved by the server from price lists
+    #[serde(default)]
     pub price: f64,
+    #[serde(default)]
+    pub price_list: Option<String>,
+    #[serde(default)]
+    pub price_rule_id: Option<String>,
+    // Tax on the line, filled in by the server from the item's rate
+    #[serde(default)]
+    pub tax_rate: f64,
+    #[serde(default)]
+    pub tax_amount: f64,
+    // Requested manual discount on the line
+    #[serde(default)]
+    pub discount: Option<Discount>,
+    // Everything taken off the line, including its share of bill-level
+    // discounts, filled in by the server
+    #[serde(default)]
+    pub discount_amount: f64,
+    #[serde(default)]
+    pub lots: Vec<LotAllocation>,
+    #[serde(default)]
+    pub serials: Vec<String>,
+    // Component stock taken when the line is a bundle, filled in by the server
+    #[serde(default)]
+    pub components: Vec<BundleComponent>,
+    // Cost of goods sold, filled in by the server
+    #[serde(default)]
+    pub cogs: f64,
+}
+
+impl Bill {
+    /// A new unpriced draft; `price_lines` fills in the amounts.
+    pub fn draft(customer_name: String, date: NaiveDate, items: Vec<BillItem>) -> Bill {
+        Bill {
+            id: uuid::Uuid::new_v4().to_string(),
+            status: BillStatus::Draft,
+            version: first_version(),
+            items,
+            total_amount: 0.0,
+            tax_amount: 0.0,
+            discount: None,
+            coupon_code: None,
+            discount_amount: 0.0,
+            discounts: Vec::new(),
+            currency: base_currency(),
+            exchange_rate: 1.0,
+            base_total_amount: None,
+            base_tax_amount: None,
+            amount_paid: 0.0,
+            credited_amount: 0.0,
+            customer_name,
+            date,
+            created_at: Some(Utc::now()),
+            branch: None,
+            location: None,
+            reservation_reference: None,
+            void_reason: None,
+            delivery_note_ids: Vec::new(),
+            recurring_bill_id: None,
+            invoice_series_id: None,
+            invoice_number: None,
+            attachments: Vec::new(),
+        }
+    }
+
+    /// Fills in the base-currency totals at the bill's exchange rate.
+    pub fn convert_totals(&mut self) {
+        self.base_total_amount = Some(round_cents(self.total_amount * self.exchange_rate));
+        self.base_tax_amount = Some(round_cents(self.tax_amount * self.exchange_rate));
+    }
+}
+
+impl BillItem {
+    /// Line amount after discounts, before tax.
+    pub fn net_amount(&self) -> f64 {
+        self.price * self.quantity as f64 - self.discount_amount
+    }
 }
 
 #[derive(Deserialize)]
 pub struct CreateBillRequest {
     pub items: Vec<BillItem>,
     pub customer_name: String,
-    pub date: String,
+    // Defaults to today
+    pub date: Option<NaiveDate>,
+    pub location: Option<String>,
+    // Picks the invoice series; an explicit series wins over the branch's own
+    pub branch: Option<String>,
+    pub invoice_series_id: Option<String>,
+    // Reference the stock for this bill was reserved under, if any
+    pub reservation_reference: Option<String>,
+    pub discount: Option<Discount>,
+    pub coupon_code: Option<String>,
+    // Defaults to the base currency
+    pub currency: Option<String>,
+    // Save as an editable draft instead of issuing straight away
+    #[serde(default)]
+    pub draft: bool,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateDraftRequest {
+    pub bill_id: String,
+    pub items: Vec<BillItem>,
+    pub customer_name: String,
+    pub date: NaiveDate,
+    pub location: Option<String>,
+    pub branch: Option<String>,
+    pub invoice_series_id: Option<String>,
+    pub reservation_reference: Option<String>,
+    pub discount: Option<Discount>,
+    pub coupon_code: Option<String>,
+    pub currency: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct IssueBillRequest {
+    pub bill_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct VoidBillRequest {
+    pub bill_id: String,
+    pub reason: String,
+}
+
+#[derive(Deserialize)]
+pub struct AmendBillRequest {
+    pub bill_id: String,
+    pub items: Vec<BillItem>,
+    pub customer_name: Option<String>,
+    pub date: Option<NaiveDate>,
+    pub reason: String,
+}
+
+/// An issued bill as it stood before an amendment replaced it.
+#[derive(Serialize, Deserialize)]
+pub struct BillVersion {
+    pub id: String,
+    pub bill_id: String,
+    pub version: u32,
+    pub reason: String,
+    #[serde(with = "crate::dates::datetime")]
+    pub replaced_at: DateTime<Utc>,
+    pub bill: Bill,
+}
+
+#[derive(Deserialize)]
+pub struct BillFilters {
+    pub customer_name: Option<String>,
+    pub status: Option<BillStatus>,
+    pub currency: Option<String>,
+    pub invoice_number: Option<String>,
+    pub branch: Option<String>,
+    pub date_from: Option<NaiveDate>,
+    pub date_to: Option<NaiveDate>,
+}
+
+#[derive(Serialize)]
+pub struct BundleSales {
+    pub item_id: String,
+    pub item_name: String,
+    pub quantity: i32,
+    pub revenue: f64,
+}
+
+#[derive(Serialize)]
+pub struct ComponentConsumption {
+    pub item_id: String,
+    pub item_name: String,
+    pub quantity: i32,
+}
+
+#[derive(Serialize)]
+pub struct BundleReport {
+    pub bundle_sales: Vec<BundleSales>,
+    pub component_consumption: Vec<ComponentConsumption>,
+}
+
+fn first_version() -> u32 {
+    1
 }
 
 async fn get_db() -> &'static Surreal<Client> {
     DB.get().expect("DB not initialized")
 }
 
+pub async fn get_bill(db: &Surreal<Client>, bill_id: &str) -> Option<Bill> {
+    let query = "SELECT * FROM type::thing('bill', $id)";
+    let res = db.query(query).bind(("id", bill_id)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<Bill>>().ok()?;
+    result.into_iter().next()
+}
+
+/// Tax on a net amount, rounded to cents.
+pub fn line_tax(amount: f64, tax_rate: f64) -> f64 {
+    (amount * tax_rate).round() / 100.0
+}
+
+/// Units of each stocked item the bill lines take, with bundles broken down
+/// into their components.
+pub fn stock_demand(lines: &[BillItem]) -> HashMap<String, i32> {
+    let mut demand = HashMap::new();
+    for line in lines {
+        if line.components.is_empty() {
+            *demand.entry(line.item_id.clone()).or_insert(0) += line.quantity;
+        }
+        for component in &line.components {
+            *demand.entry(component.item_id.clone()).or_insert(0) += component.quantity;
+        }
+    }
+    demand
+}
+
+/// Prices every line for the bill's customer and date, applies promotions,
+/// coupons and manual discounts, fills in tax and bundle components and
+/// totals the bill. Touches no stock, so quotations use it too.
+pub async fn price_lines(db: &Surreal<Client>, bill: &mut Bill) -> Result<(), HttpResponse> {
+    // Price lists are in the base currency
+    bill.exchange_rate = match rate_on(db, &bill.currency, bill.date).await {
+        Ok(Some(rate)) => rate,
+        Ok(None) => {
+            return Err(HttpResponse::BadRequest().body(format!(
+                "No exchange rate for {} on {}",
+                bill.currency, bill.date
+            )));
+        }
+        Err(e) => {
+            return Err(HttpResponse::InternalServerError()
+                .body(format!("Failed to load exchange rate: {}", e)));
+        }
+    };
+    price_lines_at_rate(db, bill).await
+}
+
+/// The same as `price_lines`, keeping the bill's exchange rate.
+async fn price_lines_at_rate(db: &Surreal<Client>, bill: &mut Bill) -> Result<(), HttpResponse> {
+    let bill_date = bill.date;
+    if bill.items.is_empty() {
+        return Err(HttpResponse::BadRequest().body("A bill needs at least one line"));
+    }
+    let promotions = match active_promotions(db, bill_date).await {
+        Ok(promotions) => promotions,
+        Err(e) => {
+            return Err(HttpResponse::InternalServerError()
+                .body(format!("Failed to load promotions: {}", e)));
+        }
+    };
+    let mut discounts = Vec::new();
+    for (index, line) in bill.items.iter_mut().enumerate() {
+        if line.quantity <= 0 {
+            return Err(HttpResponse::BadRequest().body("Quantity must be positive"));
+        }
+        let Some(item) = get_item(db, &line.item_id).await else {
+            return Err(HttpResponse::BadRequest().body(format!("Item {} not found", line.item_id)));
+        };
+        match resolve_price(db, &item, line.quantity, &bill.customer_name, bill_date).await {
+            Ok(resolved) => {
+                line.price = round_cents(resolved.price / bill.exchange_rate);
+                line.price_list = resolved.price_list;
+                line.price_rule_id = resolved.price_rule_id;
+            }
+            Err(e) => {
+                return Err(HttpResponse::InternalServerError()
+                    .body(format!("Failed to resolve price: {}", e)));
+            }
+        }
+        if !item.track_serials && !line.serials.is_empty() {
+            return Err(HttpResponse::BadRequest()
+                .body(format!("Item {} is not serial-tracked", item.name)));
+        }
+
+        // Automatic promotion first, then any manual discount on what's left
+        line.discount_amount = 0.0;
+        if let Some((promotion, amount)) =
+            best_promotion(&promotions, &item, line.quantity, line.price)
+        {
+            line.discount_amount += amount;
+            discounts.push(AppliedDiscount {
+                description: promotion.name.clone(),
+                promotion_id: Some(promotion.id.clone()),
+                coupon_code: None,
+                line_index: Some(index),
+                amount,
+            });
+        }
+        if let Some(discount) = &line.discount {
+            if let Err(msg) = discount.validate() {
+                return Err(HttpResponse::BadRequest().body(msg));
+            }
+            let amount = discount.amount_off(line.net_amount());
+            line.discount_amount += amount;
+            discounts.push(AppliedDiscount {
+                description: "Line discount".to_string(),
+                promotion_id: None,
+                coupon_code: None,
+                line_index: Some(index),
+                amount,
+            });
+        }
+
+        line.lots = Vec::new();
+        line.cogs = 0.0;
+        line.components = item
+            .components
+            .iter()
+            .map(|component| BundleComponent {
+                item_id: component.item_id.clone(),
+                quantity: component.quantity * line.quantity,
+            })
+            .collect();
+        line.tax_rate = item.tax_rate;
+    }
+
+    // Bill-level discounts come off the subtotal after line discounts
+    let subtotal: f64 = bill.items.iter().map(BillItem::net_amount).sum();
+    let mut bill_discount = 0.0;
+    if let Some(discount) = &bill.discount {
+        if let Err(msg) = discount.validate() {
+            return Err(HttpResponse::BadRequest().body(msg));
+        }
+        let amount = discount.amount_off(subtotal);
+        bill_discount += amount;
+        discounts.push(AppliedDiscount {
+            description: "Bill discount".to_string(),
+            promotion_id: None,
+            coupon_code: None,
+            line_index: None,
+            amount,
+        });
+    }
+    if let Some(code) = &bill.coupon_code {
+        // An issued bill being amended has already used its coupon
+        let counted = bill.status == BillStatus::Issued;
+        // Coupon thresholds and fixed amounts are in the base currency
+        let base_subtotal = subtotal * bill.exchange_rate;
+        let coupon = match find_coupon(db, code, bill_date, base_subtotal, counted).await {
+            Ok(coupon) => coupon,
+            Err(msg) => return Err(HttpResponse::BadRequest().body(msg)),
+        };
+        let amount = round_cents(
+            coupon
+                .discount
+                .amount_off((subtotal - bill_discount) * bill.exchange_rate)
+                / bill.exchange_rate,
+        );
+        bill_discount += amount;
+        discounts.push(AppliedDiscount {
+            description: format!("Coupon {}", coupon.code),
+            promotion_id: None,
+            coupon_code: Some(coupon.code.clone()),
+            line_index: None,
+            amount,
+        });
+        bill.coupon_code = Some(coupon.code);
+    }
+    // Spread bill-level discounts over the lines so tax is charged on what is paid
+    if bill_discount > 0.0 && subtotal > 0.0 {
+        let mut remaining = bill_discount;
+        let last = bill.items.len() - 1;
+        for (index, line) in bill.items.iter_mut().enumerate() {
+            let share = if index == last {
+                remaining
+            } else {
+                round_cents(bill_discount * line.net_amount() / subtotal)
+            };
+            line.discount_amount += share;
+            remaining -= share;
+        }
+    }
+
+    for line in bill.items.iter_mut() {
+        line.tax_amount = line_tax(line.net_amount(), line.tax_rate);
+    }
+    bill.discounts = discounts;
+    bill.discount_amount = bill.discounts.iter().map(|d| d.amount).sum();
+    bill.tax_amount = bill.items.iter().map(|item| item.tax_amount).sum();
+    bill.total_amount = subtotal - bill_discount + bill.tax_amount;
+    bill.convert_totals();
+    Ok(())
+}
+
+/// Picks lots, checks serials and availability, then takes the stock for
+/// every line and books its cost. The caller must hold the stock lock.
+pub async fn issue_stock(db: &Surreal<Client>, bill: &mut Bill) -> Result<(), HttpResponse> {
+    // Work out lot picks and check serials before touching any stock
+    let mut stock_items = Vec::new();
+    for line in bill.items.iter_mut() {
+        let Some(item) = get_item(db, &line.item_id).await else {
+            return Err(HttpResponse::BadRequest().body(format!("Item {} not found", line.item_id)));
+        };
+        line.lots = Vec::new();
+        if item.track_lots {
+            match plan_fefo(db, &item.id, line.quantity).await {
+                Ok(Some(allocations)) => line.lots = allocations,
+                Ok(None) => {
+                    return Err(HttpResponse::BadRequest().body(format!(
+                        "Not enough unexpired lot stock for item {}",
+                        item.name
+                    )));
+                }
+                Err(e) => {
+                    return Err(HttpResponse::InternalServerError()
+                        .body(format!("Failed to allocate lots: {}", e)));
+                }
+            }
+        }
+        if item.track_serials {
+            if let Err(msg) = validate_for_sale(db, &item.id, line.quantity, &line.serials).await {
+                return Err(HttpResponse::BadRequest().body(msg));
+            }
+        }
+        stock_items.push(item);
+    }
+
+    // Other people's reservations are off limits; our own are what we're billing
+    for (item_id, requested) in stock_demand(&bill.items) {
+        let Some(item) = get_item(db, &item_id).await else {
+            return Err(HttpResponse::BadRequest().body(format!("Item {} not found", item_id)));
+        };
+        let reserved =
+            match reserved_quantity(db, &item.id, bill.reservation_reference.as_deref()).await {
+                Ok(reserved) => reserved,
+                Err(e) => {
+                    return Err(HttpResponse::InternalServerError()
+                        .body(format!("Failed to check reservations: {}", e)));
+                }
+            };
+        if item.quantity - reserved < requested {
+            return Err(HttpResponse::Conflict().body(format!(
+                "Only {} units of {} are available",
+                (item.quantity - reserved).max(0),
+                item.name
+            )));
+        }
+    }
+
+    let location = bill.location.as_deref();
+    for (line, item) in bill.items.iter_mut().zip(&stock_items) {
+        if item.is_bundle() {
+            line.cogs = 0.0;
+            for component in &line.components {
+                let Some(component_item) = get_item(db, &component.item_id).await else {
+                    continue;
+                };
+                match record_issue(db, &component_item, component.quantity, &bill.id).await {
+                    Ok(cogs) => line.cogs += cogs,
+                    Err(e) => {
+                        return Err(HttpResponse::InternalServerError()
+                            .body(format!("Failed to cost bundle component: {}", e)));
+                    }
+                }
+                if let Err(e) = adjust_quantity(
+                    db,
+                    &component.item_id,
+                    location,
+                    -component.quantity,
+                    "sale",
+                    &bill.id,
+                )
+                .await
+                {
+                    return Err(HttpResponse::InternalServerError()
+                        .body(format!("Failed to update component quantity: {}", e)));
+                }
+            }
+            continue;
+        }
+        match record_issue(db, item, line.quantity, &bill.id).await {
+            Ok(cogs) => line.cogs = cogs,
+            Err(e) => {
+                return Err(HttpResponse::InternalServerError()
+                    .body(format!("Failed to cost bill line: {}", e)));
+            }
+        }
+        if let Err(e) = consume_lots(db, &line.lots).await {
+            return Err(
+                HttpResponse::InternalServerError().body(format!("Failed to update lots: {}", e))
+            );
+        }
+        if let Err(e) = mark_sold(db, &line.serials, &bill.id, &bill.customer_name).await {
+            return Err(HttpResponse::InternalServerError()
+                .body(format!("Failed to update serials: {}", e)));
+        }
+        if let Err(e) = adjust_quantity(
+            db,
+            &line.item_id,
+            location,
+            -line.quantity,
+            "sale",
+            &bill.id,
+        )
+        .await
+        {
+            return Err(HttpResponse::InternalServerError()
+                .body(format!("Failed to update item quantity: {}", e)));
+        }
+    }
+
+    if let Some(reference) = &bill.reservation_reference {
+        if let Err(e) = consume_reservations(db, reference).await {
+            return Err(HttpResponse::InternalServerError()
+                .body(format!("Failed to consume reservations: {}", e)));
+        }
+    }
+    Ok(())
+}
+
+/// Takes `quantity` units of a line sold on `bill_id` back into stock at what
+/// they cost when sold. Restocked units go back into their lots and serials
+/// become sellable again; otherwise the units are booked as damaged, outside
+/// sellable stock, and serials stay flagged as returned. Serials no longer
+/// sold on the bill, e.g. returned on their own, are skipped. Returns the
+/// stock value booked and the lots the units went back into.
+pub async fn return_line_stock(
+    db: &Surreal<Client>,
+    bill_id: &str,
+    line: &BillItem,
+    quantity: i32,
+    serials: &[String],
+    restock: bool,
+    location: Option<&str>,
+    reference: &str,
+) -> Result<(f64, Vec<LotAllocation>), surrealdb::Error> {
+    // Bundles were never stocked; their components come back instead
+    if !line.components.is_empty() {
+        let mut cost = 0.0;
+        for component in &line.components {
+            let Some(item) = get_item(db, &component.item_id).await else {
+                continue;
+            };
+            let component_quantity = component.quantity / line.quantity * quantity;
+            if !restock {
+                book_damaged(db, &item.id, component_quantity, reference).await?;
+                continue;
+            }
+            record_receipt(db, &item, component_quantity, item.average_cost, reference).await?;
+            adjust_quantity(
+                db,
+                &item.id,
+                location,
+                component_quantity,
+                "return",
+                reference,
+            )
+            .await?;
+            cost += component_quantity as f64 * item.average_cost;
+        }
+        return Ok((cost, Vec::new()));
+    }
+
+    let Some(item) = get_item(db, &line.item_id).await else {
+        return Ok((0.0, Vec::new()));
+    };
+    let unit_cost = if line.quantity > 0 {
+        line.cogs / line.quantity as f64
+    } else {
+        item.average_cost
+    };
+    if !serials.is_empty() {
+        let mut sold = Vec::new();
+        for serial in serials {
+            match get_serial(db, serial).await {
+                Some(serial)
+                    if serial.status == SerialStatus::Sold
+                        && serial.bill_id.as_deref() == Some(bill_id) =>
+                {
+                    sold.push(serial)
+                }
+                _ => {}
+            }
+        }
+        return_serials(db, &item, &sold, unit_cost, restock, location, reference).await?;
+        let cost = if restock {
+            sold.len() as f64 * unit_cost
+        } else {
+            0.0
+        };
+        return Ok((cost, Vec::new()));
+    }
+    if !restock {
+        book_damaged(db, &item.id, quantity, reference).await?;
+        return Ok((0.0, Vec::new()));
+    }
+    record_receipt(db, &item, quantity, unit_cost, reference).await?;
+    let mut lots = Vec::new();
+    if !line.lots.is_empty() {
+        lots = return_to_lots(db, &line.lots, quantity).await?;
+    }
+    adjust_quantity(db, &item.id, location, quantity, "return", reference).await?;
+    Ok((quantity as f64 * unit_cost, lots))
+}
+
+/// Puts back all the stock an issued bill took.
+pub async fn return_bill_stock(
+    db: &Surreal<Client>,
+    bill: &Bill,
+    reference: &str,
+) -> Result<(), surrealdb::Error> {
+    for line in &bill.items {
+        return_line_stock(
+            db,
+            &bill.id,
+            line,
+            line.quantity,
+            &line.serials,
+            true,
+            bill.location.as_deref(),
+            reference,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Takes the stock for a priced bill and stores it as issued under the next
+/// number of its invoice series. The caller must hold the stock lock.
+pub async fn issue_bill(db: &Surreal<Client>, bill: Bill) -> Result<Bill, HttpResponse> {
+    issue(db, bill, true).await
+}
+
+/// Issues a bill for goods that already left on delivery notes, so no stock
+/// is taken.
+pub async fn issue_delivered_bill(db: &Surreal<Client>, bill: Bill) -> Result<Bill, HttpResponse> {
+    issue(db, bill, false).await
+}
+
+async fn issue(
+    db: &Surreal<Client>,
+    mut bill: Bill,
+    take_stock: bool,
+) -> Result<Bill, HttpResponse> {
+    let series = match find_series(
+        db,
+        bill.invoice_series_id.as_deref(),
+        bill.branch.as_deref(),
+    )
+    .await
+    {
+        Ok(Some(series)) => series,
+        Ok(None) => {
+            return Err(HttpResponse::BadRequest().body("No invoice series for this bill"));
+        }
+        Err(e) => {
+            return Err(HttpResponse::InternalServerError()
+                .body(format!("Failed to load invoice series: {}", e)));
+        }
+    };
+    if let Some(code) = &bill.coupon_code {
+        match claim_coupon(db, code).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(
+                    HttpResponse::Conflict().body(format!("Coupon {} has been used up", code))
+                );
+            }
+            Err(e) => {
+                return Err(HttpResponse::InternalServerError()
+                    .body(format!("Failed to use coupon: {}", e)));
+            }
+        }
+    }
+    if take_stock {
+        if let Err(response) = issue_stock(db, &mut bill).await {
+            if let Some(code) = &bill.coupon_code {
+                let _ = release_coupon(db, code).await;
+            }
+            return Err(response);
+        }
+    }
+    bill.status = BillStatus::Issued;
+    if let Err(msg) = create_numbered_bill(db, &series, bill.date, &mut bill).await {
+        // Nothing was stored, so put back what was taken for it
+        if take_stock {
+            if let Err(e) = return_bill_stock(db, &bill, &bill.id).await {
+                eprintln!("Failed to return stock for bill {}: {}", bill.id, e);
+            }
+            if let Some(reference) = &bill.reservation_reference {
+                if let Err(e) = restore_reservations(db, reference).await {
+                    eprintln!("Failed to restore reservations {}: {}", reference, e);
+                }
+            }
+        }
+        if let Some(code) = &bill.coupon_code {
+            let _ = release_coupon(db, code).await;
+        }
+        return Err(HttpResponse::InternalServerError().body(msg));
+    }
+    Ok(bill)
+}
+
+/// Checks that the stock for an amended bill can be taken once the previous
+/// version's stock is back, before any of it is moved.
+async fn check_amended_stock(
+    db: &Surreal<Client>,
+    previous: &Bill,
+    bill: &Bill,
+) -> Result<(), HttpResponse> {
+    let returning = stock_demand(&previous.items);
+    let mut returning_lots: HashMap<&str, i32> = HashMap::new();
+    let mut sold_serials: HashMap<&str, &str> = HashMap::new();
+    for line in &previous.items {
+        for lot in &line.lots {
+            *returning_lots.entry(line.item_id.as_str()).or_insert(0) += lot.quantity;
+        }
+        for serial in &line.serials {
+            sold_serials.insert(serial.as_str(), line.item_id.as_str());
+        }
+    }
+
+    for line in &bill.items {
+        if line.serials.is_empty() {
+            continue;
+        }
+        let unique: HashSet<&String> = line.serials.iter().collect();
+        if line.serials.len() != line.quantity as usize || unique.len() != line.serials.len() {
+            return Err(HttpResponse::BadRequest().body(format!(
+                "Item {} needs exactly {} distinct serial numbers",
+                line.item_id, line.quantity
+            )));
+        }
+        // Serials sold on the previous version come back before the new one is taken
+        let mut new_serials = Vec::new();
+        for serial in &line.serials {
+            match sold_serials.get(serial.as_str()) {
+                Some(item_id) if *item_id == line.item_id => {}
+                Some(_) => {
+                    return Err(HttpResponse::BadRequest()
+                        .body(format!("Serial {} belongs to a different item", serial)));
+                }
+                None => new_serials.push(serial.clone()),
+            }
+        }
+        if let Err(msg) =
+            validate_for_sale(db, &line.item_id, new_serials.len() as i32, &new_serials).await
+        {
+            return Err(HttpResponse::BadRequest().body(msg));
+        }
+    }
+
+    for (item_id, requested) in stock_demand(&bill.items) {
+        let Some(item) = get_item(db, &item_id).await else {
+            return Err(HttpResponse::BadRequest().body(format!("Item {} not found", item_id)));
+        };
+        let reserved = match reserved_quantity(db, &item.id, None).await {
+            Ok(reserved) => reserved,
+            Err(e) => {
+                return Err(HttpResponse::InternalServerError()
+                    .body(format!("Failed to check reservations: {}", e)));
+            }
+        };
+        let available = item.quantity + returning.get(&item_id).copied().unwrap_or(0) - reserved;
+        if available < requested {
+            return Err(HttpResponse::Conflict().body(format!(
+                "Only {} units of {} are available",
+                available.max(0),
+                item.name
+            )));
+        }
+        if item.track_lots {
+            let needed = requested - returning_lots.get(item_id.as_str()).copied().unwrap_or(0);
+            if needed <= 0 {
+                continue;
+            }
+            match plan_fefo(db, &item.id, needed).await {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    return Err(HttpResponse::BadRequest().body(format!(
+                        "Not enough unexpired lot stock for item {}",
+                        item.name
+                    )));
+                }
+                Err(e) => {
+                    return Err(HttpResponse::InternalServerError()
+                        .body(format!("Failed to allocate lots: {}", e)));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn save_bill(db: &Surreal<Client>, bill: &Bill) -> Result<(), surrealdb::Error> {
+    let query = "UPDATE type::thing('bill', $id) CONTENT $bill";
+    db.query(query)
+        .bind(("id", &bill.id))
+        .bind(("bill", bill))
+        .await?;
+    Ok(())
+}
+
+pub async fn release_delivery_notes(
+    db: &Surreal<Client>,
+    bill_id: &str,
+) -> Result<(), surrealdb::Error> {
+    let query =
+        "UPDATE delivery_note SET invoiced_bill_id = NONE WHERE invoiced_bill_id = $bill_id";
+    db.query(query).bind(("bill_id", bill_id)).await?;
+    Ok(())
+}
+
+async fn has_credit_notes(db: &Surreal<Client>, bill_id: &str) -> Result<bool, surrealdb::Error> {
+    let query = "SELECT id FROM credit_note WHERE bill_id = $bill_id LIMIT 1";
+    let res = db.query(query).bind(("bill_id", bill_id)).await?;
+    Ok(res
+        .get(0)
+        .and_then(|r| r.result::<Vec<serde_json::Value>>().ok())
+        .is_some_and(|rows| !rows.is_empty()))
+}
+
 pub async fn create_bill(req: web::Json<CreateBillRequest>) -> impl Responder {
     let db = get_db().await;
-    let total_amount = req
-        .items
-        .iter()
-        .map(|item| item.price * item.quantity as f64)
-        .sum();
-    let bill = Bill {
+    let _guard = lock_stock().await;
+    let mut bill = Bill::draft(
+        req.customer_name.clone(),
+        req.date.unwrap_or_else(today),
+        req.items.clone(),
+    );
+    bill.discount = req.discount;
+    bill.coupon_code = req.coupon_code.clone();
+    bill.currency = match req.currency.as_deref().map(normalize_currency) {
+        Some(Ok(currency)) => currency,
+        Some(Err(msg)) => return HttpResponse::BadRequest().body(msg),
+        None => base_currency(),
+    };
+    bill.branch = req.branch.clone();
+    bill.location = req.location.clone();
+    bill.reservation_reference = req.reservation_reference.clone();
+    bill.invoice_series_id = req.invoice_series_id.clone();
+    if let Err(response) = price_lines(db, &mut bill).await {
+        return response;
+    }
+    if req.draft {
+        if let Err(e) = db.create::<_, Bill>("bill").content(&bill).await {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to create bill: {}", e));
+        }
+        return HttpResponse::Ok().json(bill);
+    }
+    match issue_bill(db, bill).await {
+        Ok(bill) => HttpResponse::Ok().json(bill),
+        Err(response) => response,
+    }
+}
+
+pub async fn update_draft(req: web::Json<UpdateDraftRequest>) -> impl Responder {
+    let db = get_db().await;
+    // Keeps an issue or void of the same draft from landing between the read
+    // and the save
+    let _guard = lock_stock().await;
+    let Some(mut bill) = get_bill(db, &req.bill_id).await else {
+        return HttpResponse::NotFound().body("Bill not found");
+    };
+    if bill.status != BillStatus::Draft {
+        return HttpResponse::Conflict().body("Only draft bills can be edited; amend it instead");
+    }
+    bill.items = req.items.clone();
+    bill.customer_name = req.customer_name.clone();
+    bill.date = req.date;
+    bill.location = req.location.clone();
+    bill.branch = req.branch.clone();
+    bill.invoice_series_id = req.invoice_series_id.clone();
+    bill.reservation_reference = req.reservation_reference.clone();
+    bill.discount = req.discount;
+    bill.coupon_code = req.coupon_code.clone();
+    bill.currency = match req.currency.as_deref().map(normalize_currency) {
+        Some(Ok(currency)) => currency,
+        Some(Err(msg)) => return HttpResponse::BadRequest().body(msg),
+        None => base_currency(),
+    };
+    if let Err(response) = price_lines(db, &mut bill).await {
+        return response;
+    }
+    if let Err(e) = save_bill(db, &bill).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to save bill: {}", e));
+    }
+    HttpResponse::Ok().json(bill)
+}
+
+pub async fn issue_draft(req: web::Json<IssueBillRequest>) -> impl Responder {
+    let db = get_db().await;
+    let _guard = lock_stock().await;
+    let Some(mut bill) = get_bill(db, &req.bill_id).await else {
+        return HttpResponse::NotFound().body("Bill not found");
+    };
+    if bill.status != BillStatus::Draft {
+        return HttpResponse::Conflict().body("Bill has already been issued");
+    }
+    // Prices may have moved since the draft was saved
+    if let Err(response) = price_lines(db, &mut bill).await {
+        return response;
+    }
+    match issue_bill(db, bill).await {
+        Ok(bill) => HttpResponse::Ok().json(bill),
+        Err(response) => response,
+    }
+}
+
+/// Cancels a bill. Issued bills give their stock back and keep their
+/// invoice number, so the series stays gap-free.
+pub async fn void_bill(req: web::Json<VoidBillRequest>) -> impl Responder {
+    let db = get_db().await;
+    if req.reason.trim().is_empty() {
+        return HttpResponse::BadRequest().body("A reason is required to void a bill");
+    }
+    let _guard = lock_stock().await;
+    let Some(mut bill) = get_bill(db, &req.bill_id).await else {
+        return HttpResponse::NotFound().body("Bill not found");
+    };
+    match bill.status {
+        BillStatus::Void => return HttpResponse::Conflict().body("Bill is already void"),
+        BillStatus::Draft => {
+            if let Some(reference) = &bill.reservation_reference {
+                if let Err(e) = release_reservations(db, reference).await {
+                    return HttpResponse::InternalServerError()
+                        .body(format!("Failed to release reservations: {}", e));
+                }
+            }
+        }
+        BillStatus::Issued => {
+            if bill.amount_paid > 0.0 {
+                return HttpResponse::Conflict()
+                    .body("Bill has payments; issue a credit note instead");
+            }
+            match has_credit_notes(db, &bill.id).await {
+                Ok(false) => {}
+                Ok(true) => {
+                    return HttpResponse::Conflict()
+                        .body("Bill has credit notes; credit the remaining lines instead");
+                }
+                Err(e) => {
+                    return HttpResponse::InternalServerError()
+                        .body(format!("Failed to check credit notes: {}", e));
+                }
+            }
+            // Delivered goods stay with the customer; their notes can be invoiced again
+            let result = if bill.delivery_note_ids.is_empty() {
+                return_bill_stock(db, &bill, &bill.id).await
+            } else {
+                release_delivery_notes(db, &bill.id).await
+            };
+            if let Err(e) = result {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Failed to return stock: {}", e));
+            }
+            if let Some(code) = &bill.coupon_code {
+                if let Err(e) = release_coupon(db, code).await {
+                    return HttpResponse::InternalServerError()
+                        .body(format!("Failed to release coupon: {}", e));
+                }
+            }
+        }
+    }
+    bill.status = BillStatus::Void;
+    bill.void_reason = Some(req.reason.clone());
+    if let Err(e) = save_bill(db, &bill).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to save bill: {}", e));
+    }
+    HttpResponse::Ok().json(bill)
+}
+
+/// Replaces the lines of an issued bill. The bill keeps its id and invoice
+/// number; what it said before is kept as a numbered version.
+pub async fn amend_bill(req: web::Json<AmendBillRequest>) -> impl Responder {
+    let db = get_db().await;
+    if req.reason.trim().is_empty() {
+        return HttpResponse::BadRequest().body("A reason is required to amend a bill");
+    }
+    let _guard = lock_stock().await;
+    let Some(previous) = get_bill(db, &req.bill_id).await else {
+        return HttpResponse::NotFound().body("Bill not found");
+    };
+    if previous.status != BillStatus::Issued {
+        return HttpResponse::Conflict().body("Only issued bills can be amended");
+    }
+    if !previous.delivery_note_ids.is_empty() {
+        return HttpResponse::Conflict()
+            .body("Bills for delivery notes cannot be amended; void and invoice them again");
+    }
+    match has_credit_notes(db, &previous.id).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return HttpResponse::Conflict().body("Bills with credit notes cannot be amended");
+        }
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to check credit notes: {}", e));
+        }
+    }
+
+    let mut bill = previous.clone();
+    bill.items = req.items.clone();
+    if let Some(customer_name) = &req.customer_name {
+        bill.customer_name = customer_name.clone();
+    }
+    if let Some(date) = req.date {
+        bill.date = date;
+    }
+    bill.version += 1;
+    // Reservations were consumed when the bill was first issued
+    bill.reservation_reference = None;
+    // Payments were booked at the bill's rate, so a paid bill keeps it
+    let priced = if previous.amount_paid > 0.0 {
+        price_lines_at_rate(db, &mut bill).await
+    } else {
+        price_lines(db, &mut bill).await
+    };
+    if let Err(response) = priced {
+        return response;
+    }
+    if bill.total_amount < bill.amount_paid - 0.005 {
+        return HttpResponse::Conflict().body(format!(
+            "The amended total {:.2} is less than the {:.2} already paid",
+            bill.total_amount, bill.amount_paid
+        ));
+    }
+
+    if let Err(response) = check_amended_stock(db, &previous, &bill).await {
+        return response;
+    }
+
+    let reference = format!("{}/v{}", bill.id, bill.version);
+    if let Err(e) = return_bill_stock(db, &previous, &reference).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to return stock: {}", e));
+    }
+    // The stock was checked above while holding the lock, so this only fails
+    // on a database error
+    if issue_stock(db, &mut bill).await.is_err() {
+        return restore_issue(db, &previous).await;
+    }
+
+    let version = BillVersion {
         id: uuid::Uuid::new_v4().to_string(),
-        items: req.items.clone(),
-        total_amount,
-        customer_name: req.customer_name.clone(),
-        date: req.date.clone(),
+        bill_id: previous.id.clone(),
+        version: previous.version,
+        reason: req.reason.clone(),
+        replaced_at: Utc::now(),
+        bill: previous,
     };
-    if let Err(e) = db.create::<_, Bill>("bill").content(&bill).await {
-        return HttpResponse::InternalServerError().body(format!("Failed to create bill: {}", e));
+    let query = "BEGIN TRANSACTION;
+        CREATE type::thing('bill_version', $version_id) CONTENT $version;
+        UPDATE type::thing('bill', $id) CONTENT $bill;
+        COMMIT TRANSACTION;";
+    if let Err(e) = db
+        .query(query)
+        .bind(("version_id", &version.id))
+        .bind(("version", &version))
+        .bind(("id", &bill.id))
+        .bind(("bill", &bill))
+        .await
+    {
+        eprintln!("Failed to save amended bill {}: {}", bill.id, e);
+        if let Err(e) = return_bill_stock(db, &bill, &reference).await {
+            eprintln!("Failed to return amended stock for bill {}: {}", bill.id, e);
+        }
+        return restore_issue(db, &version.bill).await;
     }
     HttpResponse::Ok().json(bill)
 }
 
-pub async fn list_bills() -> impl Responder {
+/// Takes the stock for a bill's stored version again after a failed
+/// amendment put it back, so stock matches the bill that is still on file.
+/// Always answers with an error; if the stock can't be taken either, the
+/// response says so, as the bill and stock then disagree until fixed by hand.
+async fn restore_issue(db: &Surreal<Client>, previous: &Bill) -> HttpResponse {
+    let mut restored = previous.clone();
+    restored.reservation_reference = None;
+    if issue_stock(db, &mut restored).await.is_err() {
+        eprintln!(
+            "Stock for bill {} was returned by a failed amendment and could not be taken again",
+            previous.id
+        );
+        return HttpResponse::InternalServerError().body(format!(
+            "Failed to amend bill; its stock was returned and could not be taken again, \
+             so stock for bill {} must be corrected by hand",
+            previous.id
+        ));
+    }
+    // Lots may have been picked differently the second time
+    restored.reservation_reference = previous.reservation_reference.clone();
+    let query = "UPDATE type::thing('bill', $id) CONTENT $bill";
+    if let Err(e) = db
+        .query(query)
+        .bind(("id", &restored.id))
+        .bind(("bill", &restored))
+        .await
+    {
+        eprintln!(
+            "Failed to save restored lots for bill {}: {}",
+            restored.id, e
+        );
+    }
+    HttpResponse::InternalServerError().body("Failed to amend bill; its stock was restored")
+}
+
+pub async fn get_bill_by_id(path: web::Path<String>) -> impl Responder {
+    let db = get_db().await;
+    match get_bill(db, &path.into_inner()).await {
+        Some(bill) => HttpResponse::Ok().json(bill),
+        None => HttpResponse::NotFound().body("Bill not found"),
+    }
+}
+
+fn render_bill(bill: &Bill, item_names: &[String]) -> Vec<String> {
+    let heading = match bill.status {
+        BillStatus::Draft => "DRAFT BILL".to_string(),
+        BillStatus::Issued => format!(
+            "INVOICE {}",
+            bill.invoice_number.as_deref().unwrap_or(&bill.id)
+        ),
+        BillStatus::Void => format!(
+            "VOID INVOICE {}",
+            bill.invoice_number.as_deref().unwrap_or(&bill.id)
+        ),
+    };
+    let mut lines = vec![
+        heading,
+        String::new(),
+        format!("Customer:    {}", bill.customer_name),
+        format!("Date:        {}", bill.date),
+        format!("Currency:    {}", bill.currency),
+        String::new(),
+        format!(
+            "{:<36} {:>6} {:>11} {:>10} {:>9} {:>12}",
+            "Item", "Qty", "Unit price", "Discount", "Tax", "Amount"
+        ),
+        "-".repeat(89),
+    ];
+    for (line, name) in bill.items.iter().zip(item_names) {
+        lines.push(format!(
+            "{:<36.36} {:>6} {:>11.2} {:>10.2} {:>9.2} {:>12.2}",
+            name,
+            line.quantity,
+            line.price,
+            line.discount_amount,
+            line.tax_amount,
+            line.net_amount() + line.tax_amount
+        ));
+    }
+    lines.push("-".repeat(89));
+    for discount in &bill.discounts {
+        lines.push(format!(
+            "{:<76} {:>12.2}",
+            discount.description, -discount.amount
+        ));
+    }
+    lines.push(format!("{:<76} {:>12.2}", "Tax", bill.tax_amount));
+    lines.push(format!("{:<76} {:>12.2}", "Total", bill.total_amount));
+    lines
+}
+
+pub async fn bill_pdf(db: &Surreal<Client>, bill: &Bill) -> Result<Vec<u8>, String> {
+    let mut item_names = Vec::new();
+    for line in &bill.items {
+        let name = get_item(db, &line.item_id)
+            .await
+            .map_or_else(|| line.item_id.clone(), |item| item.name);
+        item_names.push(name);
+    }
+    let title = format!(
+        "Invoice {}",
+        bill.invoice_number.as_deref().unwrap_or(&bill.id)
+    );
+    render_text_pdf(&title, &render_bill(bill, &item_names))
+        .map_err(|e| format!("Failed to render bill: {}", e))
+}
+
+pub async fn bill_pdf_by_id(path: web::Path<String>) -> impl Responder {
     let db = get_db().await;
-    let query = "SELECT * FROM bill";
-    match db.query(query).await {
+    let Some(bill) = get_bill(db, &path.into_inner()).await else {
+        return HttpResponse::NotFound().body("Bill not found");
+    };
+    match bill_pdf(db, &bill).await {
+        Ok(pdf) => HttpResponse::Ok()
+            .content_type("application/pdf")
+            .insert_header((
+                "Content-Disposition",
+                format!("inline; filename=\"bill-{}.pdf\"", bill.id),
+            ))
+            .body(pdf),
+        Err(msg) => HttpResponse::InternalServerError().body(msg),
+    }
+}
+
+/// Earlier versions of an amended bill, oldest first.
+pub async fn bill_versions(path: web::Path<String>) -> impl Responder {
+    let db = get_db().await;
+    let query = "SELECT * FROM bill_version WHERE bill_id = $bill_id ORDER BY version ASC";
+    match db.query(query).bind(("bill_id", path.into_inner())).await {
         Ok(res) => {
-            let bills = res
+            let versions = res
                 .get(0)
-                .and_then(|r| r.result::<Vec<Bill>>().ok())
+                .and_then(|r| r.result::<Vec<BillVersion>>().ok())
                 .unwrap_or_default();
-            HttpResponse::Ok().json(bills)
+            HttpResponse::Ok().json(versions)
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list versions: {}", e))
         }
+    }
+}
+
+pub async fn list_bills(
+    params: web::Query<ListParams>,
+    filters: web::Query<BillFilters>,
+) -> impl Responder {
+    let db = get_db().await;
+    let mut query = ListQuery::new(
+        "bill",
+        &["customer_name", "invoice_number"],
+        &[
+            "date",
+            "created_at",
+            "total_amount",
+            "customer_name",
+            "invoice_number",
+        ],
+    );
+    query.filter("customer_name", "=", filters.customer_name.as_ref());
+    query.filter("status", "=", filters.status.as_ref());
+    let currency = filters.currency.as_ref().map(|code| code.to_uppercase());
+    query.filter("currency", "=", currency);
+    query.filter("invoice_number", "=", filters.invoice_number.as_ref());
+    query.filter("branch", "=", filters.branch.as_ref());
+    query.filter("date", ">=", filters.date_from);
+    query.filter("date", "<=", filters.date_to);
+    match query.fetch::<Bill>(db, &params).await {
+        Ok(page) => HttpResponse::Ok().json(page),
         Err(e) => HttpResponse::InternalServerError().body(format!("Failed to list bills: {}", e)),
     }
 }
+
+/// Bundle sales alongside the component stock they consumed.
+pub async fn bundle_report() -> impl Responder {
+    let db = get_db().await;
+    let bills = match db
+        .query("SELECT * FROM bill WHERE status NOT IN ['draft', 'void']")
+        .await
+    {
+        Ok(res) => match res.get(0).map(|r| r.result::<Vec<Bill>>()) {
+            Some(Ok(bills)) => bills,
+            Some(Err(e)) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Failed to read bills: {}", e));
+            }
+            None => Vec::new(),
+        },
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to list bills: {}", e));
+        }
+    };
+
+    let mut sales: HashMap<String, (i32, f64)> = HashMap::new();
+    let mut consumption: HashMap<String, i32> = HashMap::new();
+    for line in bills.iter().flat_map(|bill| &bill.items) {
+        if line.components.is_empty() {
+            continue;
+        }
+        let entry = sales.entry(line.item_id.clone()).or_insert((0, 0.0));
+        entry.0 += line.quantity;
+        entry.1 += line.net_amount();
+        for component in &line.components {
+            *consumption.entry(component.item_id.clone()).or_insert(0) += component.quantity;
+        }
+    }
+
+    let mut bundle_sales = Vec::new();
+    for (item_id, (quantity, revenue)) in sales {
+        let item_name = get_item(db, &item_id)
+            .await
+            .map_or_else(String::new, |item| item.name);
+        bundle_sales.push(BundleSales {
+            item_id,
+            item_name,
+            quantity,
+            revenue,
+        });
+    }
+    let mut component_consumption = Vec::new();
+    for (item_id, quantity) in consumption {
+        let item_name = get_item(db, &item_id)
+            .await
+            .map_or_else(String::new, |item| item.name);
+        component_consumption.push(ComponentConsumption {
+            item_id,
+            item_name,
+            quantity,
+        });
+    }
+    HttpResponse::Ok().json(BundleReport {
+        bundle_sales,
+        component_consumption,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(item_id: &str, quantity: i32, components: &[(&str, i32)]) -> BillItem {
+        BillItem {
+            item_id: item_id.to_string(),
+            quantity,
+            price: 0.0,
+            price_list: None,
+            price_rule_id: None,
+            tax_rate: 0.0,
+            tax_amount: 0.0,
+            discount: None,
+            discount_amount: 0.0,
+            lots: Vec::new(),
+            serials: Vec::new(),
+            components: components
+                .iter()
+                .map(|(item_id, quantity)| BundleComponent {
+                    item_id: item_id.to_string(),
+                    quantity: *quantity,
+                })
+                .collect(),
+            cogs: 0.0,
+        }
+    }
+
+    #[test]
+    fn line_tax_rounds_to_cents() {
+        assert_eq!(line_tax(100.0, 18.0), 18.0);
+        assert_eq!(line_tax(10.0, 12.5), 1.25);
+        assert_eq!(line_tax(3.33, 18.0), 0.6);
+        assert_eq!(line_tax(100.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn stock_demand_sums_lines_per_item() {
+        let demand = stock_demand(&[line("a", 2, &[]), line("b", 1, &[]), line("a", 3, &[])]);
+        assert_eq!(demand.len(), 2);
+        assert_eq!(demand["a"], 5);
+        assert_eq!(demand["b"], 1);
+    }
+
+    #[test]
+    fn stock_demand_breaks_bundles_into_components() {
+        let demand = stock_demand(&[line("kit", 2, &[("a", 4), ("b", 2)]), line("a", 1, &[])]);
+        assert!(!demand.contains_key("kit"));
+        assert_eq!(demand["a"], 5);
+        assert_eq!(demand["b"], 2);
+    }
+}
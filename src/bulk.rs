@@ -0,0 +1,460 @@
+use crate::db::DB;
+use crate::inventory::{CreateItemRequest, InventoryItem};
+use crate::reservations::lock_stock;
+use crate::search::{index_item, remove_item};
+use actix_web::{HttpResponse, Responder, web};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::{HashMap, HashSet};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+/// Catalogue fields that can be edited. Stock quantities are not in here;
+/// they only change through receipts, sales and stock counts.
+#[derive(Deserialize)]
+pub struct ItemChanges {
+    pub name: Option<String>,
+    pub sku: Option<String>,
+    pub description: Option<String>,
+    pub price: Option<f64>,
+    pub category: Option<String>,
+    pub tax_rate: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BulkOperation {
+    Create {
+        item: CreateItemRequest,
+    },
+    Update {
+        item_id: String,
+        changes: ItemChanges,
+    },
+    Delete {
+        item_id: String,
+    },
+    // Rule-based change, e.g. +8% on every item in a category
+    AdjustPrices {
+        category: Option<String>,
+        percent: Option<f64>,
+        amount: Option<f64>,
+    },
+}
+
+#[derive(Deserialize)]
+pub struct BulkRequest {
+    pub operations: Vec<BulkOperation>,
+    #[serde(default)]
+    pub preview: bool,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateItemRequest {
+    pub item_id: String,
+    pub changes: ItemChanges,
+}
+
+#[derive(Serialize)]
+pub struct BulkChange {
+    pub op: &'static str,
+    pub item_id: String,
+    pub before: Option<InventoryItem>,
+    pub after: Option<InventoryItem>,
+}
+
+#[derive(Serialize)]
+pub struct BulkResult {
+    pub preview: bool,
+    pub changes: Vec<BulkChange>,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+fn round_price(price: f64) -> f64 {
+    (price * 100.0).round() / 100.0
+}
+
+fn validate_components(
+    items: &HashMap<String, InventoryItem>,
+    req: &CreateItemRequest,
+) -> Result<(), String> {
+    for component in &req.components {
+        match items.get(&component.item_id) {
+            Some(c) if c.is_bundle() || c.track_lots || c.track_serials => {
+                return Err(format!(
+                    "Component {} cannot be a bundle or a lot/serial tracked item",
+                    c.name
+                ));
+            }
+            Some(_) if component.quantity > 0 => {}
+            Some(_) => return Err("Component quantities must be positive".to_string()),
+            None => return Err(format!("Component {} not found", component.item_id)),
+        }
+    }
+    Ok(())
+}
+
+/// Applies the operations in order to an in-memory copy of the catalogue and
+/// returns every change they make. Nothing is written here.
+fn plan(
+    items: &mut HashMap<String, InventoryItem>,
+    operations: &[BulkOperation],
+) -> Result<Vec<BulkChange>, String> {
+    let mut changes = Vec::new();
+    for (index, operation) in operations.iter().enumerate() {
+        let fail = |msg: String| format!("Operation {}: {}", index, msg);
+        match operation {
+            BulkOperation::Create { item: req } => {
+                if req.name.trim().is_empty() || req.price < 0.0 {
+                    return Err(fail(
+                        "Items need a name and a non-negative price".to_string(),
+                    ));
+                }
+                if !(0.0..=100.0).contains(&req.tax_rate) {
+                    return Err(fail(
+                        "Tax rate must be between 0 and 100 percent".to_string(),
+                    ));
+                }
+                if req.quantity != 0 {
+                    return Err(fail(
+                        "Bulk-created items start with zero stock; receive stock separately"
+                            .to_string(),
+                    ));
+                }
+                if req.track_lots && req.track_serials {
+                    return Err(fail(
+                        "An item can track either lots or serials, not both".to_string(),
+                    ));
+                }
+                if !req.components.is_empty() && (req.track_lots || req.track_serials) {
+                    return Err(fail("Bundles cannot be lot or serial tracked".to_string()));
+                }
+                validate_components(items, req).map_err(fail)?;
+                let item = InventoryItem {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    name: req.name.clone(),
+                    sku: req.sku.clone(),
+                    description: req.description.clone(),
+                    quantity: 0,
+                    price: req.price,
+                    category: req.category.clone(),
+                    tax_rate: req.tax_rate,
+                    track_lots: req.track_lots,
+                    track_serials: req.track_serials,
+                    costing_method: req.costing_method,
+                    average_cost: req.unit_cost.unwrap_or(0.0),
+                    components: req.components.clone(),
+                    attachments: Vec::new(),
+                };
+                changes.push(BulkChange {
+                    op: "create",
+                    item_id: item.id.clone(),
+                    before: None,
+                    after: Some(item.clone()),
+                });
+                items.insert(item.id.clone(), item);
+            }
+            BulkOperation::Update {
+                item_id,
+                changes: edit,
+            } => {
+                let Some(item) = items.get_mut(item_id) else {
+                    return Err(fail(format!("Item {} not found", item_id)));
+                };
+                let before = item.clone();
+                if let Some(name) = &edit.name {
+                    if name.trim().is_empty() {
+                        return Err(fail("Name cannot be empty".to_string()));
+                    }
+                    item.name = name.clone();
+                }
+                if let Some(price) = edit.price {
+                    if price < 0.0 {
+                        return Err(fail("Price cannot be negative".to_string()));
+                    }
+                    item.price = price;
+                }
+                if edit.sku.is_some() {
+                    item.sku = edit.sku.clone();
+                }
+                if edit.description.is_some() {
+                    item.description = edit.description.clone();
+                }
+                if edit.category.is_some() {
+                    item.category = edit.category.clone();
+                }
+                if let Some(tax_rate) = edit.tax_rate {
+                    if !(0.0..=100.0).contains(&tax_rate) {
+                        return Err(fail(
+                            "Tax rate must be between 0 and 100 percent".to_string(),
+                        ));
+                    }
+                    item.tax_rate = tax_rate;
+                }
+                changes.push(BulkChange {
+                    op: "update",
+                    item_id: item_id.clone(),
+                    before: Some(before),
+                    after: Some(item.clone()),
+                });
+            }
+            BulkOperation::Delete { item_id } => {
+                let Some(item) = items.get(item_id) else {
+                    return Err(fail(format!("Item {} not found", item_id)));
+                };
+                if item.quantity != 0 {
+                    return Err(fail(format!("{} still has stock on hand", item.name)));
+                }
+                if let Some(bundle) = items
+                    .values()
+                    .find(|b| b.components.iter().any(|c| &c.item_id == item_id))
+                {
+                    return Err(fail(format!(
+                        "{} is a component of {}",
+                        item.name, bundle.name
+                    )));
+                }
+                let before = items.remove(item_id);
+                changes.push(BulkChange {
+                    op: "delete",
+                    item_id: item_id.clone(),
+                    before,
+                    after: None,
+                });
+            }
+            BulkOperation::AdjustPrices {
+                category,
+                percent,
+                amount,
+            } => {
+                let adjust: Box<dyn Fn(f64) -> f64> = match (percent, amount) {
+                    (Some(percent), None) => {
+                        let factor = 1.0 + percent / 100.0;
+                        Box::new(move |price| round_price(price * factor))
+                    }
+                    (None, Some(amount)) => {
+                        let amount = *amount;
+                        Box::new(move |price| round_price(price + amount))
+                    }
+                    _ => return Err(fail("Give either a percent or an amount".to_string())),
+                };
+                let mut ids: Vec<&String> = items
+                    .iter()
+                    .filter(|(_, item)| category.is_none() || item.category == *category)
+                    .map(|(id, _)| id)
+                    .collect();
+                ids.sort();
+                let ids: Vec<String> = ids.into_iter().cloned().collect();
+                for item_id in ids {
+                    let Some(item) = items.get_mut(&item_id) else {
+                        continue;
+                    };
+                    let before = item.clone();
+                    let price = adjust(item.price);
+                    if price < 0.0 {
+                        return Err(fail(format!("{} would get a negative price", item.name)));
+                    }
+                    item.price = price;
+                    changes.push(BulkChange {
+                        op: "update",
+                        item_id,
+                        before: Some(before),
+                        after: Some(item.clone()),
+                    });
+                }
+            }
+        }
+    }
+    Ok(changes)
+}
+
+// Tables whose records point back at an item, with the condition that finds
+// them; documents keep their items in line arrays. Deleting an item that
+// appears in any of them would leave that history dangling.
+const HISTORY_TABLES: [(&str, &str); 19] = [
+    ("stock_movement", "item_id = $item_id"),
+    ("cost_layer", "item_id = $item_id"),
+    ("cost_entry", "item_id = $item_id"),
+    ("lot", "item_id = $item_id"),
+    ("serial", "item_id = $item_id"),
+    ("reservation", "item_id = $item_id"),
+    ("item_location", "item_id = $item_id"),
+    ("bill", "items.*.item_id CONTAINS $item_id"),
+    ("bill_version", "bill.items.*.item_id CONTAINS $item_id"),
+    ("quotation", "items.*.item_id CONTAINS $item_id"),
+    ("recurring_bill", "items.*.item_id CONTAINS $item_id"),
+    ("sales_order", "lines.*.item_id CONTAINS $item_id"),
+    ("delivery_note", "lines.*.item_id CONTAINS $item_id"),
+    ("credit_note", "lines.*.item_id CONTAINS $item_id"),
+    ("purchase_order", "lines.*.item_id CONTAINS $item_id"),
+    ("goods_receipt", "lines.*.item_id CONTAINS $item_id"),
+    ("stock_count", "lines.*.item_id CONTAINS $item_id"),
+    (
+        "assembly_order",
+        "finished_item_id = $item_id OR components.*.item_id CONTAINS $item_id",
+    ),
+    ("attachment", "owner_type = 'item' AND owner_id = $item_id"),
+];
+
+async fn has_history(db: &Surreal<Client>, item_id: &str) -> Result<bool, surrealdb::Error> {
+    for (table, condition) in HISTORY_TABLES {
+        let query = format!("SELECT id FROM {} WHERE {} LIMIT 1", table, condition);
+        let res = db.query(query).bind(("item_id", item_id)).await?;
+        let rows = match res.get(0) {
+            Some(r) => r.result::<Vec<JsonValue>>()?,
+            None => Vec::new(),
+        };
+        if !rows.is_empty() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Refuses deletes of existing items that already have stock or document history.
+async fn check_deletes(db: &Surreal<Client>, changes: &[BulkChange]) -> Result<(), HttpResponse> {
+    for change in changes {
+        let Some(item) = change.before.as_ref().filter(|_| change.op == "delete") else {
+            continue;
+        };
+        match has_history(db, &item.id).await {
+            Ok(false) => {}
+            Ok(true) => {
+                return Err(HttpResponse::Conflict().body(format!(
+                    "{} has stock or document history and cannot be deleted",
+                    item.name
+                )));
+            }
+            Err(e) => {
+                return Err(HttpResponse::InternalServerError()
+                    .body(format!("Failed to check item history: {}", e)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Only the catalogue fields of an item; bulk edits never write anything else
+/// so stock and costing changes made elsewhere are kept.
+fn catalogue_fields(item: &InventoryItem) -> JsonValue {
+    serde_json::json!({
+        "name": item.name,
+        "sku": item.sku,
+        "description": item.description,
+        "price": item.price,
+        "category": item.category,
+        "tax_rate": item.tax_rate,
+    })
+}
+
+/// Writes every touched item in a single transaction. Updates merge the
+/// catalogue fields only; deletes also drop the item's price and reorder rules.
+async fn commit(
+    db: &Surreal<Client>,
+    items: &HashMap<String, InventoryItem>,
+    changes: &[BulkChange],
+) -> Result<(), surrealdb::Error> {
+    let created: HashSet<&String> = changes
+        .iter()
+        .filter(|c| c.op == "create")
+        .map(|c| &c.item_id)
+        .collect();
+    let mut touched: Vec<&String> = changes.iter().map(|c| &c.item_id).collect();
+    touched.sort();
+    touched.dedup();
+
+    let mut sql = String::from("BEGIN TRANSACTION;\n");
+    let mut bindings: Vec<(String, JsonValue)> = Vec::new();
+    for (i, item_id) in touched.into_iter().enumerate() {
+        let statement = match (items.get(item_id), created.contains(item_id)) {
+            (Some(_), true) => "CREATE type::thing('inventory', $id{i}) CONTENT $item{i};\n",
+            (Some(_), false) => "UPDATE type::thing('inventory', $id{i}) MERGE $item{i};\n",
+            // Created and deleted in the same batch: nothing to write
+            (None, true) => continue,
+            (None, false) => concat!(
+                "DELETE type::thing('inventory', $id{i});\n",
+                "DELETE price_rule WHERE item_id = $id{i};\n",
+                "DELETE reorder_rule WHERE item_id = $id{i};\n",
+            ),
+        };
+        sql.push_str(&statement.replace("{i}", &i.to_string()));
+        bindings.push((format!("id{}", i), JsonValue::String(item_id.clone())));
+        if let Some(item) = items.get(item_id) {
+            let value = if created.contains(item_id) {
+                serde_json::to_value(item).unwrap_or_default()
+            } else {
+                catalogue_fields(item)
+            };
+            bindings.push((format!("item{}", i), value));
+        }
+    }
+    sql.push_str("COMMIT TRANSACTION;");
+
+    let mut query = db.query(sql);
+    for binding in bindings {
+        query = query.bind(binding);
+    }
+    query.await?;
+    Ok(())
+}
+
+async fn run(db: &Surreal<Client>, operations: &[BulkOperation], preview: bool) -> HttpResponse {
+    // Held through the commit so no sale or receipt lands between planning
+    // and writing.
+    let _guard = match preview {
+        true => None,
+        false => Some(lock_stock().await),
+    };
+    let mut items: HashMap<String, InventoryItem> = match db.query("SELECT * FROM inventory").await
+    {
+        Ok(res) => res
+            .get(0)
+            .and_then(|r| r.result::<Vec<InventoryItem>>().ok())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|item| (item.id.clone(), item))
+            .collect(),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to load items: {}", e));
+        }
+    };
+    let changes = match plan(&mut items, operations) {
+        Ok(changes) => changes,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    if let Err(response) = check_deletes(db, &changes).await {
+        return response;
+    }
+    if !preview {
+        if let Err(e) = commit(db, &items, &changes).await {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to apply changes: {}", e));
+        }
+        for change in &changes {
+            match &change.after {
+                Some(item) => index_item(item).await,
+                None => remove_item(&change.item_id).await,
+            }
+        }
+    }
+    HttpResponse::Ok().json(BulkResult { preview, changes })
+}
+
+pub async fn bulk_items(req: web::Json<BulkRequest>) -> impl Responder {
+    let db = get_db().await;
+    run(db, &req.operations, req.preview).await
+}
+
+pub async fn update_item(req: web::Json<UpdateItemRequest>) -> impl Responder {
+    let db = get_db().await;
+    let req = req.into_inner();
+    let operations = [BulkOperation::Update {
+        item_id: req.item_id,
+        changes: req.changes,
+    }];
+    run(db, &operations, false).await
+}
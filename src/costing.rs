@@ -0,0 +1,257 @@
+use crate::dates::end_of_day;
+use crate::db::DB;
+use crate::inventory::InventoryItem;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::sql::Datetime;
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CostingMethod {
+    #[default]
+    Fifo,
+    WeightedAverage,
+}
+
+/// Units received together at one purchase cost. FIFO issues drain the
+/// oldest layers first.
+#[derive(Serialize, Deserialize)]
+pub struct CostLayer {
+    pub id: String,
+    pub item_id: String,
+    #[serde(with = "crate::dates::datetime")]
+    pub received_at: DateTime<Utc>,
+    pub quantity: i32,
+    pub remaining: i32,
+    pub unit_cost: f64,
+    pub reference: String,
+}
+
+/// One signed change to the quantity and value of an item's stock. Summing
+/// these up to a point in time gives the valuation at that time, whatever
+/// the costing method.
+#[derive(Serialize, Deserialize)]
+pub struct CostEntry {
+    pub id: String,
+    pub item_id: String,
+    #[serde(with = "crate::dates::datetime")]
+    pub at: DateTime<Utc>,
+    pub quantity: i32,
+    pub value: f64,
+    pub reference: String,
+}
+
+#[derive(Deserialize)]
+pub struct ValuationQuery {
+    pub as_of: Option<NaiveDate>,
+}
+
+#[derive(Deserialize)]
+pub struct StockTotals {
+    pub item_id: String,
+    pub quantity: i32,
+    pub value: f64,
+}
+
+#[derive(Serialize)]
+pub struct ValuationLine {
+    pub item_id: String,
+    pub item_name: String,
+    pub costing_method: CostingMethod,
+    pub quantity: i32,
+    pub value: f64,
+}
+
+#[derive(Serialize)]
+pub struct ValuationReport {
+    pub as_of: DateTime<Utc>,
+    pub lines: Vec<ValuationLine>,
+    pub total_value: f64,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+async fn add_entry(
+    db: &Surreal<Client>,
+    item_id: &str,
+    quantity: i32,
+    value: f64,
+    reference: &str,
+) -> Result<(), surrealdb::Error> {
+    let entry = CostEntry {
+        id: uuid::Uuid::new_v4().to_string(),
+        item_id: item_id.to_string(),
+        at: Utc::now(),
+        quantity,
+        value,
+        reference: reference.to_string(),
+    };
+    db.create::<_, CostEntry>("cost_entry")
+        .content(&entry)
+        .await?;
+    Ok(())
+}
+
+/// Opens a cost layer for received stock without touching the item's average cost.
+pub async fn add_layer(
+    db: &Surreal<Client>,
+    item_id: &str,
+    quantity: i32,
+    unit_cost: f64,
+    reference: &str,
+) -> Result<(), surrealdb::Error> {
+    let layer = CostLayer {
+        id: uuid::Uuid::new_v4().to_string(),
+        item_id: item_id.to_string(),
+        received_at: Utc::now(),
+        quantity,
+        remaining: quantity,
+        unit_cost,
+        reference: reference.to_string(),
+    };
+    db.create::<_, CostLayer>("cost_layer")
+        .content(&layer)
+        .await?;
+    add_entry(
+        db,
+        item_id,
+        quantity,
+        quantity as f64 * unit_cost,
+        reference,
+    )
+    .await
+}
+
+/// Books the purchase cost of received stock. `item` must be the state before
+/// the receipt so the moving average is taken over what was already on hand.
+pub async fn record_receipt(
+    db: &Surreal<Client>,
+    item: &InventoryItem,
+    quantity: i32,
+    unit_cost: f64,
+    reference: &str,
+) -> Result<(), surrealdb::Error> {
+    let on_hand = item.quantity.max(0) as f64;
+    let total = on_hand + quantity as f64;
+    let average_cost = if total > 0.0 {
+        (on_hand * item.average_cost + quantity as f64 * unit_cost) / total
+    } else {
+        unit_cost
+    };
+    let query = "UPDATE type::thing('inventory', $id) SET average_cost = $average_cost";
+    db.query(query)
+        .bind(("id", &item.id))
+        .bind(("average_cost", average_cost))
+        .await?;
+    add_layer(db, &item.id, quantity, unit_cost, reference).await
+}
+
+/// Books stock leaving at cost and returns the cost of goods for it.
+pub async fn record_issue(
+    db: &Surreal<Client>,
+    item: &InventoryItem,
+    quantity: i32,
+    reference: &str,
+) -> Result<f64, surrealdb::Error> {
+    let cost = match item.costing_method {
+        CostingMethod::WeightedAverage => quantity as f64 * item.average_cost,
+        CostingMethod::Fifo => {
+            let query = "SELECT * FROM cost_layer WHERE item_id = $item_id AND remaining > 0 ORDER BY received_at ASC";
+            let res = db.query(query).bind(("item_id", &item.id)).await?;
+            let layers = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<CostLayer>>().ok())
+                .unwrap_or_default();
+            let mut remaining = quantity;
+            let mut cost = 0.0;
+            for layer in layers {
+                if remaining == 0 {
+                    break;
+                }
+                let take = remaining.min(layer.remaining);
+                db.query("UPDATE type::thing('cost_layer', $id) SET remaining -= $take")
+                    .bind(("id", &layer.id))
+                    .bind(("take", take))
+                    .await?;
+                cost += take as f64 * layer.unit_cost;
+                remaining -= take;
+            }
+            // Stock that never had a cost layer (e.g. overselling) goes at average cost
+            cost + remaining as f64 * item.average_cost
+        }
+    };
+    add_entry(db, &item.id, -quantity, -cost, reference).await?;
+    Ok(cost)
+}
+
+/// Quantity and value of every item's stock at `as_of`, from the cost entries.
+pub async fn stock_totals_at(
+    db: &Surreal<Client>,
+    as_of: DateTime<Utc>,
+) -> Result<Vec<StockTotals>, surrealdb::Error> {
+    let query = "SELECT item_id, math::sum(quantity) AS quantity, math::sum(value) AS value FROM cost_entry WHERE at <= $as_of GROUP BY item_id";
+    let res = db
+        .query(query)
+        .bind(("as_of", Datetime::from(as_of)))
+        .await?;
+    match res.get(0) {
+        Some(r) => r.result::<Vec<StockTotals>>(),
+        None => Ok(Vec::new()),
+    }
+}
+
+pub async fn stock_valuation(query: web::Query<ValuationQuery>) -> impl Responder {
+    let db = get_db().await;
+    let as_of = match query.as_of {
+        Some(date) => end_of_day(date),
+        None => Utc::now(),
+    };
+    let totals = match stock_totals_at(db, as_of).await {
+        Ok(totals) => totals,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to compute valuation: {}", e));
+        }
+    };
+    let items: HashMap<String, InventoryItem> = match db.query("SELECT * FROM inventory").await {
+        Ok(res) => res
+            .get(0)
+            .and_then(|r| r.result::<Vec<InventoryItem>>().ok())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|item| (item.id.clone(), item))
+            .collect(),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to list items: {}", e));
+        }
+    };
+
+    let lines: Vec<ValuationLine> = totals
+        .into_iter()
+        .filter(|t| t.quantity != 0 || t.value.abs() > f64::EPSILON)
+        .map(|t| {
+            let item = items.get(&t.item_id);
+            ValuationLine {
+                item_name: item.map_or_else(String::new, |item| item.name.clone()),
+                costing_method: item
+                    .map_or_else(CostingMethod::default, |item| item.costing_method),
+                item_id: t.item_id,
+                quantity: t.quantity,
+                value: t.value,
+            }
+        })
+        .collect();
+    let total_value = lines.iter().map(|line| line.value).sum();
+    HttpResponse::Ok().json(ValuationReport {
+        as_of,
+        lines,
+        total_value,
+    })
+}
@@ -0,0 +1,506 @@
+use crate::billing::{Bill, BillItem, BillStatus, get_bill, line_tax, return_line_stock};
+use crate::customers::get_customer_by_name;
+use crate::dates::business_date;
+use crate::db::DB;
+use crate::ledger::post_foreign_entry;
+use crate::listing::{ListParams, ListQuery};
+use crate::lots::LotAllocation;
+use crate::promotions::round_cents;
+use crate::reservations::lock_stock;
+use crate::serials::{SerialStatus, get_serial};
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReturnDisposition {
+    Restock, // back into sellable stock
+    Damaged, // booked into the damaged goods location
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Settlement {
+    Refund,
+    AccountCredit, // kept as credit on the customer's account
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CreditNoteLine {
+    // Position of the credited line on the original bill
+    pub line_index: usize,
+    pub item_id: String,
+    pub quantity: i32,
+    pub disposition: ReturnDisposition,
+    #[serde(default)]
+    pub serials: Vec<String>,
+    // Filled in by the server from the original bill line
+    #[serde(default)]
+    pub unit_price: f64,
+    #[serde(default)]
+    pub tax_amount: f64,
+    #[serde(default)]
+    pub lots: Vec<LotAllocation>,
+    // Stock value taken back in
+    #[serde(default)]
+    pub cost: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreditNote {
+    pub id: String,
+    pub bill_id: String,
+    pub invoice_number: Option<String>,
+    pub customer_name: String,
+    pub lines: Vec<CreditNoteLine>,
+    pub net_amount: f64,
+    pub tax_amount: f64,
+    pub total_amount: f64,
+    pub settlement: Settlement,
+    // Part of the total set against what was still owed on the bill
+    #[serde(default)]
+    pub applied_amount: f64,
+    // The rest, refunded or kept on account depending on `settlement`
+    #[serde(default)]
+    pub settled_amount: f64,
+    pub reason: String,
+    #[serde(with = "crate::dates::datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateCreditNoteRequest {
+    pub bill_id: String,
+    pub lines: Vec<CreditNoteLine>,
+    pub settlement: Settlement,
+    pub reason: String,
+    // Where restocked units go
+    pub location: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreditNoteFilters {
+    pub bill_id: Option<String>,
+    pub customer_name: Option<String>,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+async fn get_credit_note(db: &Surreal<Client>, note_id: &str) -> Option<CreditNote> {
+    let query = "SELECT * FROM type::thing('credit_note', $id)";
+    let res = db.query(query).bind(("id", note_id)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<CreditNote>>().ok()?;
+    result.into_iter().next()
+}
+
+/// Lines of the credit notes already raised against a bill.
+async fn earlier_lines(
+    db: &Surreal<Client>,
+    bill_id: &str,
+) -> Result<Vec<CreditNoteLine>, surrealdb::Error> {
+    let query = "SELECT * FROM credit_note WHERE bill_id = $bill_id";
+    let res = db.query(query).bind(("bill_id", bill_id)).await?;
+    let notes = res
+        .get(0)
+        .and_then(|r| r.result::<Vec<CreditNote>>().ok())
+        .unwrap_or_default();
+    Ok(notes.into_iter().flat_map(|note| note.lines).collect())
+}
+
+/// The lot allocations of a bill line less what earlier credit notes already
+/// put back, so a second partial return doesn't refill the same lot twice.
+fn remaining_lots(
+    bill_line: &BillItem,
+    line_index: usize,
+    earlier: &[CreditNoteLine],
+) -> Vec<LotAllocation> {
+    let mut returned: HashMap<&str, i32> = HashMap::new();
+    for line in earlier.iter().filter(|line| line.line_index == line_index) {
+        for lot in &line.lots {
+            *returned.entry(lot.lot_id.as_str()).or_insert(0) += lot.quantity;
+        }
+    }
+    bill_line
+        .lots
+        .iter()
+        .filter_map(|allocation| {
+            let already = returned
+                .get(allocation.lot_id.as_str())
+                .copied()
+                .unwrap_or(0);
+            let quantity = allocation.quantity - already;
+            (quantity > 0).then(|| LotAllocation {
+                quantity,
+                ..allocation.clone()
+            })
+        })
+        .collect()
+}
+
+/// Checks a credit note line against the bill line it reverses.
+async fn validate_line(
+    db: &Surreal<Client>,
+    line: &CreditNoteLine,
+    bill_line: &BillItem,
+    already_credited: i32,
+) -> Result<(), String> {
+    if line.item_id != bill_line.item_id {
+        return Err(format!(
+            "Line {} of the bill is not for item {}",
+            line.line_index, line.item_id
+        ));
+    }
+    if line.quantity <= 0 {
+        return Err("Quantity must be positive".to_string());
+    }
+    if line.quantity > bill_line.quantity - already_credited {
+        return Err(format!(
+            "Only {} units of line {} can still be credited",
+            bill_line.quantity - already_credited,
+            line.line_index
+        ));
+    }
+    if bill_line.serials.is_empty() {
+        if !line.serials.is_empty() {
+            return Err(format!("Line {} was not sold by serial", line.line_index));
+        }
+        return Ok(());
+    }
+    if line.serials.len() != line.quantity as usize {
+        return Err(format!(
+            "Line {} needs one serial per returned unit",
+            line.line_index
+        ));
+    }
+    for serial in &line.serials {
+        if !bill_line.serials.contains(serial) {
+            return Err(format!("Serial {} was not sold on this bill", serial));
+        }
+        match get_serial(db, serial).await {
+            Some(s) if s.status == SerialStatus::Sold => {}
+            _ => return Err(format!("Serial {} has already been returned", serial)),
+        }
+    }
+    Ok(())
+}
+
+/// Takes the returned units of one line back. Restocked units go back into
+/// sellable stock; damaged ones are booked into the damaged goods location,
+/// outside the sellable quantity. Returns the stock value booked.
+async fn take_back(
+    db: &Surreal<Client>,
+    note_id: &str,
+    bill_id: &str,
+    line: &mut CreditNoteLine,
+    bill_line: &BillItem,
+    earlier: &[CreditNoteLine],
+    location: Option<&str>,
+) -> Result<f64, surrealdb::Error> {
+    let restock = line.disposition == ReturnDisposition::Restock;
+    let bill_line = BillItem {
+        lots: remaining_lots(bill_line, line.line_index, earlier),
+        ..bill_line.clone()
+    };
+    let (cost, lots) = return_line_stock(
+        db,
+        bill_id,
+        &bill_line,
+        line.quantity,
+        &line.serials,
+        restock,
+        location,
+        note_id,
+    )
+    .await?;
+    line.lots = lots;
+    Ok(cost)
+}
+
+/// Posts the reversal of the credited sale, net and tax separately, and any
+/// money refunded to the customer.
+async fn post_reversal(
+    db: &Surreal<Client>,
+    note: &CreditNote,
+    bill: &Bill,
+) -> Result<(), surrealdb::Error> {
+    let bill_ref = bill.invoice_number.as_deref().unwrap_or(&bill.id);
+    let date = business_date(note.created_at);
+    post_foreign_entry(
+        db,
+        format!(
+            "Sales returned on bill {} (credit note {})",
+            bill_ref, note.id
+        ),
+        note.net_amount,
+        &bill.currency,
+        bill.exchange_rate,
+        date,
+        "debit",
+    )
+    .await?;
+    if note.tax_amount > 0.0 {
+        post_foreign_entry(
+            db,
+            format!(
+                "Tax reversed on bill {} (credit note {})",
+                bill_ref, note.id
+            ),
+            note.tax_amount,
+            &bill.currency,
+            bill.exchange_rate,
+            date,
+            "debit",
+        )
+        .await?;
+    }
+    if note.settlement == Settlement::Refund && note.settled_amount > 0.0 {
+        post_foreign_entry(
+            db,
+            format!(
+                "Refund to {} for credit note {} on bill {}",
+                note.customer_name, note.id, bill_ref
+            ),
+            note.settled_amount,
+            &bill.currency,
+            bill.exchange_rate,
+            date,
+            "credit",
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Sets a credit against the bill: first against what is still owed, and
+/// only the rest comes off what was paid, to be refunded or kept on account.
+/// Returns `(applied, settled)`, or `None` if the bill changed meanwhile.
+async fn credit_bill(
+    db: &Surreal<Client>,
+    bill: &Bill,
+    amount: f64,
+) -> Result<Option<(f64, f64)>, surrealdb::Error> {
+    let owed = (bill.total_amount - bill.credited_amount - bill.amount_paid).max(0.0);
+    let applied = round_cents(amount.min(owed));
+    // Never more than was paid, so an unpaid bill can't be refunded in cash
+    let settled = round_cents((amount - applied).min(bill.amount_paid));
+    // Bills from before credits were tracked have neither field stored
+    let query = "UPDATE type::thing('bill', $id) SET credited_amount = (credited_amount OR 0) + $amount, amount_paid = (amount_paid OR 0) - $settled WHERE (amount_paid OR 0) = $paid AND (credited_amount OR 0) = $credited";
+    let res = db
+        .query(query)
+        .bind(("id", &bill.id))
+        .bind(("amount", amount))
+        .bind(("settled", settled))
+        .bind(("paid", bill.amount_paid))
+        .bind(("credited", bill.credited_amount))
+        .await?;
+    let updated = res
+        .get(0)
+        .and_then(|r| r.result::<Vec<serde_json::Value>>().ok())
+        .is_some_and(|rows| !rows.is_empty());
+    Ok(updated.then_some((applied, settled)))
+}
+
+/// Undoes `credit_bill` when the credit note could not be saved.
+async fn uncredit_bill(
+    db: &Surreal<Client>,
+    bill_id: &str,
+    amount: f64,
+    settled: f64,
+) -> Result<(), surrealdb::Error> {
+    let query =
+        "UPDATE type::thing('bill', $id) SET credited_amount -= $amount, amount_paid += $settled";
+    db.query(query)
+        .bind(("id", bill_id))
+        .bind(("amount", amount))
+        .bind(("settled", settled))
+        .await?;
+    Ok(())
+}
+
+pub async fn create_credit_note(req: web::Json<CreateCreditNoteRequest>) -> impl Responder {
+    let db = get_db().await;
+    if req.lines.is_empty() {
+        return HttpResponse::BadRequest().body("A credit note needs at least one line");
+    }
+    if req.reason.trim().is_empty() {
+        return HttpResponse::BadRequest().body("A reason is required");
+    }
+    let _guard = lock_stock().await;
+    let Some(bill) = get_bill(db, &req.bill_id).await else {
+        return HttpResponse::NotFound().body("Bill not found");
+    };
+    if bill.status != BillStatus::Issued {
+        return HttpResponse::Conflict().body("Only issued bills can be credited");
+    }
+    if req.settlement == Settlement::AccountCredit
+        && get_customer_by_name(db, &bill.customer_name)
+            .await
+            .is_none()
+    {
+        return HttpResponse::BadRequest()
+            .body("Credit can only be kept for customers with an account");
+    }
+    let mut earlier = match earlier_lines(db, &bill.id).await {
+        Ok(earlier) => earlier,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to load credit notes: {}", e));
+        }
+    };
+    let mut credited: HashMap<usize, i32> = HashMap::new();
+    for line in &earlier {
+        *credited.entry(line.line_index).or_insert(0) += line.quantity;
+    }
+
+    let mut lines = req.lines.clone();
+    for line in lines.iter_mut() {
+        let Some(bill_line) = bill.items.get(line.line_index) else {
+            return HttpResponse::BadRequest()
+                .body(format!("Bill has no line {}", line.line_index));
+        };
+        let already_credited = credited.get(&line.line_index).copied().unwrap_or(0);
+        if let Err(msg) = validate_line(db, line, bill_line, already_credited).await {
+            return HttpResponse::BadRequest().body(msg);
+        }
+        // Counts lines crediting the same bill line twice in one request
+        credited.insert(line.line_index, already_credited + line.quantity);
+        // Refunds are at what was actually paid, after discounts
+        line.unit_price = bill_line.net_amount() / bill_line.quantity as f64;
+        line.tax_amount = line_tax(line.unit_price * line.quantity as f64, bill_line.tax_rate);
+        line.lots = Vec::new();
+    }
+
+    let net_amount: f64 = lines
+        .iter()
+        .map(|line| line.unit_price * line.quantity as f64)
+        .sum();
+    let tax_amount: f64 = lines.iter().map(|line| line.tax_amount).sum();
+    let total_amount = net_amount + tax_amount;
+    let (applied_amount, settled_amount) = match credit_bill(db, &bill, total_amount).await {
+        Ok(Some(amounts)) => amounts,
+        Ok(None) => return HttpResponse::Conflict().body("Bill changed, try again"),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to credit bill: {}", e));
+        }
+    };
+    let mut note = CreditNote {
+        id: uuid::Uuid::new_v4().to_string(),
+        bill_id: bill.id.clone(),
+        invoice_number: bill.invoice_number.clone(),
+        customer_name: bill.customer_name.clone(),
+        lines,
+        net_amount,
+        tax_amount,
+        total_amount,
+        settlement: req.settlement,
+        applied_amount,
+        settled_amount,
+        reason: req.reason.clone(),
+        created_at: Utc::now(),
+    };
+    // The note is saved before any stock comes back so a failed restock can't
+    // be repeated by raising the same credit again
+    if let Err(e) = db
+        .create::<_, CreditNote>("credit_note")
+        .content(&note)
+        .await
+    {
+        if let Err(e) = uncredit_bill(db, &bill.id, total_amount, settled_amount).await {
+            eprintln!("Failed to take credit back off bill {}: {e}", bill.id);
+        }
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to create credit note: {}", e));
+    }
+    for line in note.lines.iter_mut() {
+        let bill_line = &bill.items[line.line_index];
+        match take_back(
+            db,
+            &note.id,
+            &bill.id,
+            line,
+            bill_line,
+            &earlier,
+            req.location.as_deref(),
+        )
+        .await
+        {
+            Ok(cost) => {
+                line.cost = cost;
+                // Lines crediting the same bill line share its lots
+                earlier.push(line.clone());
+            }
+            Err(e) => {
+                return HttpResponse::InternalServerError().body(format!(
+                    "Credit note {} was created but restocking failed: {}",
+                    note.id, e
+                ));
+            }
+        }
+    }
+    let query = "UPDATE type::thing('credit_note', $id) SET lines = $lines";
+    if let Err(e) = db
+        .query(query)
+        .bind(("id", &note.id))
+        .bind(("lines", &note.lines))
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to update credit note: {}", e));
+    }
+    if let Err(e) = post_reversal(db, &note, &bill).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to post credit note: {}", e));
+    }
+    if note.settlement == Settlement::AccountCredit && note.settled_amount > 0.0 {
+        // Account credit is kept in the base currency
+        let query = "UPDATE customer SET credit_balance += $amount WHERE name = $name";
+        if let Err(e) = db
+            .query(query)
+            .bind((
+                "amount",
+                round_cents(note.settled_amount * bill.exchange_rate),
+            ))
+            .bind(("name", &note.customer_name))
+            .await
+        {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to credit customer account: {}", e));
+        }
+    }
+    HttpResponse::Ok().json(note)
+}
+
+pub async fn list_credit_notes(
+    params: web::Query<ListParams>,
+    filters: web::Query<CreditNoteFilters>,
+) -> impl Responder {
+    let db = get_db().await;
+    let mut query = ListQuery::new(
+        "credit_note",
+        &["customer_name", "invoice_number", "reason"],
+        &["created_at", "total_amount", "customer_name"],
+    );
+    query.filter("bill_id", "=", filters.bill_id.as_ref());
+    query.filter("customer_name", "=", filters.customer_name.as_ref());
+    match query.fetch::<CreditNote>(db, &params).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list credit notes: {}", e))
+        }
+    }
+}
+
+pub async fn get_credit_note_by_id(path: web::Path<String>) -> impl Responder {
+    let db = get_db().await;
+    match get_credit_note(db, &path.into_inner()).await {
+        Some(note) => HttpResponse::Ok().json(note),
+        None => HttpResponse::NotFound().body("Credit note not found"),
+    }
+}
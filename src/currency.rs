@@ -0,0 +1,239 @@
+use crate::db::DB;
+use crate::listing::{ListParams, ListQuery};
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+/// Currency the books are kept in, from `BASE_CURRENCY` (default INR).
+pub fn base_currency() -> String {
+    std::env::var("BASE_CURRENCY")
+        .map(|code| code.to_uppercase())
+        .unwrap_or_else(|_| "INR".to_string())
+}
+
+pub fn unit_rate() -> f64 {
+    1.0
+}
+
+/// Upper-cases a three-letter ISO 4217 code.
+pub fn normalize_currency(code: &str) -> Result<String, String> {
+    let code = code.trim().to_uppercase();
+    if code.len() != 3 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(format!("Invalid currency code {}", code));
+    }
+    Ok(code)
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateSource {
+    Manual,
+    Import,
+}
+
+/// Base-currency units for one unit of `currency`, from `date` until the
+/// next rate. One rate per currency per day; setting it again replaces it.
+#[derive(Serialize, Deserialize)]
+pub struct ExchangeRate {
+    pub currency: String,
+    pub date: NaiveDate,
+    pub rate: f64,
+    pub source: RateSource,
+    #[serde(with = "crate::dates::datetime")]
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct SetRateRequest {
+    pub currency: String,
+    pub date: NaiveDate,
+    pub rate: f64,
+}
+
+#[derive(Deserialize)]
+pub struct ImportRatesRequest {
+    // CSV file in EXCHANGE_RATE_DIR with `currency,date,rate` lines
+    pub file_name: String,
+}
+
+#[derive(Deserialize)]
+pub struct RateFilters {
+    pub currency: Option<String>,
+    pub date_from: Option<NaiveDate>,
+    pub date_to: Option<NaiveDate>,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+/// Rate in force for `currency` on `date`; always 1 for the base currency.
+pub async fn rate_on(
+    db: &Surreal<Client>,
+    currency: &str,
+    date: NaiveDate,
+) -> Result<Option<f64>, surrealdb::Error> {
+    if currency == base_currency() {
+        return Ok(Some(1.0));
+    }
+    let query = "SELECT * FROM exchange_rate WHERE currency = $currency AND date <= $date ORDER BY date DESC LIMIT 1";
+    let res = db
+        .query(query)
+        .bind(("currency", currency))
+        .bind(("date", date))
+        .await?;
+    Ok(res
+        .get(0)
+        .and_then(|r| r.result::<Vec<ExchangeRate>>().ok())
+        .and_then(|rates| rates.into_iter().next())
+        .map(|rate| rate.rate))
+}
+
+async fn save_rate(db: &Surreal<Client>, rate: &ExchangeRate) -> Result<(), surrealdb::Error> {
+    let query = "UPDATE type::thing('exchange_rate', $id) CONTENT $rate";
+    db.query(query)
+        .bind(("id", (&rate.currency, rate.date)))
+        .bind(("rate", rate))
+        .await?;
+    Ok(())
+}
+
+fn validate_rate(currency: &str, rate: f64) -> Result<String, String> {
+    let currency = normalize_currency(currency)?;
+    if currency == base_currency() {
+        return Err("The base currency has no exchange rate".to_string());
+    }
+    if !(rate.is_finite() && rate > 0.0) {
+        return Err("Exchange rate must be positive".to_string());
+    }
+    Ok(currency)
+}
+
+fn parse_rate_line(line: &str) -> Result<ExchangeRate, String> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    let [currency, date, rate] = fields[..] else {
+        return Err("expected currency,date,rate".to_string());
+    };
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| format!("invalid date {}", date))?;
+    let rate = rate
+        .parse::<f64>()
+        .map_err(|_| format!("invalid rate {}", rate))?;
+    Ok(ExchangeRate {
+        currency: validate_rate(currency, rate)?,
+        date,
+        rate,
+        source: RateSource::Import,
+        updated_at: Utc::now(),
+    })
+}
+
+pub async fn set_rate(req: web::Json<SetRateRequest>) -> impl Responder {
+    let db = get_db().await;
+    let currency = match validate_rate(&req.currency, req.rate) {
+        Ok(currency) => currency,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    let rate = ExchangeRate {
+        currency,
+        date: req.date,
+        rate: req.rate,
+        source: RateSource::Manual,
+        updated_at: Utc::now(),
+    };
+    if let Err(e) = save_rate(db, &rate).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to save exchange rate: {}", e));
+    }
+    HttpResponse::Ok().json(rate)
+}
+
+/// Loads rates from a CSV file on the server. Nothing is saved unless every
+/// line is valid.
+pub async fn import_rates(req: web::Json<ImportRatesRequest>) -> impl Responder {
+    let db = get_db().await;
+    let file_name = req.file_name.trim();
+    // Only files directly inside the rates directory can be read
+    if file_name.is_empty() || file_name.contains(['/', '\\']) || file_name.starts_with('.') {
+        return HttpResponse::BadRequest().body("Invalid file name");
+    }
+    let dir = std::env::var("EXCHANGE_RATE_DIR").unwrap_or_else(|_| "exchange_rates".to_string());
+    let contents = match tokio::fs::read_to_string(std::path::Path::new(&dir).join(file_name)).await
+    {
+        Ok(contents) => contents,
+        Err(e) => {
+            return HttpResponse::BadRequest().body(format!("Failed to read {}: {}", file_name, e));
+        }
+    };
+
+    let mut rates = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.to_lowercase().starts_with("currency") {
+            continue;
+        }
+        match parse_rate_line(line) {
+            Ok(rate) => rates.push(rate),
+            Err(msg) => {
+                return HttpResponse::BadRequest().body(format!("Line {}: {}", number + 1, msg));
+            }
+        }
+    }
+    for rate in &rates {
+        if let Err(e) = save_rate(db, rate).await {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to save exchange rate: {}", e));
+        }
+    }
+    HttpResponse::Ok().body(format!("Imported {} exchange rates", rates.len()))
+}
+
+pub async fn list_rates(
+    params: web::Query<ListParams>,
+    filters: web::Query<RateFilters>,
+) -> impl Responder {
+    let db = get_db().await;
+    let mut query = ListQuery::new("exchange_rate", &["currency"], &["date", "currency"]);
+    let currency = filters.currency.as_ref().map(|code| code.to_uppercase());
+    query.filter("currency", "=", currency);
+    query.filter("date", ">=", filters.date_from);
+    query.filter("date", "<=", filters.date_to);
+    match query.fetch::<ExchangeRate>(db, &params).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to list exchange rates: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_rate_line() {
+        let rate = parse_rate_line(" usd , 2026-01-05 , 83.25 ").unwrap();
+        assert_eq!(rate.currency, "USD");
+        assert_eq!(rate.date, NaiveDate::from_ymd_opt(2026, 1, 5).unwrap());
+        assert_eq!(rate.rate, 83.25);
+        assert!(rate.source == RateSource::Import);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(parse_rate_line("USD,2026-01-05").is_err());
+        assert!(parse_rate_line("USD,2026-01-05,83,1").is_err());
+        assert!(parse_rate_line("USD,05/01/2026,83").is_err());
+        assert!(parse_rate_line("USD,2026-01-05,abc").is_err());
+        assert!(parse_rate_line("US,2026-01-05,83").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_rates_and_the_base_currency() {
+        assert!(parse_rate_line("USD,2026-01-05,0").is_err());
+        assert!(parse_rate_line("USD,2026-01-05,-2").is_err());
+        assert!(parse_rate_line("USD,2026-01-05,NaN").is_err());
+        assert!(parse_rate_line(&format!("{},2026-01-05,1", base_currency())).is_err());
+    }
+}
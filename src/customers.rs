@@ -0,0 +1,116 @@
+use crate::db::DB;
+use crate::pricing::get_price_list;
+use actix_web::{HttpResponse, Responder, web};
+use serde::{Deserialize, Serialize};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+#[derive(Serialize, Deserialize)]
+pub struct Customer {
+    pub id: String,
+    pub name: String, // matched against `Bill.customer_name`
+    pub email: Option<String>,
+    pub price_list_id: Option<String>,
+    // Store credit from returns, owed to the customer
+    #[serde(default)]
+    pub credit_balance: f64,
+}
+
+#[derive(Deserialize)]
+pub struct CreateCustomerRequest {
+    pub name: String,
+    pub email: Option<String>,
+    pub price_list_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AssignPriceListRequest {
+    pub customer_id: String,
+    pub price_list_id: Option<String>,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+pub async fn get_customer_by_name(db: &Surreal<Client>, name: &str) -> Option<Customer> {
+    let query = "SELECT * FROM customer WHERE name = $name LIMIT 1";
+    let res = db.query(query).bind(("name", name)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<Customer>>().ok()?;
+    result.into_iter().next()
+}
+
+async fn get_customer(db: &Surreal<Client>, id: &str) -> Option<Customer> {
+    let query = "SELECT * FROM type::thing('customer', $id)";
+    let res = db.query(query).bind(("id", id)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<Customer>>().ok()?;
+    result.into_iter().next()
+}
+
+pub async fn create_customer(req: web::Json<CreateCustomerRequest>) -> impl Responder {
+    let db = get_db().await;
+    if get_customer_by_name(db, &req.name).await.is_some() {
+        return HttpResponse::BadRequest().body("Customer already exists");
+    }
+    if let Some(price_list_id) = &req.price_list_id {
+        if get_price_list(db, price_list_id).await.is_none() {
+            return HttpResponse::BadRequest().body("Price list not found");
+        }
+    }
+    let customer = Customer {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: req.name.clone(),
+        email: req.email.clone(),
+        price_list_id: req.price_list_id.clone(),
+        credit_balance: 0.0,
+    };
+    if let Err(e) = db
+        .create::<_, Customer>("customer")
+        .content(&customer)
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to create customer: {}", e));
+    }
+    HttpResponse::Ok().json(customer)
+}
+
+pub async fn list_customers() -> impl Responder {
+    let db = get_db().await;
+    let query = "SELECT * FROM customer";
+    match db.query(query).await {
+        Ok(res) => {
+            let customers = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<Customer>>().ok())
+                .unwrap_or_default();
+            HttpResponse::Ok().json(customers)
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list customers: {}", e))
+        }
+    }
+}
+
+pub async fn assign_price_list(req: web::Json<AssignPriceListRequest>) -> impl Responder {
+    let db = get_db().await;
+    if get_customer(db, &req.customer_id).await.is_none() {
+        return HttpResponse::NotFound().body("Customer not found");
+    }
+    if let Some(price_list_id) = &req.price_list_id {
+        if get_price_list(db, price_list_id).await.is_none() {
+            return HttpResponse::BadRequest().body("Price list not found");
+        }
+    }
+    let query = "UPDATE type::thing('customer', $id) SET price_list_id = $price_list_id";
+    if let Err(e) = db
+        .query(query)
+        .bind(("id", &req.customer_id))
+        .bind(("price_list_id", &req.price_list_id))
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to assign price list: {}", e));
+    }
+    HttpResponse::Ok().body("Price list assigned successfully")
+}
@@ -0,0 +1,231 @@
+use crate::serials::SerialEvent;
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, Utc};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use std::str::FromStr;
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+// Business dates are stored as ISO strings ("2024-03-31"), which sort and
+// compare in date order
+const ISO_DATE: &str = "%Y-%m-%d";
+
+// Fields that held free text before dates were typed
+const LEGACY_DATE_FIELDS: [(&str, &str); 3] = [
+    ("bill", "date"),
+    ("ledger", "date"),
+    ("bill_version", "bill.date"),
+];
+
+// Timestamp fields, written as text before they were stored as datetimes
+const STORED_DATETIMES: [(&str, &str); 23] = [
+    ("reservation", "created_at"),
+    ("reservation", "expires_at"),
+    ("stock_movement", "at"),
+    ("purchase_order", "created_at"),
+    ("goods_receipt", "received_at"),
+    ("credit_note", "created_at"),
+    ("stock_count", "created_at"),
+    ("stock_count", "posted_at"),
+    ("ledger", "created_at"),
+    ("payment", "created_at"),
+    ("quotation", "created_at"),
+    ("recurring_bill", "created_at"),
+    ("bill", "created_at"),
+    ("bill_version", "replaced_at"),
+    ("bill_version", "bill.created_at"),
+    ("exchange_rate", "updated_at"),
+    ("attachment", "uploaded_at"),
+    ("assembly_order", "created_at"),
+    ("assembly_order", "completed_at"),
+    ("cost_layer", "received_at"),
+    ("cost_entry", "at"),
+    ("sales_order", "created_at"),
+    ("delivery_note", "delivered_at"),
+];
+
+#[derive(Deserialize)]
+struct StoredHistory {
+    id: String,
+    history: Vec<SerialEvent>,
+}
+
+#[derive(Deserialize)]
+struct StoredDate {
+    id: String,
+    date: Option<JsonValue>,
+}
+
+/// Offset business dates are reckoned in, from `BUSINESS_UTC_OFFSET`
+/// (e.g. "+05:30"); defaults to the server's local offset.
+pub fn business_offset() -> FixedOffset {
+    std::env::var("BUSINESS_UTC_OFFSET")
+        .ok()
+        .and_then(|offset| FixedOffset::from_str(offset.trim()).ok())
+        .unwrap_or_else(|| *Local::now().offset())
+}
+
+/// Calendar date of a moment in the business time zone.
+pub fn business_date(at: DateTime<Utc>) -> NaiveDate {
+    at.with_timezone(&business_offset()).date_naive()
+}
+
+/// First second of a business date, as a UTC timestamp.
+pub fn start_of_day(date: NaiveDate) -> DateTime<Utc> {
+    (date.and_hms_opt(0, 0, 0).unwrap() - business_offset()).and_utc()
+}
+
+/// Last second of a business date, as a UTC timestamp.
+pub fn end_of_day(date: NaiveDate) -> DateTime<Utc> {
+    (date.and_hms_opt(23, 59, 59).unwrap() - business_offset()).and_utc()
+}
+
+/// Today's date in the business time zone.
+pub fn today() -> NaiveDate {
+    business_date(Utc::now())
+}
+
+/// Whether old free-text dates like 03/04/2020 are day first, from
+/// `LEGACY_DATE_ORDER` ("dmy", the default, or "mdy").
+pub fn legacy_day_first() -> bool {
+    !matches!(
+        std::env::var("LEGACY_DATE_ORDER").as_deref().map(str::trim),
+        Ok("mdy")
+    )
+}
+
+/// Reads the date formats that turn up in old free-text dates: ISO dates
+/// and timestamps, and dates with `/`, `-` or `.` separators in the given
+/// day/month order.
+pub fn parse_legacy_date(text: &str, day_first: bool) -> Option<NaiveDate> {
+    let text = text.trim();
+    if let Ok(at) = DateTime::parse_from_rfc3339(text) {
+        return Some(business_date(at.with_timezone(&Utc)));
+    }
+    let formats = if day_first {
+        [ISO_DATE, "%Y/%m/%d", "%d/%m/%Y", "%d-%m-%Y", "%d.%m.%Y"]
+    } else {
+        [ISO_DATE, "%Y/%m/%d", "%m/%d/%Y", "%m-%d-%Y", "%m.%d.%Y"]
+    };
+    formats
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(text, format).ok())
+}
+
+/// Rewrites free-text dates left from before dates were typed into ISO form,
+/// so lists and date filters can read them. The original text is kept in
+/// `legacy_date`. Text that isn't a date is left as it is and reported, one
+/// line per record, for someone to correct.
+pub async fn migrate_legacy_dates(db: &Surreal<Client>) -> Result<Vec<String>, String> {
+    let day_first = legacy_day_first();
+    let mut unreadable = Vec::new();
+    for (table, field) in LEGACY_DATE_FIELDS {
+        let query = format!("SELECT id, {} AS date FROM {}", field, table);
+        let res = db.query(query).await.map_err(|e| e.to_string())?;
+        let rows = match res.get(0).map(|r| r.result::<Vec<StoredDate>>()) {
+            Some(Ok(rows)) => rows,
+            Some(Err(e)) => return Err(format!("Failed to read {}.{}: {}", table, field, e)),
+            None => Vec::new(),
+        };
+        for row in rows {
+            let text = match &row.date {
+                Some(JsonValue::String(text)) => text.clone(),
+                Some(other) => other.to_string(),
+                None => String::new(),
+            };
+            if NaiveDate::parse_from_str(&text, ISO_DATE).is_ok() {
+                continue;
+            }
+            let Some(date) = parse_legacy_date(&text, day_first) else {
+                unreadable.push(format!(
+                    "{} {} has an unreadable date {:?}",
+                    table, row.id, text
+                ));
+                continue;
+            };
+            let query = format!(
+                "UPDATE type::thing($table, $id) SET {} = $date, legacy_date = $legacy_date",
+                field
+            );
+            db.query(query)
+                .bind(("table", table))
+                .bind(("id", &row.id))
+                .bind(("date", date))
+                .bind(("legacy_date", &text))
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(unreadable)
+}
+
+/// Turns timestamps stored as text, as they were before `datetime` was used
+/// on every timestamp field, into SurrealDB datetimes.
+pub async fn migrate_datetimes(db: &Surreal<Client>) -> Result<Vec<String>, String> {
+    for (table, field) in STORED_DATETIMES {
+        let query = format!(
+            "UPDATE {table} SET {field} = <datetime> {field} WHERE {field} != NONE AND {field} != NULL"
+        );
+        let res = db.query(query).await.map_err(|e| e.to_string())?;
+        if let Some(Err(e)) = res.get(0).map(|r| r.result::<Vec<JsonValue>>()) {
+            return Err(format!("Failed to convert {}.{}: {}", table, field, e));
+        }
+    }
+    // Serial histories are lists, so each one is read and written back
+    let res = db
+        .query("SELECT id, history FROM serial")
+        .await
+        .map_err(|e| e.to_string())?;
+    let serials = match res.get(0).map(|r| r.result::<Vec<StoredHistory>>()) {
+        Some(Ok(serials)) => serials,
+        Some(Err(e)) => return Err(format!("Failed to read serial histories: {}", e)),
+        None => Vec::new(),
+    };
+    for serial in serials {
+        db.query("UPDATE type::thing('serial', $id) SET history = $history")
+            .bind(("id", &serial.id))
+            .bind(("history", &serial.history))
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(Vec::new())
+}
+
+/// Serde adapter that stores a `DateTime<Utc>` as a SurrealDB `datetime`.
+/// chrono on its own writes RFC 3339 text, whose varying fractional seconds
+/// make text comparison and sorting unreliable. JSON output is unchanged.
+pub mod datetime {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use surrealdb::sql::Datetime;
+
+    pub fn serialize<S: Serializer>(at: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+        Datetime::from(*at).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        Ok(Datetime::deserialize(deserializer)?.0)
+    }
+
+    /// The same for optional timestamps; pair with `#[serde(default)]`.
+    pub mod option {
+        use chrono::{DateTime, Utc};
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+        use surrealdb::sql::Datetime;
+
+        pub fn serialize<S: Serializer>(
+            at: &Option<DateTime<Utc>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            at.map(Datetime::from).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<DateTime<Utc>>, D::Error> {
+            Ok(Option::<Datetime>::deserialize(deserializer)?.map(|at| at.0))
+        }
+    }
+}
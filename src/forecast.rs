@@ -0,0 +1,466 @@
+use crate::billing::{Bill, stock_demand};
+use crate::dates::today;
+use crate::db::DB;
+use crate::inventory::{InventoryItem, get_item};
+use crate::purchasing::{PurchaseOrder, PurchaseOrderStatus, get_supplier};
+use crate::reservations::reserved_by_item;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{Datelike, Months, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+const DAYS_PER_MONTH: f64 = 30.0;
+// Longest lead time, cover or safety period a suggestion is planned over
+pub const MAX_PLANNING_DAYS: i64 = 3650;
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForecastMethod {
+    #[default]
+    MovingAverage,
+    ExponentialSmoothing,
+}
+
+#[derive(Deserialize)]
+pub struct ForecastQuery {
+    pub item_id: Option<String>,
+    #[serde(default)]
+    pub method: ForecastMethod,
+    // Months of sales history to look at
+    pub history_months: Option<u32>,
+    // Months averaged by the moving average
+    pub window: Option<usize>,
+    // Smoothing factor for exponential smoothing, between 0 and 1
+    pub alpha: Option<f64>,
+    // Scale each forecast month by how that calendar month sold in the past
+    #[serde(default)]
+    pub seasonal: bool,
+    // Months ahead to forecast
+    pub horizon: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct SuggestionQuery {
+    // Used for items with no supplier lead time on record
+    pub lead_time_days: Option<i64>,
+    // Days of demand each purchase should cover after it arrives
+    pub cover_days: Option<i64>,
+    pub safety_days: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct MonthlyDemand {
+    pub month: String,
+    pub quantity: f64,
+}
+
+#[derive(Serialize)]
+pub struct ItemForecast {
+    pub item_id: String,
+    pub item_name: String,
+    pub method: ForecastMethod,
+    pub history: Vec<MonthlyDemand>,
+    pub forecast: Vec<MonthlyDemand>,
+}
+
+#[derive(Serialize)]
+pub struct PurchaseSuggestion {
+    pub item_id: String,
+    pub item_name: String,
+    pub supplier_id: Option<String>,
+    pub lead_time_days: i64,
+    pub forecast_demand: f64,
+    pub available: i32,
+    pub on_order: i32,
+    pub suggested_quantity: i32,
+    pub last_unit_cost: Option<f64>,
+}
+
+/// Most recent purchase of an item, for its supplier and price.
+struct LastPurchase {
+    supplier_id: String,
+    unit_cost: f64,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+fn month_start(date: NaiveDate) -> NaiveDate {
+    date.with_day(1).unwrap_or(date)
+}
+
+fn month_label(month: NaiveDate) -> String {
+    month.format("%Y-%m").to_string()
+}
+
+/// Units sold per item for each of the last `months` whole calendar months,
+/// oldest first. Bundles count as demand for their components.
+async fn monthly_sales(
+    db: &Surreal<Client>,
+    months: u32,
+) -> Result<(Vec<NaiveDate>, HashMap<String, Vec<f64>>), surrealdb::Error> {
+    let this_month = month_start(today());
+    let first = this_month - Months::new(months);
+    let periods: Vec<NaiveDate> = (0..months).map(|i| first + Months::new(i)).collect();
+
+    let query =
+        "SELECT * FROM bill WHERE date >= $from AND date < $to AND status NOT IN ['draft', 'void']";
+    let res = db
+        .query(query)
+        .bind(("from", first))
+        .bind(("to", this_month))
+        .await?;
+    let bills = match res.get(0) {
+        Some(r) => r.result::<Vec<Bill>>()?,
+        None => Vec::new(),
+    };
+
+    let mut sales: HashMap<String, Vec<f64>> = HashMap::new();
+    for bill in bills {
+        let Some(period) = periods.iter().position(|p| *p == month_start(bill.date)) else {
+            continue;
+        };
+        for (item_id, quantity) in stock_demand(&bill.items) {
+            sales
+                .entry(item_id)
+                .or_insert_with(|| vec![0.0; periods.len()])[period] += quantity as f64;
+        }
+    }
+    Ok((periods, sales))
+}
+
+/// Level of demand at the end of the history, before any seasonal adjustment.
+fn base_level(history: &[f64], query: &ForecastQuery) -> f64 {
+    if history.is_empty() {
+        return 0.0;
+    }
+    match query.method {
+        ForecastMethod::MovingAverage => {
+            let window = query.window.unwrap_or(3).clamp(1, history.len());
+            history[history.len() - window..].iter().sum::<f64>() / window as f64
+        }
+        ForecastMethod::ExponentialSmoothing => {
+            let alpha = query.alpha.unwrap_or(0.3);
+            history[1..].iter().fold(history[0], |level, actual| {
+                alpha * actual + (1.0 - alpha) * level
+            })
+        }
+    }
+}
+
+/// Ratio of each calendar month's average sales to the overall average.
+/// Months with no history get a neutral 1.0.
+fn seasonal_indices(periods: &[NaiveDate], history: &[f64]) -> [f64; 12] {
+    let mut indices = [1.0; 12];
+    let overall = history.iter().sum::<f64>() / history.len().max(1) as f64;
+    if overall == 0.0 {
+        return indices;
+    }
+    let mut totals = [(0.0, 0); 12];
+    for (period, quantity) in periods.iter().zip(history) {
+        let slot = &mut totals[period.month0() as usize];
+        slot.0 += quantity;
+        slot.1 += 1;
+    }
+    for (index, (total, count)) in indices.iter_mut().zip(totals) {
+        if count > 0 {
+            *index = total / count as f64 / overall;
+        }
+    }
+    indices
+}
+
+fn forecast_item(
+    item: &InventoryItem,
+    periods: &[NaiveDate],
+    history: &[f64],
+    query: &ForecastQuery,
+) -> ItemForecast {
+    let level = base_level(history, query);
+    let indices = if query.seasonal {
+        seasonal_indices(periods, history)
+    } else {
+        [1.0; 12]
+    };
+    let next_month = month_start(today());
+    let forecast = (0..query.horizon.unwrap_or(3))
+        .map(|i| {
+            let month = next_month + Months::new(i);
+            MonthlyDemand {
+                month: month_label(month),
+                quantity: level * indices[month.month0() as usize],
+            }
+        })
+        .collect();
+    ItemForecast {
+        item_id: item.id.clone(),
+        item_name: item.name.clone(),
+        method: query.method,
+        history: periods
+            .iter()
+            .zip(history)
+            .map(|(month, quantity)| MonthlyDemand {
+                month: month_label(*month),
+                quantity: *quantity,
+            })
+            .collect(),
+        forecast,
+    }
+}
+
+fn validate_query(query: &ForecastQuery) -> Result<(), String> {
+    if query
+        .history_months
+        .is_some_and(|months| months == 0 || months > 120)
+    {
+        return Err("History must be between 1 and 120 months".to_string());
+    }
+    if query.window == Some(0) {
+        return Err("Window must be at least one month".to_string());
+    }
+    if query
+        .alpha
+        .is_some_and(|alpha| !(alpha > 0.0 && alpha <= 1.0))
+    {
+        return Err("Alpha must be greater than 0 and at most 1".to_string());
+    }
+    if query
+        .horizon
+        .is_some_and(|months| months == 0 || months > 24)
+    {
+        return Err("Horizon must be between 1 and 24 months".to_string());
+    }
+    Ok(())
+}
+
+async fn load_forecasts(
+    db: &Surreal<Client>,
+    query: &ForecastQuery,
+) -> Result<Vec<(InventoryItem, ItemForecast)>, surrealdb::Error> {
+    // Seasonality needs at least two of each calendar month to mean anything
+    let default_months = if query.seasonal { 24 } else { 12 };
+    let (periods, sales) =
+        monthly_sales(db, query.history_months.unwrap_or(default_months)).await?;
+    let items = match &query.item_id {
+        Some(item_id) => get_item(db, item_id).await.into_iter().collect(),
+        None => {
+            let res = db.query("SELECT * FROM inventory").await?;
+            res.get(0)
+                .and_then(|r| r.result::<Vec<InventoryItem>>().ok())
+                .unwrap_or_default()
+        }
+    };
+    let empty = vec![0.0; periods.len()];
+    Ok(items
+        .into_iter()
+        // Bundles hold no stock; their sales already count against components
+        .filter(|item| !item.is_bundle())
+        .map(|item| {
+            let history = sales.get(&item.id).unwrap_or(&empty);
+            let forecast = forecast_item(&item, &periods, history, query);
+            (item, forecast)
+        })
+        .collect())
+}
+
+/// Units still to arrive on open purchase orders, plus the latest supplier
+/// and cost each item was bought at.
+async fn open_purchases(
+    db: &Surreal<Client>,
+) -> Result<(HashMap<String, i32>, HashMap<String, LastPurchase>), surrealdb::Error> {
+    let res = db
+        .query("SELECT * FROM purchase_order ORDER BY created_at ASC")
+        .await?;
+    let orders = res
+        .get(0)
+        .and_then(|r| r.result::<Vec<PurchaseOrder>>().ok())
+        .unwrap_or_default();
+    let mut on_order = HashMap::new();
+    let mut last = HashMap::new();
+    for order in orders {
+        for line in &order.lines {
+            if order.status != PurchaseOrderStatus::Closed {
+                *on_order.entry(line.item_id.clone()).or_insert(0) +=
+                    (line.quantity - line.received_quantity).max(0);
+            }
+            last.insert(
+                line.item_id.clone(),
+                LastPurchase {
+                    supplier_id: order.supplier_id.clone(),
+                    unit_cost: line.unit_cost,
+                },
+            );
+        }
+    }
+    Ok((on_order, last))
+}
+
+/// Forecast demand over the next `days`, taking each forecast month in turn;
+/// past the end of the forecast the last month's rate carries on.
+fn demand_over(forecast: &[MonthlyDemand], days: i64) -> f64 {
+    let days = days.max(0) as f64;
+    let mut demand = 0.0;
+    for (month, monthly) in forecast.iter().enumerate() {
+        let start = month as f64 * DAYS_PER_MONTH;
+        if start >= days {
+            break;
+        }
+        demand += (days - start).min(DAYS_PER_MONTH) * monthly.quantity / DAYS_PER_MONTH;
+    }
+    let beyond = days - forecast.len() as f64 * DAYS_PER_MONTH;
+    if let Some(last) = forecast.last().filter(|_| beyond > 0.0) {
+        demand += beyond * last.quantity / DAYS_PER_MONTH;
+    }
+    demand
+}
+
+pub async fn demand_forecast(query: web::Query<ForecastQuery>) -> impl Responder {
+    let db = get_db().await;
+    if let Err(msg) = validate_query(&query) {
+        return HttpResponse::BadRequest().body(msg);
+    }
+    match load_forecasts(db, &query).await {
+        Ok(forecasts) => {
+            let forecasts: Vec<ItemForecast> = forecasts
+                .into_iter()
+                .map(|(_, forecast)| forecast)
+                .collect();
+            HttpResponse::Ok().json(forecasts)
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to forecast demand: {}", e))
+        }
+    }
+}
+
+/// What to order now so stock lasts through the supplier's lead time and the
+/// cover period after it, net of free stock and what is already on order.
+pub async fn purchase_suggestions(
+    forecast_query: web::Query<ForecastQuery>,
+    query: web::Query<SuggestionQuery>,
+) -> impl Responder {
+    let db = get_db().await;
+    if let Err(msg) = validate_query(&forecast_query) {
+        return HttpResponse::BadRequest().body(msg);
+    }
+    let default_lead_time = query.lead_time_days.unwrap_or(14);
+    let cover_days = query.cover_days.unwrap_or(30);
+    let safety_days = query.safety_days.unwrap_or(0);
+    if [default_lead_time, cover_days, safety_days]
+        .iter()
+        .any(|days| !(0..=MAX_PLANNING_DAYS).contains(days))
+    {
+        return HttpResponse::BadRequest().body(format!(
+            "Day counts must be between 0 and {}",
+            MAX_PLANNING_DAYS
+        ));
+    }
+
+    let forecasts = match load_forecasts(db, &forecast_query).await {
+        Ok(forecasts) => forecasts,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to forecast demand: {}", e));
+        }
+    };
+    let reserved = match reserved_by_item(db).await {
+        Ok(reserved) => reserved,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to load reservations: {}", e));
+        }
+    };
+    let (on_order, last_purchases) = match open_purchases(db).await {
+        Ok(purchases) => purchases,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to load purchase orders: {}", e));
+        }
+    };
+
+    let mut suppliers = HashMap::new();
+    let mut suggestions = Vec::new();
+    for (item, forecast) in forecasts {
+        let last = last_purchases.get(&item.id);
+        let mut lead_time_days = default_lead_time;
+        if let Some(last) = last {
+            if !suppliers.contains_key(&last.supplier_id) {
+                let supplier = get_supplier(db, &last.supplier_id).await;
+                suppliers.insert(last.supplier_id.clone(), supplier);
+            }
+            if let Some(Some(supplier)) = suppliers.get(&last.supplier_id) {
+                lead_time_days = supplier
+                    .lead_time_days
+                    .unwrap_or(default_lead_time)
+                    .clamp(0, MAX_PLANNING_DAYS);
+            }
+        }
+        let forecast_demand = demand_over(
+            &forecast.forecast,
+            lead_time_days + cover_days + safety_days,
+        );
+        let available = item.quantity - reserved.get(&item.id).copied().unwrap_or(0);
+        let on_order = on_order.get(&item.id).copied().unwrap_or(0);
+        let shortfall = forecast_demand - (available + on_order) as f64;
+        if shortfall <= 0.0 {
+            continue;
+        }
+        suggestions.push(PurchaseSuggestion {
+            item_id: item.id,
+            item_name: item.name,
+            supplier_id: last.map(|last| last.supplier_id.clone()),
+            lead_time_days,
+            forecast_demand,
+            available,
+            on_order,
+            suggested_quantity: shortfall.ceil() as i32,
+            last_unit_cost: last.map(|last| last.unit_cost),
+        });
+    }
+    suggestions.sort_by(|a, b| b.suggested_quantity.cmp(&a.suggested_quantity));
+    HttpResponse::Ok().json(suggestions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forecast(quantities: &[f64]) -> Vec<MonthlyDemand> {
+        quantities
+            .iter()
+            .enumerate()
+            .map(|(i, quantity)| MonthlyDemand {
+                month: format!("2026-{:02}", i + 1),
+                quantity: *quantity,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn takes_each_month_in_turn() {
+        let forecast = forecast(&[30.0, 60.0]);
+        assert_eq!(demand_over(&forecast, 15), 15.0);
+        assert_eq!(demand_over(&forecast, 30), 30.0);
+        assert_eq!(demand_over(&forecast, 45), 60.0);
+        assert_eq!(demand_over(&forecast, 60), 90.0);
+    }
+
+    #[test]
+    fn last_month_carries_on() {
+        let forecast = forecast(&[30.0, 60.0]);
+        assert_eq!(demand_over(&forecast, 90), 150.0);
+        // Two forecast months, then 60 a month (2 a day) for the rest
+        let rest = (MAX_PLANNING_DAYS - 60) as f64;
+        assert_eq!(demand_over(&forecast, MAX_PLANNING_DAYS), 90.0 + rest * 2.0);
+    }
+
+    #[test]
+    fn nothing_without_days_or_forecast() {
+        assert_eq!(demand_over(&forecast(&[30.0]), 0), 0.0);
+        assert_eq!(demand_over(&forecast(&[30.0]), -10), 0.0);
+        assert_eq!(demand_over(&[], 90), 0.0);
+    }
+}
@@ -1,38 +1,281 @@
+use crate::costing::{CostingMethod, add_layer};
 use crate::db::DB;
+use crate::listing::{ListParams, ListQuery, Page};
+use crate::reorder::check_reorder_point;
+use crate::reservations::reserved_by_item;
+use crate::search::index_item;
 use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use surrealdb::Surreal;
 use surrealdb::engine::remote::ws::Client;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct InventoryItem {
     pub id: String,
     pub name: String,
+    pub sku: Option<String>,
     pub description: Option<String>,
     pub quantity: i32,
     pub price: f64,
+    pub category: Option<String>,
+    // Sales tax charged on top of the price, in percent
+    #[serde(default)]
+    pub tax_rate: f64,
+    #[serde(default)]
+    pub track_lots: bool,
+    #[serde(default)]
+    pub track_serials: bool,
+    #[serde(default)]
+    pub costing_method: CostingMethod,
+    #[serde(default)]
+    pub average_cost: f64,
+    // Bill of materials; an item with components is a bundle and holds no stock itself
+    #[serde(default)]
+    pub components: Vec<BundleComponent>,
+    // Attachment ids, newest last
+    #[serde(default)]
+    pub attachments: Vec<String>,
+}
+
+impl InventoryItem {
+    pub fn is_bundle(&self) -> bool {
+        !self.components.is_empty()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BundleComponent {
+    pub item_id: String,
+    pub quantity: i32, // units per bundle
+}
+
+/// An item as shown in listings, with stock held by reservations taken off.
+#[derive(Serialize)]
+pub struct InventoryItemListing {
+    #[serde(flatten)]
+    pub item: InventoryItem,
+    pub reserved: i32,
+    pub available: i32,
+}
+
+/// Stock of an item held at one location. The item's own `quantity` stays the
+/// total across all locations.
+#[derive(Serialize, Deserialize)]
+pub struct ItemLocationStock {
+    pub item_id: String,
+    pub location: String,
+    pub quantity: i32,
+}
+
+/// Audit record written for every change to an item's quantity.
+#[derive(Serialize, Deserialize)]
+pub struct StockMovement {
+    pub id: String,
+    pub item_id: String,
+    pub location: Option<String>,
+    pub quantity: i32,
+    pub reason: String, // e.g. "receipt", "sale", "return", "stock_count"
+    pub reference: String,
+    #[serde(with = "crate::dates::datetime")]
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct ItemFilters {
+    pub category: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ListMovementsQuery {
+    pub item_id: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct CreateItemRequest {
     pub name: String,
+    pub sku: Option<String>,
     pub description: Option<String>,
     pub quantity: i32,
     pub price: f64,
+    pub category: Option<String>,
+    #[serde(default)]
+    pub tax_rate: f64,
+    #[serde(default)]
+    pub track_lots: bool,
+    #[serde(default)]
+    pub track_serials: bool,
+    #[serde(default)]
+    pub costing_method: CostingMethod,
+    // Purchase cost of the opening quantity
+    pub unit_cost: Option<f64>,
+    #[serde(default)]
+    pub components: Vec<BundleComponent>,
 }
 
 async fn get_db() -> &'static Surreal<Client> {
     DB.get().expect("DB not initialized")
 }
 
+pub async fn get_item(db: &Surreal<Client>, item_id: &str) -> Option<InventoryItem> {
+    let query = "SELECT * FROM type::thing('inventory', $id)";
+    let res = db.query(query).bind(("id", item_id)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<InventoryItem>>().ok()?;
+    result.into_iter().next()
+}
+
+pub async fn get_location_quantity(
+    db: &Surreal<Client>,
+    item_id: &str,
+    location: &str,
+) -> Option<i32> {
+    let query = "SELECT * FROM type::thing('item_location', [$id, $location])";
+    let res = db
+        .query(query)
+        .bind(("id", item_id))
+        .bind(("location", location))
+        .await
+        .ok()?;
+    let result = res.get(0)?.result::<Vec<ItemLocationStock>>().ok()?;
+    Some(result.into_iter().next().map_or(0, |stock| stock.quantity))
+}
+
+/// Every stock change goes through here so it is recorded as a movement and
+/// the reorder check sees it.
+pub async fn adjust_quantity(
+    db: &Surreal<Client>,
+    item_id: &str,
+    location: Option<&str>,
+    delta: i32,
+    reason: &str,
+    reference: &str,
+) -> Result<(), surrealdb::Error> {
+    let query = "UPDATE type::thing('inventory', $id) SET quantity += $delta";
+    db.query(query)
+        .bind(("id", item_id))
+        .bind(("delta", delta))
+        .await?;
+    if let Some(location) = location {
+        let query = "UPDATE type::thing('item_location', [$id, $location]) SET item_id = $id, location = $location, quantity += $delta";
+        db.query(query)
+            .bind(("id", item_id))
+            .bind(("location", location))
+            .bind(("delta", delta))
+            .await?;
+    }
+    let movement = StockMovement {
+        id: uuid::Uuid::new_v4().to_string(),
+        item_id: item_id.to_string(),
+        location: location.map(str::to_string),
+        quantity: delta,
+        reason: reason.to_string(),
+        reference: reference.to_string(),
+        at: Utc::now(),
+    };
+    db.create::<_, StockMovement>("stock_movement")
+        .content(&movement)
+        .await?;
+
+    let item_id = item_id.to_string();
+    let location = location.map(str::to_string);
+    tokio::spawn(async move {
+        check_reorder_point(&item_id, location.as_deref()).await;
+    });
+    Ok(())
+}
+
+/// Location returned goods that can't be sold again are kept in.
+pub fn damaged_location() -> String {
+    std::env::var("DAMAGED_LOCATION").unwrap_or_else(|_| "damaged".to_string())
+}
+
+/// Books returned units that can't be sold again into the damaged goods
+/// location. Unlike `adjust_quantity` this leaves the item's sellable quantity
+/// alone, so the units are neither available nor valued.
+pub async fn book_damaged(
+    db: &Surreal<Client>,
+    item_id: &str,
+    quantity: i32,
+    reference: &str,
+) -> Result<(), surrealdb::Error> {
+    let location = damaged_location();
+    let query = "UPDATE type::thing('item_location', [$id, $location]) SET item_id = $id, location = $location, quantity += $delta";
+    db.query(query)
+        .bind(("id", item_id))
+        .bind(("location", &location))
+        .bind(("delta", quantity))
+        .await?;
+    let movement = StockMovement {
+        id: uuid::Uuid::new_v4().to_string(),
+        item_id: item_id.to_string(),
+        location: Some(location),
+        quantity,
+        reason: "damaged_return".to_string(),
+        reference: reference.to_string(),
+        at: Utc::now(),
+    };
+    db.create::<_, StockMovement>("stock_movement")
+        .content(&movement)
+        .await?;
+    Ok(())
+}
+
 pub async fn create_item(req: web::Json<CreateItemRequest>) -> impl Responder {
     let db = get_db().await;
+    if !(0.0..=100.0).contains(&req.tax_rate) {
+        return HttpResponse::BadRequest().body("Tax rate must be between 0 and 100 percent");
+    }
+    if req.track_lots && req.track_serials {
+        return HttpResponse::BadRequest()
+            .body("An item can track either lots or serials, not both");
+    }
+    // Tracked stock has to arrive with a lot or serial number, so it goes through
+    // /lots/receive or /serials/receive
+    if (req.track_lots || req.track_serials) && req.quantity != 0 {
+        return HttpResponse::BadRequest()
+            .body("Lot or serial tracked items must be created with zero quantity");
+    }
+    if !req.components.is_empty() {
+        if req.quantity != 0 || req.track_lots || req.track_serials {
+            return HttpResponse::BadRequest()
+                .body("Bundles hold no stock of their own and cannot be lot or serial tracked");
+        }
+        for component in &req.components {
+            if component.quantity <= 0 {
+                return HttpResponse::BadRequest().body("Component quantities must be positive");
+            }
+            match get_item(db, &component.item_id).await {
+                // Component stock is picked without lot or serial numbers
+                Some(c) if c.is_bundle() || c.track_lots || c.track_serials => {
+                    return HttpResponse::BadRequest().body(format!(
+                        "Component {} cannot be a bundle or a lot/serial tracked item",
+                        c.name
+                    ));
+                }
+                Some(_) => {}
+                None => {
+                    return HttpResponse::BadRequest()
+                        .body(format!("Component {} not found", component.item_id));
+                }
+            }
+        }
+    }
     let item = InventoryItem {
         id: uuid::Uuid::new_v4().to_string(),
         name: req.name.clone(),
+        sku: req.sku.clone(),
         description: req.description.clone(),
         quantity: req.quantity,
         price: req.price,
+        category: req.category.clone(),
+        tax_rate: req.tax_rate,
+        track_lots: req.track_lots,
+        track_serials: req.track_serials,
+        costing_method: req.costing_method,
+        average_cost: req.unit_cost.unwrap_or(0.0),
+        components: req.components.clone(),
+        attachments: Vec::new(),
     };
     if let Err(e) = db
         .create::<_, InventoryItem>("inventory")
@@ -41,20 +284,179 @@ pub async fn create_item(req: web::Json<CreateItemRequest>) -> impl Responder {
     {
         return HttpResponse::InternalServerError().body(format!("Failed to create item: {}", e));
     }
+    if item.quantity > 0 {
+        if let Err(e) = add_layer(db, &item.id, item.quantity, item.average_cost, "opening").await {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to record opening cost: {}", e));
+        }
+    }
+    index_item(&item).await;
     HttpResponse::Ok().json(item)
 }
 
-pub async fn list_items() -> impl Responder {
+pub async fn list_items(
+    params: web::Query<ListParams>,
+    filters: web::Query<ItemFilters>,
+) -> impl Responder {
     let db = get_db().await;
-    let query = "SELECT * FROM inventory";
-    match db.query(query).await {
+    let reserved = match reserved_by_item(db).await {
+        Ok(reserved) => reserved,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to load reservations: {}", e));
+        }
+    };
+    let mut query = ListQuery::new(
+        "inventory",
+        &["name", "sku", "description"],
+        &["name", "sku", "price", "quantity", "category"],
+    );
+    query.filter("category", "=", filters.category.as_ref());
+    let page: Page<InventoryItem> = match query.fetch(db, &params).await {
+        Ok(page) => page,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to list items: {}", e));
+        }
+    };
+
+    let available_of =
+        |item: &InventoryItem| item.quantity - reserved.get(&item.id).copied().unwrap_or(0);
+    let mut items = Vec::new();
+    for item in page.items {
+        let available = if item.is_bundle() {
+            // Components are usually not on the same page, so look them up
+            let mut components = HashMap::new();
+            for component in &item.components {
+                if let Some(component_item) = get_item(db, &component.item_id).await {
+                    components.insert(component_item.id.clone(), available_of(&component_item));
+                }
+            }
+            bundle_availability(&item, &components)
+        } else {
+            available_of(&item)
+        };
+        items.push(InventoryItemListing {
+            available,
+            reserved: reserved.get(&item.id).copied().unwrap_or(0),
+            item,
+        });
+    }
+    HttpResponse::Ok().json(Page {
+        items,
+        total: page.total,
+        page: page.page,
+        per_page: page.per_page,
+    })
+}
+
+/// How many complete bundles the available component stock can make.
+fn bundle_availability(bundle: &InventoryItem, available: &HashMap<String, i32>) -> i32 {
+    bundle
+        .components
+        .iter()
+        .map(|component| {
+            available
+                .get(&component.item_id)
+                .copied()
+                .unwrap_or(0)
+                .max(0)
+                / component.quantity
+        })
+        .min()
+        .unwrap_or(0)
+}
+
+pub async fn list_movements(query: web::Query<ListMovementsQuery>) -> impl Responder {
+    let db = get_db().await;
+    let res = match &query.item_id {
+        Some(item_id) => {
+            db.query("SELECT * FROM stock_movement WHERE item_id = $item_id ORDER BY at DESC")
+                .bind(("item_id", item_id))
+                .await
+        }
+        None => {
+            db.query("SELECT * FROM stock_movement ORDER BY at DESC")
+                .await
+        }
+    };
+    match res {
         Ok(res) => {
-            let items = res
+            let movements = res
                 .get(0)
-                .and_then(|r| r.result::<Vec<InventoryItem>>().ok())
+                .and_then(|r| r.result::<Vec<StockMovement>>().ok())
                 .unwrap_or_default();
-            HttpResponse::Ok().json(items)
+            HttpResponse::Ok().json(movements)
+        }
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to list stock movements: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle(components: &[(&str, i32)]) -> InventoryItem {
+        InventoryItem {
+            id: "bundle".to_string(),
+            name: "Bundle".to_string(),
+            sku: None,
+            description: None,
+            quantity: 0,
+            price: 0.0,
+            category: None,
+            tax_rate: 0.0,
+            track_lots: false,
+            track_serials: false,
+            costing_method: CostingMethod::default(),
+            average_cost: 0.0,
+            components: components
+                .iter()
+                .map(|(item_id, quantity)| BundleComponent {
+                    item_id: item_id.to_string(),
+                    quantity: *quantity,
+                })
+                .collect(),
+            attachments: Vec::new(),
         }
-        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to list items: {}", e)),
+    }
+
+    fn available(stock: &[(&str, i32)]) -> HashMap<String, i32> {
+        stock
+            .iter()
+            .map(|(item_id, quantity)| (item_id.to_string(), *quantity))
+            .collect()
+    }
+
+    #[test]
+    fn limited_by_scarcest_component() {
+        let item = bundle(&[("a", 2), ("b", 1)]);
+        assert_eq!(
+            bundle_availability(&item, &available(&[("a", 7), ("b", 5)])),
+            3
+        );
+        assert_eq!(
+            bundle_availability(&item, &available(&[("a", 20), ("b", 4)])),
+            4
+        );
+    }
+
+    #[test]
+    fn missing_or_negative_component_stock_makes_none() {
+        let item = bundle(&[("a", 1), ("b", 1)]);
+        assert_eq!(bundle_availability(&item, &available(&[("a", 5)])), 0);
+        assert_eq!(
+            bundle_availability(&item, &available(&[("a", 5), ("b", -3)])),
+            0
+        );
+    }
+
+    #[test]
+    fn no_components_makes_none() {
+        assert_eq!(
+            bundle_availability(&bundle(&[]), &available(&[("a", 5)])),
+            0
+        );
     }
 }
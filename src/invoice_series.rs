@@ -0,0 +1,269 @@
+use crate::billing::Bill;
+use crate::db::DB;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{Datelike, NaiveDate};
+use serde::{Deserialize, Serialize};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+// Attempts at claiming the next number before giving up on a busy series
+const MAX_ALLOCATION_ATTEMPTS: usize = 10;
+
+/// A numbering sequence for invoices, e.g. one per branch. Numbers restart
+/// at 1 each fiscal year.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct InvoiceSeries {
+    pub id: String,
+    pub name: String,
+    pub prefix: String,
+    // Bills for this branch use the series; None is the fallback for all branches
+    pub branch: Option<String>,
+    // Month (1-12) the fiscal year starts in
+    pub fiscal_year_start_month: u32,
+    // Minimum digits in the sequence number, zero padded
+    pub padding: usize,
+}
+
+/// Last number handed out in a series for one fiscal year.
+#[derive(Serialize, Deserialize)]
+pub struct InvoiceCounter {
+    pub series_id: String,
+    pub fiscal_year: String,
+    pub last_number: i64,
+}
+
+#[derive(Deserialize)]
+pub struct CreateInvoiceSeriesRequest {
+    pub name: String,
+    pub prefix: String,
+    pub branch: Option<String>,
+    pub fiscal_year_start_month: Option<u32>,
+    pub padding: Option<usize>,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+async fn list_series(db: &Surreal<Client>) -> Result<Vec<InvoiceSeries>, surrealdb::Error> {
+    let res = db
+        .query("SELECT * FROM invoice_series ORDER BY name ASC")
+        .await?;
+    Ok(res
+        .get(0)
+        .and_then(|r| r.result::<Vec<InvoiceSeries>>().ok())
+        .unwrap_or_default())
+}
+
+/// The series a bill is numbered in: the one named by `series_id`, otherwise
+/// the branch's own series, otherwise the series without a branch.
+pub async fn find_series(
+    db: &Surreal<Client>,
+    series_id: Option<&str>,
+    branch: Option<&str>,
+) -> Result<Option<InvoiceSeries>, surrealdb::Error> {
+    let series = list_series(db).await?;
+    if let Some(series_id) = series_id {
+        return Ok(series.into_iter().find(|s| s.id == series_id));
+    }
+    let branch_series = series
+        .iter()
+        .find(|s| branch.is_some() && s.branch.as_deref() == branch);
+    Ok(branch_series
+        .or_else(|| series.iter().find(|s| s.branch.is_none()))
+        .cloned())
+}
+
+impl InvoiceSeries {
+    /// Label of the fiscal year `date` falls in, e.g. "2026" or "2026-27".
+    pub fn fiscal_year(&self, date: NaiveDate) -> String {
+        let start_year = if date.month() >= self.fiscal_year_start_month {
+            date.year()
+        } else {
+            date.year() - 1
+        };
+        if self.fiscal_year_start_month == 1 {
+            start_year.to_string()
+        } else {
+            format!("{}-{:02}", start_year, (start_year + 1) % 100)
+        }
+    }
+
+    fn format_number(&self, fiscal_year: &str, number: i64) -> String {
+        format!(
+            "{}{}/{:0width$}",
+            self.prefix,
+            fiscal_year,
+            number,
+            width = self.padding
+        )
+    }
+}
+
+async fn last_number(
+    db: &Surreal<Client>,
+    counter_id: &[String; 2],
+) -> Result<i64, surrealdb::Error> {
+    // Creating an existing counter fails harmlessly, so this only seeds new years
+    let _ = db
+        .query("CREATE type::thing('invoice_counter', $id) SET series_id = $id[0], fiscal_year = $id[1], last_number = 0")
+        .bind(("id", counter_id))
+        .await;
+    let res = db
+        .query("SELECT * FROM type::thing('invoice_counter', $id)")
+        .bind(("id", counter_id))
+        .await?;
+    Ok(res
+        .get(0)
+        .and_then(|r| r.result::<Vec<InvoiceCounter>>().ok())
+        .and_then(|counters| counters.into_iter().next())
+        .map_or(0, |counter| counter.last_number))
+}
+
+async fn stored_number(
+    db: &Surreal<Client>,
+    bill_id: &str,
+) -> Result<Option<String>, surrealdb::Error> {
+    let res = db
+        .query("SELECT * FROM type::thing('bill', $id)")
+        .bind(("id", bill_id))
+        .await?;
+    Ok(res
+        .get(0)
+        .and_then(|r| r.result::<Vec<Bill>>().ok())
+        .and_then(|bills| bills.into_iter().next())
+        .and_then(|bill| bill.invoice_number))
+}
+
+/// Numbers `bill` in `series` and stores it, replacing its draft if it has
+/// one. The counter bump and the bill are written in one transaction that
+/// only commits if nobody else took the number first, so numbers are never
+/// skipped or handed out twice, even across server instances.
+pub async fn create_numbered_bill(
+    db: &Surreal<Client>,
+    series: &InvoiceSeries,
+    date: NaiveDate,
+    bill: &mut Bill,
+) -> Result<(), String> {
+    let fiscal_year = series.fiscal_year(date);
+    let counter_id = [series.id.clone(), fiscal_year.clone()];
+    let sql = "BEGIN TRANSACTION;
+        LET $claimed = UPDATE type::thing('invoice_counter', $counter_id) SET last_number = $next WHERE last_number = $current;
+        IF array::len($claimed) = 0 { THROW 'Invoice number already taken' };
+        UPDATE type::thing('bill', $bill_id) CONTENT $bill;
+        COMMIT TRANSACTION;";
+    for _ in 0..MAX_ALLOCATION_ATTEMPTS {
+        let current = last_number(db, &counter_id)
+            .await
+            .map_err(|e| format!("Failed to read invoice counter: {}", e))?;
+        let next = current + 1;
+        bill.invoice_series_id = Some(series.id.clone());
+        bill.invoice_number = Some(series.format_number(&fiscal_year, next));
+        db.query(sql)
+            .bind(("counter_id", &counter_id))
+            .bind(("current", current))
+            .bind(("next", next))
+            .bind(("bill_id", &bill.id))
+            .bind(("bill", &*bill))
+            .await
+            .map_err(|e| format!("Failed to create bill: {}", e))?;
+        // A lost race rolls the whole transaction back, so the bill only
+        // carries the number if it was ours
+        match stored_number(db, &bill.id).await {
+            Ok(number) if number == bill.invoice_number => return Ok(()),
+            Ok(_) => continue,
+            Err(e) => return Err(format!("Failed to create bill: {}", e)),
+        }
+    }
+    Err(format!(
+        "Could not allocate an invoice number in series {}, try again",
+        series.name
+    ))
+}
+
+pub async fn create_series(req: web::Json<CreateInvoiceSeriesRequest>) -> impl Responder {
+    let db = get_db().await;
+    let fiscal_year_start_month = req.fiscal_year_start_month.unwrap_or(1);
+    if !(1..=12).contains(&fiscal_year_start_month) {
+        return HttpResponse::BadRequest().body("Fiscal year start month must be 1-12");
+    }
+    if req.prefix.trim().is_empty() {
+        return HttpResponse::BadRequest().body("Prefix must not be empty");
+    }
+    let existing = match list_series(db).await {
+        Ok(series) => series,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to list invoice series: {}", e));
+        }
+    };
+    // A series without a branch is the fallback, and there is only one
+    if existing.iter().any(|s| s.branch == req.branch) {
+        return HttpResponse::Conflict().body(match req.branch {
+            Some(_) => "That branch already has an invoice series",
+            None => "A fallback series already exists",
+        });
+    }
+    if existing.iter().any(|s| s.prefix == req.prefix) {
+        return HttpResponse::Conflict().body("Prefix is already used by another series");
+    }
+    let series = InvoiceSeries {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: req.name.clone(),
+        prefix: req.prefix.clone(),
+        branch: req.branch.clone(),
+        fiscal_year_start_month,
+        padding: req.padding.unwrap_or(5).min(12),
+    };
+    if let Err(e) = db
+        .create::<_, InvoiceSeries>("invoice_series")
+        .content(&series)
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to create invoice series: {}", e));
+    }
+    HttpResponse::Ok().json(series)
+}
+
+pub async fn list_invoice_series() -> impl Responder {
+    let db = get_db().await;
+    match list_series(db).await {
+        Ok(series) => HttpResponse::Ok().json(series),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to list invoice series: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(fiscal_year_start_month: u32) -> InvoiceSeries {
+        InvoiceSeries {
+            id: "series".to_string(),
+            name: "Main".to_string(),
+            prefix: "INV".to_string(),
+            branch: None,
+            fiscal_year_start_month,
+            padding: 4,
+        }
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn calendar_fiscal_year() {
+        assert_eq!(series(1).fiscal_year(date("2026-01-01")), "2026");
+        assert_eq!(series(1).fiscal_year(date("2026-12-31")), "2026");
+    }
+
+    #[test]
+    fn split_fiscal_year() {
+        assert_eq!(series(4).fiscal_year(date("2026-03-31")), "2025-26");
+        assert_eq!(series(4).fiscal_year(date("2026-04-01")), "2026-27");
+        assert_eq!(series(4).fiscal_year(date("1999-12-01")), "1999-00");
+    }
+}
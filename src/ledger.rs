@@ -1,5 +1,10 @@
+use crate::currency::{base_currency, normalize_currency, rate_on, unit_rate};
+use crate::dates::today;
 use crate::db::DB;
+use crate::listing::{ListParams, ListQuery};
+use crate::promotions::round_cents;
 use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use surrealdb::Surreal;
 use surrealdb::engine::remote::ws::Client;
@@ -9,49 +14,158 @@ pub struct LedgerEntry {
     pub id: String,
     pub description: String,
     pub amount: f64,
-    pub date: String,
+    pub date: NaiveDate,
     pub entry_type: String, // e.g., "debit" or "credit"
+    // `amount` is in this currency
+    #[serde(default = "base_currency")]
+    pub currency: String,
+    #[serde(default = "unit_rate")]
+    pub exchange_rate: f64,
+    // None on entries from before currencies were tracked, which are in the
+    // base currency
+    #[serde(default)]
+    pub base_amount: Option<f64>,
+    // When the entry was posted; None on entries from before it was recorded
+    #[serde(default, with = "crate::dates::datetime::option")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub attachments: Vec<String>,
 }
 
 #[derive(Deserialize)]
 pub struct CreateLedgerEntryRequest {
     pub description: String,
     pub amount: f64,
-    pub date: String,
+    // Defaults to today
+    pub date: Option<NaiveDate>,
     pub entry_type: String,
+    // Defaults to the base currency
+    pub currency: Option<String>,
+    // Defaults to the rate on the entry date
+    pub exchange_rate: Option<f64>,
+}
+
+#[derive(Deserialize)]
+pub struct LedgerFilters {
+    pub entry_type: Option<String>,
+    pub currency: Option<String>,
+    pub date_from: Option<NaiveDate>,
+    pub date_to: Option<NaiveDate>,
 }
 
 async fn get_db() -> &'static Surreal<Client> {
     DB.get().expect("DB not initialized")
 }
 
-pub async fn create_ledger_entry(req: web::Json<CreateLedgerEntryRequest>) -> impl Responder {
-    let db = get_db().await;
+/// Posts an entry on behalf of another module, e.g. a stock write-off.
+pub async fn post_entry(
+    db: &Surreal<Client>,
+    description: String,
+    amount: f64,
+    date: NaiveDate,
+    entry_type: &str,
+) -> Result<LedgerEntry, surrealdb::Error> {
+    post_foreign_entry(
+        db,
+        description,
+        amount,
+        &base_currency(),
+        1.0,
+        date,
+        entry_type,
+    )
+    .await
+}
+
+/// Posts an amount in another currency along with its base-currency value.
+pub async fn post_foreign_entry(
+    db: &Surreal<Client>,
+    description: String,
+    amount: f64,
+    currency: &str,
+    exchange_rate: f64,
+    date: NaiveDate,
+    entry_type: &str,
+) -> Result<LedgerEntry, surrealdb::Error> {
     let entry = LedgerEntry {
         id: uuid::Uuid::new_v4().to_string(),
-        description: req.description.clone(),
-        amount: req.amount,
-        date: req.date.clone(),
-        entry_type: req.entry_type.clone(),
+        description,
+        amount,
+        date,
+        entry_type: entry_type.to_string(),
+        currency: currency.to_string(),
+        exchange_rate,
+        base_amount: Some(round_cents(amount * exchange_rate)),
+        created_at: Some(Utc::now()),
+        attachments: Vec::new(),
     };
-    if let Err(e) = db.create::<_, LedgerEntry>("ledger").content(&entry).await {
-        return HttpResponse::InternalServerError()
-            .body(format!("Failed to create ledger entry: {}", e));
+    db.create::<_, LedgerEntry>("ledger")
+        .content(&entry)
+        .await?;
+    Ok(entry)
+}
+
+pub async fn create_ledger_entry(req: web::Json<CreateLedgerEntryRequest>) -> impl Responder {
+    let db = get_db().await;
+    let currency = match req.currency.as_deref().map(normalize_currency) {
+        Some(Ok(currency)) => currency,
+        Some(Err(msg)) => return HttpResponse::BadRequest().body(msg),
+        None => base_currency(),
+    };
+    if currency == base_currency() && req.exchange_rate.is_some_and(|rate| rate != 1.0) {
+        return HttpResponse::BadRequest()
+            .body("Entries in the base currency have an exchange rate of 1");
+    }
+    let date = req.date.unwrap_or_else(today);
+    let exchange_rate = match req.exchange_rate {
+        Some(rate) if rate.is_finite() && rate > 0.0 => rate,
+        Some(_) => return HttpResponse::BadRequest().body("Exchange rate must be positive"),
+        None => match rate_on(db, &currency, date).await {
+            Ok(Some(rate)) => rate,
+            Ok(None) => {
+                return HttpResponse::BadRequest()
+                    .body(format!("No exchange rate for {} on {}", currency, date));
+            }
+            Err(e) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Failed to load exchange rate: {}", e));
+            }
+        },
+    };
+    match post_foreign_entry(
+        db,
+        req.description.clone(),
+        req.amount,
+        &currency,
+        exchange_rate,
+        date,
+        &req.entry_type,
+    )
+    .await
+    {
+        Ok(entry) => HttpResponse::Ok().json(entry),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to create ledger entry: {}", e)),
     }
-    HttpResponse::Ok().json(entry)
 }
 
-pub async fn list_ledger_entries() -> impl Responder {
+pub async fn list_ledger_entries(
+    params: web::Query<ListParams>,
+    filters: web::Query<LedgerFilters>,
+) -> impl Responder {
     let db = get_db().await;
-    let query = "SELECT * FROM ledger";
-    match db.query(query).await {
-        Ok(res) => {
-            let entries = res
-                .get(0)
-                .and_then(|r| r.result::<Vec<LedgerEntry>>().ok())
-                .unwrap_or_default();
-            HttpResponse::Ok().json(entries)
-        }
+    let mut query = ListQuery::new(
+        "ledger",
+        &["description"],
+        &["date", "created_at", "amount", "base_amount", "entry_type"],
+    );
+    query.filter("entry_type", "=", filters.entry_type.as_ref());
+    let currency = filters.currency.as_ref().map(|code| code.to_uppercase());
+    query.filter("currency", "=", currency);
+    query.filter("date", ">=", filters.date_from);
+    query.filter("date", "<=", filters.date_to);
+    match query.fetch::<LedgerEntry>(db, &params).await {
+        Ok(page) => HttpResponse::Ok().json(page),
         Err(e) => HttpResponse::InternalServerError()
             .body(format!("Failed to list ledger entries: {}", e)),
     }
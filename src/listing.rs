@@ -0,0 +1,155 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+const DEFAULT_PER_PAGE: u32 = 50;
+const MAX_PER_PAGE: u32 = 500;
+
+/// Paging, sorting and search parameters shared by the list endpoints.
+#[derive(Deserialize)]
+pub struct ListParams {
+    pub page: Option<u32>, // 1-based
+    pub per_page: Option<u32>,
+    pub sort: Option<String>,
+    pub order: Option<String>, // "asc" or "desc"
+    pub q: Option<String>,     // free-text search
+}
+
+#[derive(Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: u64,
+    pub page: u32,
+    pub per_page: u32,
+}
+
+#[derive(Deserialize)]
+struct CountRow {
+    count: u64,
+}
+
+/// Builds a filtered, sorted and paged `SELECT` over one table. Values are
+/// always bound as parameters; field names only ever come from this code.
+pub struct ListQuery {
+    table: &'static str,
+    conditions: Vec<String>,
+    bindings: Vec<(String, JsonValue)>,
+    search_fields: &'static [&'static str],
+    sort_fields: &'static [&'static str],
+}
+
+impl ListQuery {
+    /// `sort_fields` lists the fields callers may sort by; the first is the default.
+    pub fn new(
+        table: &'static str,
+        search_fields: &'static [&'static str],
+        sort_fields: &'static [&'static str],
+    ) -> Self {
+        ListQuery {
+            table,
+            conditions: Vec::new(),
+            bindings: Vec::new(),
+            search_fields,
+            sort_fields,
+        }
+    }
+
+    fn bind(&mut self, value: impl Serialize) -> String {
+        let name = format!("p{}", self.bindings.len());
+        self.bindings.push((
+            name.clone(),
+            serde_json::to_value(value).unwrap_or_default(),
+        ));
+        name
+    }
+
+    /// Adds `field <op> value` when a value is given.
+    pub fn filter<V: Serialize>(
+        &mut self,
+        field: &'static str,
+        op: &'static str,
+        value: Option<V>,
+    ) {
+        if let Some(value) = value {
+            let name = self.bind(value);
+            self.conditions.push(format!("{} {} ${}", field, op, name));
+        }
+    }
+
+    fn where_clause(&mut self, q: Option<&str>) -> String {
+        if let Some(q) = q.map(str::trim).filter(|q| !q.is_empty()) {
+            if !self.search_fields.is_empty() {
+                let name = self.bind(q.to_lowercase());
+                let matches: Vec<String> = self
+                    .search_fields
+                    .iter()
+                    .map(|field| format!("string::lowercase({} ?? '') CONTAINS ${}", field, name))
+                    .collect();
+                self.conditions.push(format!("({})", matches.join(" OR ")));
+            }
+        }
+        if self.conditions.is_empty() {
+            String::new()
+        } else {
+            format!(" WHERE {}", self.conditions.join(" AND "))
+        }
+    }
+
+    pub async fn fetch<T: DeserializeOwned>(
+        mut self,
+        db: &Surreal<Client>,
+        params: &ListParams,
+    ) -> Result<Page<T>, surrealdb::Error> {
+        let page = params.page.unwrap_or(1).max(1);
+        let per_page = params
+            .per_page
+            .unwrap_or(DEFAULT_PER_PAGE)
+            .clamp(1, MAX_PER_PAGE);
+        let sort = params
+            .sort
+            .as_deref()
+            .and_then(|sort| self.sort_fields.iter().find(|field| **field == sort))
+            .or(self.sort_fields.first())
+            .copied()
+            .unwrap_or("id");
+        let order = match params.order.as_deref() {
+            Some("desc") => "DESC",
+            _ => "ASC",
+        };
+        let where_clause = self.where_clause(params.q.as_deref());
+        // Sorting by id as well keeps rows with equal sort values in the same
+        // order on every page
+        let sql = format!(
+            "SELECT * FROM {table}{where_clause} ORDER BY {sort} {order}, id {order} LIMIT {limit} START {start}; \
+             SELECT count() FROM {table}{where_clause} GROUP ALL;",
+            table = self.table,
+            limit = per_page,
+            start = u64::from(page - 1).saturating_mul(u64::from(per_page)),
+        );
+
+        let mut query = db.query(sql);
+        for (name, value) in self.bindings {
+            query = query.bind((name, value));
+        }
+        let res = query.await?;
+        // A record that doesn't deserialize fails the page rather than
+        // silently emptying it
+        let items = match res.get(0) {
+            Some(r) => r.result::<Vec<T>>()?,
+            None => Vec::new(),
+        };
+        let total = res
+            .get(1)
+            .and_then(|r| r.result::<Vec<CountRow>>().ok())
+            .and_then(|rows| rows.into_iter().next())
+            .map_or(0, |row| row.count);
+        Ok(Page {
+            items,
+            total,
+            page,
+            per_page,
+        })
+    }
+}
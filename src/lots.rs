@@ -0,0 +1,347 @@
+use crate::costing::record_receipt;
+use crate::dates::today;
+use crate::db::DB;
+use crate::inventory::{adjust_quantity, get_item};
+use crate::reservations::lock_stock;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{Duration, NaiveDate};
+use serde::{Deserialize, Serialize};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+// Furthest ahead the expiry report looks
+const MAX_EXPIRY_DAYS: i64 = 3650;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Lot {
+    pub id: String,
+    pub item_id: String,
+    pub lot_number: String,
+    pub received_quantity: i32,
+    pub quantity: i32, // remaining on hand
+    pub manufactured_on: Option<NaiveDate>,
+    pub expires_on: Option<NaiveDate>,
+    pub blocked: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LotAllocation {
+    pub lot_id: String,
+    pub lot_number: String,
+    pub quantity: i32,
+    pub expires_on: Option<NaiveDate>,
+}
+
+#[derive(Deserialize)]
+pub struct ReceiveLotRequest {
+    pub item_id: String,
+    pub lot_number: String,
+    pub quantity: i32,
+    pub manufactured_on: Option<NaiveDate>,
+    pub expires_on: Option<NaiveDate>,
+    pub location: Option<String>,
+    pub unit_cost: Option<f64>,
+}
+
+#[derive(Deserialize)]
+pub struct ListLotsQuery {
+    pub item_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ExpiringQuery {
+    pub days: i64,
+}
+
+#[derive(Deserialize)]
+pub struct BlockLotRequest {
+    pub lot_id: String,
+    pub blocked: bool,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+async fn get_lot(db: &Surreal<Client>, lot_id: &str) -> Option<Lot> {
+    let query = "SELECT * FROM type::thing('lot', $id)";
+    let res = db.query(query).bind(("id", lot_id)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<Lot>>().ok()?;
+    result.into_iter().next()
+}
+
+/// Picks lots for `quantity` units of an item, earliest expiry first.
+/// Blocked lots and lots already past their expiry date are never picked.
+/// Returns `None` when the sellable lots cannot cover the quantity.
+pub async fn plan_fefo(
+    db: &Surreal<Client>,
+    item_id: &str,
+    quantity: i32,
+) -> Result<Option<Vec<LotAllocation>>, surrealdb::Error> {
+    let query = "SELECT * FROM lot WHERE item_id = $item_id AND quantity > 0 AND blocked = false";
+    let res = db.query(query).bind(("item_id", item_id)).await?;
+    let lots = res
+        .get(0)
+        .and_then(|r| r.result::<Vec<Lot>>().ok())
+        .unwrap_or_default();
+    Ok(allocate_fefo(lots, quantity, today()))
+}
+
+/// The allocation part of `plan_fefo`, for the lots of one item.
+fn allocate_fefo(
+    mut lots: Vec<Lot>,
+    quantity: i32,
+    today: NaiveDate,
+) -> Option<Vec<LotAllocation>> {
+    lots.retain(|lot| {
+        !lot.blocked && lot.quantity > 0 && lot.expires_on.is_none_or(|expiry| expiry >= today)
+    });
+    // Lots without an expiry date go last
+    lots.sort_by_key(|lot| (lot.expires_on.is_none(), lot.expires_on));
+
+    let mut remaining = quantity;
+    let mut allocations = Vec::new();
+    for lot in lots {
+        if remaining == 0 {
+            break;
+        }
+        let take = remaining.min(lot.quantity);
+        allocations.push(LotAllocation {
+            lot_id: lot.id,
+            lot_number: lot.lot_number,
+            quantity: take,
+            expires_on: lot.expires_on,
+        });
+        remaining -= take;
+    }
+    if remaining > 0 {
+        return None;
+    }
+    Some(allocations)
+}
+
+pub async fn consume_lots(
+    db: &Surreal<Client>,
+    allocations: &[LotAllocation],
+) -> Result<(), surrealdb::Error> {
+    for allocation in allocations {
+        let query = "UPDATE type::thing('lot', $id) SET quantity -= $quantity";
+        db.query(query)
+            .bind(("id", &allocation.lot_id))
+            .bind(("quantity", allocation.quantity))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Puts returned units back into the lots they were sold from, most recently
+/// allocated lot first. Returns where they went.
+pub async fn return_to_lots(
+    db: &Surreal<Client>,
+    allocations: &[LotAllocation],
+    quantity: i32,
+) -> Result<Vec<LotAllocation>, surrealdb::Error> {
+    let mut remaining = quantity;
+    let mut returned = Vec::new();
+    for allocation in allocations.iter().rev() {
+        if remaining == 0 {
+            break;
+        }
+        let put_back = remaining.min(allocation.quantity);
+        let query = "UPDATE type::thing('lot', $id) SET quantity += $quantity";
+        db.query(query)
+            .bind(("id", &allocation.lot_id))
+            .bind(("quantity", put_back))
+            .await?;
+        returned.push(LotAllocation {
+            quantity: put_back,
+            ..allocation.clone()
+        });
+        remaining -= put_back;
+    }
+    Ok(returned)
+}
+
+/// Saves a newly received lot and books its quantity into stock.
+pub async fn store_lot(
+    db: &Surreal<Client>,
+    lot: &Lot,
+    location: Option<&str>,
+    reference: &str,
+) -> Result<(), surrealdb::Error> {
+    db.create::<_, Lot>("lot").content(lot).await?;
+    adjust_quantity(
+        db,
+        &lot.item_id,
+        location,
+        lot.quantity,
+        "receipt",
+        reference,
+    )
+    .await
+}
+
+pub async fn receive_lot(req: web::Json<ReceiveLotRequest>) -> impl Responder {
+    let db = get_db().await;
+    if req.quantity <= 0 {
+        return HttpResponse::BadRequest().body("Quantity must be positive");
+    }
+    // The receipt is costed against the item's current average
+    let _guard = lock_stock().await;
+    let item = match get_item(db, &req.item_id).await {
+        Some(item) if item.track_lots => item,
+        Some(_) => return HttpResponse::BadRequest().body("Item is not lot-tracked"),
+        None => return HttpResponse::NotFound().body("Item not found"),
+    };
+    if let (Some(made), Some(expiry)) = (req.manufactured_on, req.expires_on) {
+        if expiry < made {
+            return HttpResponse::BadRequest().body("Expiry date is before manufacture date");
+        }
+    }
+    let lot = Lot {
+        id: uuid::Uuid::new_v4().to_string(),
+        item_id: req.item_id.clone(),
+        lot_number: req.lot_number.clone(),
+        received_quantity: req.quantity,
+        quantity: req.quantity,
+        manufactured_on: req.manufactured_on,
+        expires_on: req.expires_on,
+        blocked: false,
+    };
+    let unit_cost = req.unit_cost.unwrap_or(item.average_cost);
+    if let Err(e) = record_receipt(db, &item, lot.quantity, unit_cost, &lot.id).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to record lot cost: {}", e));
+    }
+    if let Err(e) = store_lot(db, &lot, req.location.as_deref(), &lot.id).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to receive lot: {}", e));
+    }
+    HttpResponse::Ok().json(lot)
+}
+
+pub async fn list_lots(query: web::Query<ListLotsQuery>) -> impl Responder {
+    let db = get_db().await;
+    let res = match &query.item_id {
+        Some(item_id) => {
+            db.query("SELECT * FROM lot WHERE item_id = $item_id")
+                .bind(("item_id", item_id))
+                .await
+        }
+        None => db.query("SELECT * FROM lot").await,
+    };
+    match res {
+        Ok(res) => {
+            let lots = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<Lot>>().ok())
+                .unwrap_or_default();
+            HttpResponse::Ok().json(lots)
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to list lots: {}", e)),
+    }
+}
+
+/// Lots with stock left that expire within the given number of days,
+/// including ones that have already expired.
+pub async fn expiring_lots(query: web::Query<ExpiringQuery>) -> impl Responder {
+    let db = get_db().await;
+    if !(0..=MAX_EXPIRY_DAYS).contains(&query.days) {
+        return HttpResponse::BadRequest()
+            .body(format!("Days must be between 0 and {}", MAX_EXPIRY_DAYS));
+    }
+    let cutoff = today() + Duration::days(query.days);
+    let sql = "SELECT * FROM lot WHERE quantity > 0 AND expires_on != NONE AND expires_on <= $cutoff ORDER BY expires_on ASC";
+    match db.query(sql).bind(("cutoff", cutoff)).await {
+        Ok(res) => {
+            let lots = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<Lot>>().ok())
+                .unwrap_or_default();
+            HttpResponse::Ok().json(lots)
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list expiring lots: {}", e))
+        }
+    }
+}
+
+pub async fn block_lot(req: web::Json<BlockLotRequest>) -> impl Responder {
+    let db = get_db().await;
+    if get_lot(db, &req.lot_id).await.is_none() {
+        return HttpResponse::NotFound().body("Lot not found");
+    }
+    let query = "UPDATE type::thing('lot', $id) SET blocked = $blocked";
+    if let Err(e) = db
+        .query(query)
+        .bind(("id", &req.lot_id))
+        .bind(("blocked", req.blocked))
+        .await
+    {
+        return HttpResponse::InternalServerError().body(format!("Failed to update lot: {}", e));
+    }
+    HttpResponse::Ok().body("Lot updated successfully")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    fn lot(id: &str, quantity: i32, expires_on: Option<&str>, blocked: bool) -> Lot {
+        Lot {
+            id: id.to_string(),
+            item_id: "item".to_string(),
+            lot_number: id.to_uppercase(),
+            received_quantity: quantity,
+            quantity,
+            manufactured_on: None,
+            expires_on: expires_on.map(date),
+            blocked,
+        }
+    }
+
+    fn picked(allocations: &[LotAllocation]) -> Vec<(&str, i32)> {
+        allocations
+            .iter()
+            .map(|a| (a.lot_id.as_str(), a.quantity))
+            .collect()
+    }
+
+    #[test]
+    fn earliest_expiry_first_and_undated_last() {
+        let lots = vec![
+            lot("undated", 10, None, false),
+            lot("late", 5, Some("2026-09-01"), false),
+            lot("early", 3, Some("2026-07-01"), false),
+        ];
+        let allocations = allocate_fefo(lots, 12, date("2026-06-01")).unwrap();
+        assert_eq!(
+            picked(&allocations),
+            [("early", 3), ("late", 5), ("undated", 4)]
+        );
+    }
+
+    #[test]
+    fn skips_blocked_and_expired_lots() {
+        let lots = vec![
+            lot("expired", 5, Some("2026-05-31"), false),
+            lot("blocked", 5, Some("2026-06-15"), true),
+            lot("today", 2, Some("2026-06-01"), false),
+            lot("good", 5, Some("2026-08-01"), false),
+        ];
+        let allocations = allocate_fefo(lots, 4, date("2026-06-01")).unwrap();
+        assert_eq!(picked(&allocations), [("today", 2), ("good", 2)]);
+    }
+
+    #[test]
+    fn none_when_sellable_lots_fall_short() {
+        let lots = vec![
+            lot("expired", 10, Some("2026-01-01"), false),
+            lot("good", 3, Some("2026-08-01"), false),
+        ];
+        assert!(allocate_fefo(lots, 4, date("2026-06-01")).is_none());
+    }
+}
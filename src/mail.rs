@@ -1,5 +1,5 @@
 use lettre::{
-    message::{header, Message},
+    message::{header, Attachment, Message, MultiPart, SinglePart},
     transport::smtp::authentication::Credentials,
     AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
 };
@@ -7,34 +7,75 @@ use std::error::Error;
 use dotenv::dotenv;
 
 pub async fn send_email(to: String, message: String) -> Result<(), Box<dyn Error>> {
+    send_message(
+        to,
+        "Your OTP Code".to_string(),
+        format!("Your OTP code is: {}", message),
+    )
+    .await
+}
+
+pub async fn send_message(to: String, subject: String, body: String) -> Result<(), Box<dyn Error>> {
     dotenv().ok();
-    let smtp_username = std::env::var("SMTP_USERNAME").expect("SMTP_USERNAME not set");
-    let smtp_password = std::env::var("SMTP_PASSWORD").expect("SMTP_PASSWORD not set");
-    let smtp_server = std::env::var("SMTP_SERVER").expect("SMTP_SERVER not set");
-    let smtp_port: u16 = std::env::var("SMTP_PORT")
-        .expect("SMTP_PORT not set")
-        .parse()
-        .unwrap();
+    let smtp_username = smtp_var("SMTP_USERNAME")?;
 
     let email = Message::builder()
-        .from(smtp_username.parse().unwrap())
-        .to(to.parse().unwrap())
-        .subject("Your OTP Code")
+        .from(smtp_username.parse()?)
+        .to(to.parse()?)
+        .subject(subject)
         .header(header::ContentType::TEXT_PLAIN)
-        .body(format!("Your OTP code is: {}", message))
-        .unwrap();
+        .body(body)?;
+
+    deliver(email).await
+}
 
-    let creds = Credentials::new(smtp_username.clone(), smtp_password);
+/// Sends a plain text email with one file attached, e.g. a quotation PDF.
+pub async fn send_with_attachment(
+    to: String,
+    subject: String,
+    body: String,
+    file_name: String,
+    content_type: &str,
+    data: Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    dotenv().ok();
+    let smtp_username = smtp_var("SMTP_USERNAME")?;
+
+    let attachment =
+        Attachment::new(file_name).body(data, header::ContentType::parse(content_type)?);
+    let email = Message::builder()
+        .from(smtp_username.parse()?)
+        .to(to.parse()?)
+        .subject(subject)
+        .multipart(
+            MultiPart::mixed()
+                .singlepart(SinglePart::plain(body))
+                .singlepart(attachment),
+        )?;
 
-    let mailer = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp_server)
-        .unwrap()
+    deliver(email).await
+}
+
+async fn deliver(email: Message) -> Result<(), Box<dyn Error>> {
+    let smtp_username = smtp_var("SMTP_USERNAME")?;
+    let smtp_password = smtp_var("SMTP_PASSWORD")?;
+    let smtp_server = smtp_var("SMTP_SERVER")?;
+    let smtp_port: u16 = smtp_var("SMTP_PORT")?.parse()?;
+
+    let creds = Credentials::new(smtp_username, smtp_password);
+
+    let mailer = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp_server)?
         .port(smtp_port)
         .credentials(creds)
         .build();
 
-    match mailer.send(email).await {
-        Ok(_) => println!("Email sent successfully!"),
-        Err(e) => eprintln!("Failed to send email: {e}"),
-    }
+    // Callers decide whether a failed send matters, so it is passed back
+    mailer.send(email).await?;
     Ok(())
 }
+
+// Missing settings are reported to the caller rather than panicking, as
+// mail is also sent from background tasks such as the recurring scheduler
+fn smtp_var(name: &str) -> Result<String, Box<dyn Error>> {
+    std::env::var(name).map_err(|_| format!("{} not set", name).into())
+}
@@ -5,14 +5,42 @@ use std::env;
 use surrealdb::Surreal;
 use surrealdb::engine::remote::ws::Client;
 
+mod analytics;
+mod assembly;
+mod attachments;
 mod auth;
 mod backup;
 mod billing;
+mod bulk;
+mod costing;
+mod credit_notes;
+mod currency;
+mod customers;
+mod dates;
 mod db;
+mod forecast;
 mod import;
 mod inventory;
+mod invoice_series;
 mod ledger;
+mod listing;
+mod lots;
 mod mail;
+mod migrations;
+mod payments;
+mod pdf;
+mod pricing;
+mod promotions;
+mod purchasing;
+mod quotations;
+mod recurring;
+mod reorder;
+mod reservations;
+mod sales_orders;
+mod search;
+mod serials;
+mod stock_count;
+mod storage;
 
 use crate::db::DB;
 
@@ -47,6 +75,15 @@ async fn main() -> std::io::Result<()> {
         .expect("Failed to select namespace and database");
 
     DB.set(client).expect("Failed to set global DB client");
+    // Serve even if a migration fails; it is retried on the next start
+    if let Err(e) = migrations::run_migrations(DB.get().unwrap()).await {
+        eprintln!("{e}");
+    }
+    storage::init_storage()
+        .await
+        .expect("Failed to set up attachment storage");
+    reservations::spawn_expiry_sweeper();
+    recurring::spawn_recurring_scheduler();
 
     HttpServer::new(|| {
         App::new()
@@ -68,13 +105,215 @@ async fn main() -> std::io::Result<()> {
             .service(
                 web::scope("/inventory")
                     .route("/create", web::post().to(inventory::create_item))
-                    .route("/list", web::get().to(inventory::list_items)),
+                    .route("/update", web::post().to(bulk::update_item))
+                    .route("/bulk", web::post().to(bulk::bulk_items))
+                    .route("/list", web::get().to(inventory::list_items))
+                    .route("/search", web::get().to(search::search_items))
+                    .route("/low-stock", web::get().to(reorder::low_stock))
+                    .route("/valuation", web::get().to(costing::stock_valuation))
+                    .route("/movements", web::get().to(inventory::list_movements))
+                    .route("/reorder_rule", web::post().to(reorder::set_reorder_rule)),
+            )
+            // Lot routes
+            .service(
+                web::scope("/lots")
+                    .route("/receive", web::post().to(lots::receive_lot))
+                    .route("/list", web::get().to(lots::list_lots))
+                    .route("/expiring", web::get().to(lots::expiring_lots))
+                    .route("/block", web::post().to(lots::block_lot)),
+            )
+            // Serial number routes
+            .service(
+                web::scope("/serials")
+                    .route("/receive", web::post().to(serials::receive_serials))
+                    .route("/return", web::post().to(serials::return_serial))
+                    .route("/{serial}", web::get().to(serials::trace_serial)),
+            )
+            // Stock count routes
+            .service(
+                web::scope("/stock_counts")
+                    .route("/create", web::post().to(stock_count::create_stock_count))
+                    .route("/list", web::get().to(stock_count::list_stock_counts))
+                    .route("/submit", web::post().to(stock_count::submit_counts))
+                    .route("/post", web::post().to(stock_count::post_stock_count))
+                    .route("/{id}", web::get().to(stock_count::get_stock_count_by_id)),
+            )
+            // Assembly routes
+            .service(
+                web::scope("/assembly")
+                    .route("/create", web::post().to(assembly::create_assembly_order))
+                    .route(
+                        "/complete",
+                        web::post().to(assembly::complete_assembly_order),
+                    )
+                    .route("/cancel", web::post().to(assembly::cancel_assembly_order))
+                    .route("/list", web::get().to(assembly::list_assembly_orders))
+                    .route("/{id}", web::get().to(assembly::get_assembly_order_by_id)),
+            )
+            // Purchasing routes
+            .service(
+                web::scope("/purchasing")
+                    .route(
+                        "/suppliers/create",
+                        web::post().to(purchasing::create_supplier),
+                    )
+                    .route("/suppliers/list", web::get().to(purchasing::list_suppliers))
+                    .route(
+                        "/orders/create",
+                        web::post().to(purchasing::create_purchase_order),
+                    )
+                    .route(
+                        "/orders/list",
+                        web::get().to(purchasing::list_purchase_orders),
+                    )
+                    .route(
+                        "/orders/close",
+                        web::post().to(purchasing::close_purchase_order),
+                    )
+                    .route(
+                        "/orders/{id}",
+                        web::get().to(purchasing::get_purchase_order_by_id),
+                    )
+                    .route(
+                        "/receipts/create",
+                        web::post().to(purchasing::create_goods_receipt),
+                    )
+                    .route(
+                        "/receipts/list",
+                        web::get().to(purchasing::list_goods_receipts),
+                    ),
+            )
+            // Forecast routes
+            .service(
+                web::scope("/forecast")
+                    .route("/demand", web::get().to(forecast::demand_forecast))
+                    .route("/purchases", web::get().to(forecast::purchase_suggestions)),
+            )
+            // Reservation routes
+            .service(
+                web::scope("/reservations")
+                    .route("/create", web::post().to(reservations::create_reservation))
+                    .route(
+                        "/release",
+                        web::post().to(reservations::release_reservation),
+                    )
+                    .route("/list", web::get().to(reservations::list_reservations)),
+            )
+            // Customer routes
+            .service(
+                web::scope("/customers")
+                    .route("/create", web::post().to(customers::create_customer))
+                    .route("/list", web::get().to(customers::list_customers))
+                    .route(
+                        "/assign_price_list",
+                        web::post().to(customers::assign_price_list),
+                    ),
+            )
+            // Pricing routes
+            .service(
+                web::scope("/pricing")
+                    .route("/lists/create", web::post().to(pricing::create_price_list))
+                    .route("/lists/list", web::get().to(pricing::list_price_lists))
+                    .route("/rules/create", web::post().to(pricing::create_price_rule))
+                    .route("/rules/list", web::get().to(pricing::list_price_rules)),
+            )
+            // Promotion routes
+            .service(
+                web::scope("/promotions")
+                    .route("/create", web::post().to(promotions::create_promotion))
+                    .route("/list", web::get().to(promotions::list_promotions))
+                    .route(
+                        "/set_active",
+                        web::post().to(promotions::set_promotion_active),
+                    )
+                    .route("/coupons/create", web::post().to(promotions::create_coupon))
+                    .route("/coupons/list", web::get().to(promotions::list_coupons)),
+            )
+            // Quotation routes
+            .service(
+                web::scope("/quotations")
+                    .route("/create", web::post().to(quotations::create_quotation))
+                    .route("/list", web::get().to(quotations::list_quotations))
+                    .route("/respond", web::post().to(quotations::respond_to_quotation))
+                    .route("/email", web::post().to(quotations::email_quotation))
+                    .route("/convert", web::post().to(quotations::convert_to_bill))
+                    .route(
+                        "/convert_to_order",
+                        web::post().to(quotations::convert_to_sales_order),
+                    )
+                    .route("/{id}/pdf", web::get().to(quotations::quotation_pdf_by_id))
+                    .route("/{id}", web::get().to(quotations::get_quotation_by_id)),
+            )
+            // Sales order routes
+            .service(
+                web::scope("/sales_orders")
+                    .route("/create", web::post().to(sales_orders::create_sales_order))
+                    .route("/list", web::get().to(sales_orders::list_sales_orders))
+                    .route("/cancel", web::post().to(sales_orders::cancel_sales_order))
+                    .route("/backorders", web::get().to(sales_orders::list_backorders))
+                    .route(
+                        "/deliver",
+                        web::post().to(sales_orders::create_delivery_note),
+                    )
+                    .route(
+                        "/deliveries",
+                        web::get().to(sales_orders::list_delivery_notes),
+                    )
+                    .route(
+                        "/invoice",
+                        web::post().to(sales_orders::invoice_delivery_notes),
+                    )
+                    .route("/{id}", web::get().to(sales_orders::get_sales_order_by_id)),
             )
             // Billing routes
             .service(
                 web::scope("/billing")
                     .route("/create", web::post().to(billing::create_bill))
-                    .route("/list", web::get().to(billing::list_bills)),
+                    .route("/list", web::get().to(billing::list_bills))
+                    .route("/bundle_report", web::get().to(billing::bundle_report))
+                    .route("/update_draft", web::post().to(billing::update_draft))
+                    .route("/issue", web::post().to(billing::issue_draft))
+                    .route("/void", web::post().to(billing::void_bill))
+                    .route("/amend", web::post().to(billing::amend_bill))
+                    .route("/{id}/versions", web::get().to(billing::bill_versions))
+                    .route("/{id}/pdf", web::get().to(billing::bill_pdf_by_id))
+                    .route("/{id}", web::get().to(billing::get_bill_by_id)),
+            )
+            // Recurring bill routes
+            .service(
+                web::scope("/recurring")
+                    .route("/create", web::post().to(recurring::create_recurring_bill))
+                    .route("/list", web::get().to(recurring::list_recurring_bills))
+                    .route("/pause", web::post().to(recurring::pause_recurring_bill))
+                    .route("/resume", web::post().to(recurring::resume_recurring_bill))
+                    .route("/set_end_date", web::post().to(recurring::set_end_date))
+                    .route("/{id}", web::get().to(recurring::get_recurring_bill_by_id)),
+            )
+            // Invoice series routes
+            .service(
+                web::scope("/invoice_series")
+                    .route("/create", web::post().to(invoice_series::create_series))
+                    .route("/list", web::get().to(invoice_series::list_invoice_series)),
+            )
+            // Credit note routes
+            .service(
+                web::scope("/credit_notes")
+                    .route("/create", web::post().to(credit_notes::create_credit_note))
+                    .route("/list", web::get().to(credit_notes::list_credit_notes))
+                    .route("/{id}", web::get().to(credit_notes::get_credit_note_by_id)),
+            )
+            // Exchange rate routes
+            .service(
+                web::scope("/exchange_rates")
+                    .route("/set", web::post().to(currency::set_rate))
+                    .route("/import", web::post().to(currency::import_rates))
+                    .route("/list", web::get().to(currency::list_rates)),
+            )
+            // Payment routes
+            .service(
+                web::scope("/payments")
+                    .route("/create", web::post().to(payments::record_payment))
+                    .route("/list", web::get().to(payments::list_payments)),
             )
             // Ledger routes
             .service(
@@ -82,6 +321,25 @@ async fn main() -> std::io::Result<()> {
                     .route("/create", web::post().to(ledger::create_ledger_entry))
                     .route("/list", web::get().to(ledger::list_ledger_entries)),
             )
+            // Analytics routes
+            .service(
+                web::scope("/analytics")
+                    .route("/abc", web::get().to(analytics::abc_analysis))
+                    .route("/dead_stock", web::get().to(analytics::dead_stock))
+                    .route("/turnover", web::get().to(analytics::inventory_turnover)),
+            )
+            // Attachment routes
+            .service(
+                web::scope("/attachments")
+                    .route("/upload", web::post().to(attachments::upload_attachments))
+                    .route("/list", web::get().to(attachments::list_attachments))
+                    .route("/delete", web::post().to(attachments::delete_attachment))
+                    .route("/{id}", web::get().to(attachments::download_attachment))
+                    .route(
+                        "/{id}/thumbnail",
+                        web::get().to(attachments::download_thumbnail),
+                    ),
+            )
             // Backup routes
             .service(web::scope("/backup").route("/run", web::post().to(backup::backup_data)))
             // Import routes
@@ -0,0 +1,67 @@
+use crate::dates::{migrate_datetimes, migrate_legacy_dates};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+// One-off data changes, in the order they must run. Versions are never
+// reused or renumbered.
+const MIGRATIONS: [(u32, &str); 2] = [(1, "legacy_dates"), (2, "typed_datetimes")];
+
+/// Record of a migration that has run. `notes` lists anything it could not
+/// fix by itself, e.g. dates nobody can read.
+#[derive(Serialize, Deserialize)]
+pub struct Migration {
+    pub id: String,
+    pub version: u32,
+    pub name: String,
+    pub notes: Vec<String>,
+    #[serde(with = "crate::dates::datetime")]
+    pub applied_at: DateTime<Utc>,
+}
+
+async fn run_migration(db: &Surreal<Client>, version: u32) -> Result<Vec<String>, String> {
+    match version {
+        1 => migrate_legacy_dates(db).await,
+        2 => migrate_datetimes(db).await,
+        _ => Err(format!("Unknown migration {}", version)),
+    }
+}
+
+/// Runs every migration not yet recorded in the `migration` table, stopping
+/// at the first failure so later ones never run on half-migrated data. A
+/// failed migration is not recorded and is tried again on the next start.
+pub async fn run_migrations(db: &Surreal<Client>) -> Result<(), String> {
+    let res = db
+        .query("SELECT * FROM migration")
+        .await
+        .map_err(|e| e.to_string())?;
+    let applied = match res.get(0).map(|r| r.result::<Vec<Migration>>()) {
+        Some(Ok(applied)) => applied,
+        Some(Err(e)) => return Err(format!("Failed to read migrations: {}", e)),
+        None => Vec::new(),
+    };
+    for (version, name) in MIGRATIONS {
+        if applied.iter().any(|m| m.version == version) {
+            continue;
+        }
+        let notes = run_migration(db, version)
+            .await
+            .map_err(|e| format!("Migration {} ({}) failed: {}", version, name, e))?;
+        for note in &notes {
+            eprintln!("Migration {} ({}): {}", version, name, note);
+        }
+        let migration = Migration {
+            id: version.to_string(),
+            version,
+            name: name.to_string(),
+            notes,
+            applied_at: Utc::now(),
+        };
+        db.create::<_, Migration>("migration")
+            .content(&migration)
+            .await
+            .map_err(|e| format!("Failed to record migration {}: {}", version, e))?;
+    }
+    Ok(())
+}
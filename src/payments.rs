@@ -0,0 +1,201 @@
+use crate::billing::{BillStatus, get_bill};
+use crate::currency::{base_currency, rate_on};
+use crate::dates::today;
+use crate::db::DB;
+use crate::ledger::{post_entry, post_foreign_entry};
+use crate::listing::{ListParams, ListQuery};
+use crate::promotions::round_cents;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+/// Money received against a bill. For foreign-currency bills the difference
+/// between the rate on the bill and the rate on the day of payment is a
+/// realised exchange gain or loss.
+#[derive(Serialize, Deserialize)]
+pub struct Payment {
+    pub id: String,
+    pub bill_id: String,
+    // In the bill's currency
+    pub amount: f64,
+    pub currency: String,
+    pub exchange_rate: f64,
+    // What the payment is worth in the base currency on the day
+    pub base_amount: f64,
+    // What the same amount was booked at when the bill was issued
+    pub booked_base_amount: f64,
+    // Positive for a gain, negative for a loss
+    pub fx_gain_loss: f64,
+    pub date: NaiveDate,
+    pub reference: Option<String>,
+    #[serde(with = "crate::dates::datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct RecordPaymentRequest {
+    pub bill_id: String,
+    pub amount: f64,
+    // Defaults to today
+    pub date: Option<NaiveDate>,
+    // Rate actually received; defaults to the table rate on the payment date
+    pub exchange_rate: Option<f64>,
+    pub reference: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PaymentFilters {
+    pub bill_id: Option<String>,
+    pub currency: Option<String>,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+/// Posts the receipt and any exchange difference.
+async fn post_payment(
+    db: &Surreal<Client>,
+    payment: &Payment,
+    bill_ref: &str,
+) -> Result<(), surrealdb::Error> {
+    post_foreign_entry(
+        db,
+        format!("Payment received for bill {}", bill_ref),
+        payment.amount,
+        &payment.currency,
+        payment.exchange_rate,
+        payment.date,
+        "debit",
+    )
+    .await?;
+    if payment.fx_gain_loss != 0.0 {
+        let (label, entry_type) = if payment.fx_gain_loss > 0.0 {
+            ("gain", "credit")
+        } else {
+            ("loss", "debit")
+        };
+        post_entry(
+            db,
+            format!("Realised exchange {} on bill {}", label, bill_ref),
+            payment.fx_gain_loss.abs(),
+            payment.date,
+            entry_type,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+pub async fn record_payment(req: web::Json<RecordPaymentRequest>) -> impl Responder {
+    let db = get_db().await;
+    if !(req.amount.is_finite() && req.amount > 0.0) {
+        return HttpResponse::BadRequest().body("Amount must be positive");
+    }
+    let Some(bill) = get_bill(db, &req.bill_id).await else {
+        return HttpResponse::NotFound().body("Bill not found");
+    };
+    if bill.status != BillStatus::Issued {
+        return HttpResponse::Conflict().body("Only issued bills can be paid");
+    }
+    let date = req.date.unwrap_or_else(today);
+    let exchange_rate = match req.exchange_rate {
+        // Base-currency money has no exchange difference to book
+        _ if bill.currency == base_currency() => {
+            if req.exchange_rate.is_some_and(|rate| rate != 1.0) {
+                return HttpResponse::BadRequest()
+                    .body("Bills in the base currency are paid at a rate of 1");
+            }
+            1.0
+        }
+        Some(rate) if rate.is_finite() && rate > 0.0 => rate,
+        Some(_) => return HttpResponse::BadRequest().body("Exchange rate must be positive"),
+        None => match rate_on(db, &bill.currency, date).await {
+            Ok(Some(rate)) => rate,
+            Ok(None) => {
+                return HttpResponse::BadRequest().body(format!(
+                    "No exchange rate for {} on {}",
+                    bill.currency, date
+                ));
+            }
+            Err(e) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Failed to load exchange rate: {}", e));
+            }
+        },
+    };
+
+    // Claim the amount against what is outstanding so two payments can't
+    // overpay; credit notes already took their share off the balance
+    let query = "UPDATE type::thing('bill', $id) SET amount_paid += $amount WHERE status = $issued AND amount_paid + $amount <= total_amount - (credited_amount OR 0) + 0.005";
+    let claimed = match db
+        .query(query)
+        .bind(("id", &bill.id))
+        .bind(("amount", req.amount))
+        .bind(("issued", BillStatus::Issued))
+        .await
+    {
+        Ok(res) => res
+            .get(0)
+            .and_then(|r| r.result::<Vec<serde_json::Value>>().ok())
+            .is_some_and(|rows| !rows.is_empty()),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to record payment: {}", e));
+        }
+    };
+    if !claimed {
+        return HttpResponse::Conflict().body(format!(
+            "Payment exceeds the {:.2} outstanding on the bill",
+            bill.total_amount - bill.credited_amount - bill.amount_paid
+        ));
+    }
+
+    let base_amount = round_cents(req.amount * exchange_rate);
+    let booked_base_amount = round_cents(req.amount * bill.exchange_rate);
+    let payment = Payment {
+        id: uuid::Uuid::new_v4().to_string(),
+        bill_id: bill.id.clone(),
+        amount: req.amount,
+        currency: bill.currency.clone(),
+        exchange_rate,
+        base_amount,
+        booked_base_amount,
+        fx_gain_loss: round_cents(base_amount - booked_base_amount),
+        date,
+        reference: req.reference.clone(),
+        created_at: Utc::now(),
+    };
+    if let Err(e) = db.create::<_, Payment>("payment").content(&payment).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to record payment: {}", e));
+    }
+    let bill_ref = bill.invoice_number.as_deref().unwrap_or(&bill.id);
+    if let Err(e) = post_payment(db, &payment, bill_ref).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to post payment: {}", e));
+    }
+    HttpResponse::Ok().json(payment)
+}
+
+pub async fn list_payments(
+    params: web::Query<ListParams>,
+    filters: web::Query<PaymentFilters>,
+) -> impl Responder {
+    let db = get_db().await;
+    let mut query = ListQuery::new(
+        "payment",
+        &["reference"],
+        &["created_at", "date", "amount", "fx_gain_loss"],
+    );
+    query.filter("bill_id", "=", filters.bill_id.as_ref());
+    let currency = filters.currency.as_ref().map(|code| code.to_uppercase());
+    query.filter("currency", "=", currency);
+    match query.fetch::<Payment>(db, &params).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list payments: {}", e))
+        }
+    }
+}
@@ -0,0 +1,27 @@
+use printpdf::{BuiltinFont, Mm, PdfDocument};
+use std::error::Error;
+
+const PAGE_WIDTH: f32 = 210.0;
+const PAGE_HEIGHT: f32 = 297.0;
+const MARGIN: f32 = 15.0;
+const FONT_SIZE: f32 = 9.0;
+const LINE_HEIGHT: f32 = 4.5;
+
+/// Lays out pre-formatted lines of text on A4 pages. The font is monospaced
+/// so columns padded with `format!` line up.
+pub fn render_text_pdf(title: &str, lines: &[String]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (doc, page, layer) = PdfDocument::new(title, Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Layer 1");
+    let font = doc.add_builtin_font(BuiltinFont::Courier)?;
+    let mut current = doc.get_page(page).get_layer(layer);
+    let mut y = PAGE_HEIGHT - MARGIN;
+    for line in lines {
+        if y < MARGIN {
+            let (page, layer) = doc.add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Layer 1");
+            current = doc.get_page(page).get_layer(layer);
+            y = PAGE_HEIGHT - MARGIN;
+        }
+        current.use_text(line.as_str(), FONT_SIZE, Mm(MARGIN), Mm(y), &font);
+        y -= LINE_HEIGHT;
+    }
+    Ok(doc.save_to_bytes()?)
+}
@@ -0,0 +1,237 @@
+use crate::customers::get_customer_by_name;
+use crate::db::DB;
+use crate::inventory::{InventoryItem, get_item};
+use actix_web::{HttpResponse, Responder, web};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+/// A named set of prices, e.g. retail, wholesale or distributor. The default
+/// list applies to customers without a list of their own.
+#[derive(Serialize, Deserialize)]
+pub struct PriceList {
+    pub id: String,
+    pub name: String,
+    pub is_default: bool,
+}
+
+/// Price of an item on a list from `min_quantity` units per line upwards.
+/// Several rules for the same item form the quantity-break tiers.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PriceRule {
+    pub id: String,
+    pub price_list_id: String,
+    pub item_id: String,
+    pub min_quantity: i32,
+    pub price: f64,
+    pub valid_from: Option<NaiveDate>,
+    pub valid_to: Option<NaiveDate>,
+}
+
+#[derive(Deserialize)]
+pub struct CreatePriceListRequest {
+    pub name: String,
+    #[serde(default)]
+    pub is_default: bool,
+}
+
+#[derive(Deserialize)]
+pub struct CreatePriceRuleRequest {
+    pub price_list_id: String,
+    pub item_id: String,
+    #[serde(default = "default_min_quantity")]
+    pub min_quantity: i32,
+    pub price: f64,
+    pub valid_from: Option<NaiveDate>,
+    pub valid_to: Option<NaiveDate>,
+}
+
+#[derive(Deserialize)]
+pub struct ListPriceRulesQuery {
+    pub price_list_id: Option<String>,
+}
+
+/// The price a bill line is charged and where it came from.
+pub struct ResolvedPrice {
+    pub price: f64,
+    pub price_list: Option<String>,
+    pub price_rule_id: Option<String>,
+}
+
+fn default_min_quantity() -> i32 {
+    1
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+pub async fn get_price_list(db: &Surreal<Client>, price_list_id: &str) -> Option<PriceList> {
+    let query = "SELECT * FROM type::thing('price_list', $id)";
+    let res = db.query(query).bind(("id", price_list_id)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<PriceList>>().ok()?;
+    result.into_iter().next()
+}
+
+async fn get_default_price_list(db: &Surreal<Client>) -> Option<PriceList> {
+    let query = "SELECT * FROM price_list WHERE is_default = true LIMIT 1";
+    let res = db.query(query).await.ok()?;
+    let result = res.get(0)?.result::<Vec<PriceList>>().ok()?;
+    result.into_iter().next()
+}
+
+/// Works out the unit price for a bill line: the customer's price list (or
+/// the default list) with the largest quantity break the line qualifies for,
+/// falling back to the item's own price when no rule matches.
+pub async fn resolve_price(
+    db: &Surreal<Client>,
+    item: &InventoryItem,
+    quantity: i32,
+    customer_name: &str,
+    date: NaiveDate,
+) -> Result<ResolvedPrice, surrealdb::Error> {
+    let list_price = ResolvedPrice {
+        price: item.price,
+        price_list: None,
+        price_rule_id: None,
+    };
+    let price_list = match get_customer_by_name(db, customer_name)
+        .await
+        .and_then(|customer| customer.price_list_id)
+    {
+        Some(price_list_id) => get_price_list(db, &price_list_id).await,
+        None => get_default_price_list(db).await,
+    };
+    let Some(price_list) = price_list else {
+        return Ok(list_price);
+    };
+
+    let query = "SELECT * FROM price_rule WHERE price_list_id = $price_list_id AND item_id = $item_id AND min_quantity <= $quantity";
+    let res = db
+        .query(query)
+        .bind(("price_list_id", &price_list.id))
+        .bind(("item_id", &item.id))
+        .bind(("quantity", quantity))
+        .await?;
+    let rules = res
+        .get(0)
+        .and_then(|r| r.result::<Vec<PriceRule>>().ok())
+        .unwrap_or_default();
+    let best = rules
+        .into_iter()
+        .filter(|rule| rule.valid_from.is_none_or(|from| from <= date))
+        .filter(|rule| rule.valid_to.is_none_or(|to| date <= to))
+        .max_by(|a, b| {
+            a.min_quantity
+                .cmp(&b.min_quantity)
+                .then(b.price.total_cmp(&a.price))
+        });
+    Ok(match best {
+        Some(rule) => ResolvedPrice {
+            price: rule.price,
+            price_list: Some(price_list.name),
+            price_rule_id: Some(rule.id),
+        },
+        None => list_price,
+    })
+}
+
+pub async fn create_price_list(req: web::Json<CreatePriceListRequest>) -> impl Responder {
+    let db = get_db().await;
+    if req.is_default {
+        // Only one list can be the default
+        if let Err(e) = db.query("UPDATE price_list SET is_default = false").await {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to update price lists: {}", e));
+        }
+    }
+    let price_list = PriceList {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: req.name.clone(),
+        is_default: req.is_default,
+    };
+    if let Err(e) = db
+        .create::<_, PriceList>("price_list")
+        .content(&price_list)
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to create price list: {}", e));
+    }
+    HttpResponse::Ok().json(price_list)
+}
+
+pub async fn list_price_lists() -> impl Responder {
+    let db = get_db().await;
+    let query = "SELECT * FROM price_list";
+    match db.query(query).await {
+        Ok(res) => {
+            let lists = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<PriceList>>().ok())
+                .unwrap_or_default();
+            HttpResponse::Ok().json(lists)
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list price lists: {}", e))
+        }
+    }
+}
+
+pub async fn create_price_rule(req: web::Json<CreatePriceRuleRequest>) -> impl Responder {
+    let db = get_db().await;
+    if req.min_quantity < 1 || req.price < 0.0 {
+        return HttpResponse::BadRequest()
+            .body("Minimum quantity must be at least 1 and price not negative");
+    }
+    if let (Some(from), Some(to)) = (req.valid_from, req.valid_to) {
+        if to < from {
+            return HttpResponse::BadRequest().body("Validity ends before it starts");
+        }
+    }
+    if get_price_list(db, &req.price_list_id).await.is_none() {
+        return HttpResponse::BadRequest().body("Price list not found");
+    }
+    if get_item(db, &req.item_id).await.is_none() {
+        return HttpResponse::BadRequest().body("Item not found");
+    }
+    let rule = PriceRule {
+        id: uuid::Uuid::new_v4().to_string(),
+        price_list_id: req.price_list_id.clone(),
+        item_id: req.item_id.clone(),
+        min_quantity: req.min_quantity,
+        price: req.price,
+        valid_from: req.valid_from,
+        valid_to: req.valid_to,
+    };
+    if let Err(e) = db.create::<_, PriceRule>("price_rule").content(&rule).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to create price rule: {}", e));
+    }
+    HttpResponse::Ok().json(rule)
+}
+
+pub async fn list_price_rules(query: web::Query<ListPriceRulesQuery>) -> impl Responder {
+    let db = get_db().await;
+    let res = match &query.price_list_id {
+        Some(price_list_id) => {
+            db.query("SELECT * FROM price_rule WHERE price_list_id = $price_list_id")
+                .bind(("price_list_id", price_list_id))
+                .await
+        }
+        None => db.query("SELECT * FROM price_rule").await,
+    };
+    match res {
+        Ok(res) => {
+            let rules = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<PriceRule>>().ok())
+                .unwrap_or_default();
+            HttpResponse::Ok().json(rules)
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list price rules: {}", e))
+        }
+    }
+}
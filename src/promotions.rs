@@ -0,0 +1,381 @@
+use crate::db::DB;
+use crate::inventory::InventoryItem;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Discount {
+    Percent { percent: f64 },
+    Fixed { amount: f64 },
+}
+
+impl Discount {
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Discount::Percent { percent } if !(0.0..=100.0).contains(&percent) => {
+                Err("Discount percent must be between 0 and 100".to_string())
+            }
+            Discount::Fixed { amount } if amount < 0.0 => {
+                Err("Discount amount must not be negative".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Amount taken off `base`, never more than `base` itself.
+    pub fn amount_off(&self, base: f64) -> f64 {
+        let amount = match *self {
+            Discount::Percent { percent } => round_cents(base * percent / 100.0),
+            Discount::Fixed { amount } => amount,
+        };
+        amount.clamp(0.0, base.max(0.0))
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PromotionRule {
+    // Every `buy_quantity + free_quantity` units of the item, the last
+    // `free_quantity` are free
+    BuyXGetY {
+        item_id: String,
+        buy_quantity: i32,
+        free_quantity: i32,
+    },
+    CategoryPercent {
+        category: String,
+        percent: f64,
+    },
+}
+
+/// Applied automatically to matching bill lines while it runs.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Promotion {
+    pub id: String,
+    pub name: String,
+    pub rule: PromotionRule,
+    pub valid_from: Option<NaiveDate>,
+    pub valid_to: Option<NaiveDate>,
+    pub active: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Coupon {
+    pub id: String,
+    pub code: String,
+    pub discount: Discount,
+    // Bill subtotal needed before the coupon applies
+    pub min_total: Option<f64>,
+    pub max_uses: Option<u32>,
+    pub used: u32,
+    pub valid_from: Option<NaiveDate>,
+    pub valid_to: Option<NaiveDate>,
+    pub active: bool,
+}
+
+/// One discount on a bill: a promotion, a coupon or a manual discount.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AppliedDiscount {
+    pub description: String,
+    pub promotion_id: Option<String>,
+    pub coupon_code: Option<String>,
+    // None for discounts on the whole bill
+    pub line_index: Option<usize>,
+    pub amount: f64,
+}
+
+#[derive(Deserialize)]
+pub struct CreatePromotionRequest {
+    pub name: String,
+    pub rule: PromotionRule,
+    pub valid_from: Option<NaiveDate>,
+    pub valid_to: Option<NaiveDate>,
+}
+
+#[derive(Deserialize)]
+pub struct SetPromotionActiveRequest {
+    pub promotion_id: String,
+    pub active: bool,
+}
+
+#[derive(Deserialize)]
+pub struct CreateCouponRequest {
+    pub code: String,
+    pub discount: Discount,
+    pub min_total: Option<f64>,
+    pub max_uses: Option<u32>,
+    pub valid_from: Option<NaiveDate>,
+    pub valid_to: Option<NaiveDate>,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+pub fn round_cents(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0
+}
+
+fn in_window(valid_from: Option<NaiveDate>, valid_to: Option<NaiveDate>, date: NaiveDate) -> bool {
+    valid_from.is_none_or(|from| from <= date) && valid_to.is_none_or(|to| date <= to)
+}
+
+fn validate_window(
+    valid_from: Option<NaiveDate>,
+    valid_to: Option<NaiveDate>,
+) -> Result<(), String> {
+    match (valid_from, valid_to) {
+        (Some(from), Some(to)) if from > to => Err("Validity ends before it starts".to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Promotions running on `date`.
+pub async fn active_promotions(
+    db: &Surreal<Client>,
+    date: NaiveDate,
+) -> Result<Vec<Promotion>, surrealdb::Error> {
+    let res = db
+        .query("SELECT * FROM promotion WHERE active = true")
+        .await?;
+    Ok(res
+        .get(0)
+        .and_then(|r| r.result::<Vec<Promotion>>().ok())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|promotion| in_window(promotion.valid_from, promotion.valid_to, date))
+        .collect())
+}
+
+fn promotion_amount(rule: &PromotionRule, item: &InventoryItem, quantity: i32, price: f64) -> f64 {
+    match rule {
+        PromotionRule::BuyXGetY {
+            item_id,
+            buy_quantity,
+            free_quantity,
+        } if *item_id == item.id => {
+            let free = quantity / (buy_quantity + free_quantity) * free_quantity;
+            free as f64 * price
+        }
+        PromotionRule::CategoryPercent { category, percent }
+            if item.category.as_ref() == Some(category) =>
+        {
+            round_cents(quantity as f64 * price * percent / 100.0)
+        }
+        _ => 0.0,
+    }
+}
+
+/// The promotion giving the biggest discount on a line. Promotions don't
+/// stack with each other.
+pub fn best_promotion<'a>(
+    promotions: &'a [Promotion],
+    item: &InventoryItem,
+    quantity: i32,
+    price: f64,
+) -> Option<(&'a Promotion, f64)> {
+    promotions
+        .iter()
+        .map(|promotion| {
+            let amount = promotion_amount(&promotion.rule, item, quantity, price);
+            (promotion, amount)
+        })
+        .filter(|(_, amount)| *amount > 0.0)
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+}
+
+async fn get_coupon(db: &Surreal<Client>, code: &str) -> Option<Coupon> {
+    let query = "SELECT * FROM coupon WHERE code = $code LIMIT 1";
+    let res = db.query(query).bind(("code", code)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<Coupon>>().ok()?;
+    result.into_iter().next()
+}
+
+/// Looks up a coupon and checks it can be used on a bill dated `date` with
+/// the given subtotal. `counted` skips the usage limit for a bill that has
+/// already used the coupon.
+pub async fn find_coupon(
+    db: &Surreal<Client>,
+    code: &str,
+    date: NaiveDate,
+    subtotal: f64,
+    counted: bool,
+) -> Result<Coupon, String> {
+    let code = code.trim().to_uppercase();
+    let Some(coupon) = get_coupon(db, &code).await else {
+        return Err(format!("Coupon {} not found", code));
+    };
+    if !coupon.active || !in_window(coupon.valid_from, coupon.valid_to, date) {
+        return Err(format!("Coupon {} is not valid on this date", code));
+    }
+    if !counted && coupon.max_uses.is_some_and(|max| coupon.used >= max) {
+        return Err(format!("Coupon {} has been used up", code));
+    }
+    if coupon.min_total.is_some_and(|min| subtotal < min) {
+        return Err(format!(
+            "Coupon {} needs a subtotal of at least {:.2}",
+            code,
+            coupon.min_total.unwrap_or_default()
+        ));
+    }
+    Ok(coupon)
+}
+
+/// Counts one use of a coupon. Fails without counting when the usage
+/// limit has been reached in the meantime.
+pub async fn claim_coupon(db: &Surreal<Client>, code: &str) -> Result<bool, surrealdb::Error> {
+    let query =
+        "UPDATE coupon SET used += 1 WHERE code = $code AND (max_uses = NONE OR used < max_uses)";
+    let res = db.query(query).bind(("code", code)).await?;
+    Ok(res
+        .get(0)
+        .and_then(|r| r.result::<Vec<Coupon>>().ok())
+        .is_some_and(|updated| !updated.is_empty()))
+}
+
+/// Gives back a use of a coupon, e.g. when its bill is voided.
+pub async fn release_coupon(db: &Surreal<Client>, code: &str) -> Result<(), surrealdb::Error> {
+    let query = "UPDATE coupon SET used -= 1 WHERE code = $code AND used > 0";
+    db.query(query).bind(("code", code)).await?;
+    Ok(())
+}
+
+pub async fn create_promotion(req: web::Json<CreatePromotionRequest>) -> impl Responder {
+    let db = get_db().await;
+    if let Err(msg) = validate_window(req.valid_from, req.valid_to) {
+        return HttpResponse::BadRequest().body(msg);
+    }
+    match &req.rule {
+        PromotionRule::BuyXGetY {
+            buy_quantity,
+            free_quantity,
+            ..
+        } if *buy_quantity <= 0 || *free_quantity <= 0 => {
+            return HttpResponse::BadRequest().body("Buy and free quantities must be positive");
+        }
+        PromotionRule::CategoryPercent { percent, .. } if !(0.0..=100.0).contains(percent) => {
+            return HttpResponse::BadRequest().body("Percent must be between 0 and 100");
+        }
+        _ => {}
+    }
+    let promotion = Promotion {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: req.name.clone(),
+        rule: req.rule.clone(),
+        valid_from: req.valid_from,
+        valid_to: req.valid_to,
+        active: true,
+    };
+    if let Err(e) = db
+        .create::<_, Promotion>("promotion")
+        .content(&promotion)
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to create promotion: {}", e));
+    }
+    HttpResponse::Ok().json(promotion)
+}
+
+pub async fn list_promotions() -> impl Responder {
+    let db = get_db().await;
+    match db.query("SELECT * FROM promotion ORDER BY name ASC").await {
+        Ok(res) => {
+            let promotions = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<Promotion>>().ok())
+                .unwrap_or_default();
+            HttpResponse::Ok().json(promotions)
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list promotions: {}", e))
+        }
+    }
+}
+
+pub async fn set_promotion_active(req: web::Json<SetPromotionActiveRequest>) -> impl Responder {
+    let db = get_db().await;
+    let query = "UPDATE type::thing('promotion', $id) SET active = $active";
+    if let Err(e) = db
+        .query(query)
+        .bind(("id", &req.promotion_id))
+        .bind(("active", req.active))
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to update promotion: {}", e));
+    }
+    HttpResponse::Ok().body("Promotion updated successfully")
+}
+
+pub async fn create_coupon(req: web::Json<CreateCouponRequest>) -> impl Responder {
+    let db = get_db().await;
+    let code = req.code.trim().to_uppercase();
+    if code.is_empty() {
+        return HttpResponse::BadRequest().body("Coupon code must not be empty");
+    }
+    if let Err(msg) = req
+        .discount
+        .validate()
+        .and_then(|_| validate_window(req.valid_from, req.valid_to))
+    {
+        return HttpResponse::BadRequest().body(msg);
+    }
+    if get_coupon(db, &code).await.is_some() {
+        return HttpResponse::Conflict().body("Coupon code already exists");
+    }
+    let coupon = Coupon {
+        id: uuid::Uuid::new_v4().to_string(),
+        code,
+        discount: req.discount,
+        min_total: req.min_total,
+        max_uses: req.max_uses,
+        used: 0,
+        valid_from: req.valid_from,
+        valid_to: req.valid_to,
+        active: true,
+    };
+    if let Err(e) = db.create::<_, Coupon>("coupon").content(&coupon).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to create coupon: {}", e));
+    }
+    HttpResponse::Ok().json(coupon)
+}
+
+pub async fn list_coupons() -> impl Responder {
+    let db = get_db().await;
+    match db.query("SELECT * FROM coupon ORDER BY code ASC").await {
+        Ok(res) => {
+            let coupons = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<Coupon>>().ok())
+                .unwrap_or_default();
+            HttpResponse::Ok().json(coupons)
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list coupons: {}", e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_is_rounded_to_cents() {
+        assert_eq!(Discount::Percent { percent: 10.0 }.amount_off(19.99), 2.0);
+        assert_eq!(Discount::Percent { percent: 100.0 }.amount_off(50.0), 50.0);
+    }
+
+    #[test]
+    fn never_more_than_the_base() {
+        assert_eq!(Discount::Fixed { amount: 80.0 }.amount_off(50.0), 50.0);
+        assert_eq!(Discount::Fixed { amount: 20.0 }.amount_off(50.0), 20.0);
+        assert_eq!(Discount::Fixed { amount: 20.0 }.amount_off(-5.0), 0.0);
+        assert_eq!(Discount::Percent { percent: 10.0 }.amount_off(-5.0), 0.0);
+    }
+}
@@ -0,0 +1,471 @@
+use crate::costing::record_receipt;
+use crate::db::DB;
+use crate::forecast::MAX_PLANNING_DAYS;
+use crate::inventory::{adjust_quantity, get_item};
+use crate::lots::{Lot, store_lot};
+use crate::reservations::lock_stock;
+use crate::serials::{check_new_serials, register_serials};
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+#[derive(Serialize, Deserialize)]
+pub struct Supplier {
+    pub id: String,
+    pub name: String,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub address: Option<String>,
+    // Typical days from ordering to receipt, used for purchase suggestions
+    #[serde(default)]
+    pub lead_time_days: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateSupplierRequest {
+    pub name: String,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub address: Option<String>,
+    pub lead_time_days: Option<i64>,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PurchaseOrderStatus {
+    Open,
+    PartiallyReceived,
+    Closed,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PurchaseOrderLine {
+    pub item_id: String,
+    pub quantity: i32,
+    pub unit_cost: f64,
+    #[serde(default)]
+    pub received_quantity: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PurchaseOrder {
+    pub id: String,
+    pub supplier_id: String,
+    pub lines: Vec<PurchaseOrderLine>,
+    pub expected_date: Option<NaiveDate>,
+    pub status: PurchaseOrderStatus,
+    #[serde(with = "crate::dates::datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct CreatePurchaseOrderRequest {
+    pub supplier_id: String,
+    pub lines: Vec<PurchaseOrderLine>,
+    pub expected_date: Option<NaiveDate>,
+}
+
+#[derive(Deserialize)]
+pub struct ClosePurchaseOrderRequest {
+    pub purchase_order_id: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GoodsReceiptLine {
+    // Position of the received line on the purchase order
+    pub line_index: usize,
+    pub item_id: String,
+    pub quantity: i32,
+    // Required for lot-tracked items
+    pub lot_number: Option<String>,
+    pub manufactured_on: Option<NaiveDate>,
+    pub expires_on: Option<NaiveDate>,
+    // Required for serial-tracked items, one per unit
+    #[serde(default)]
+    pub serials: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GoodsReceipt {
+    pub id: String,
+    pub purchase_order_id: String,
+    pub lines: Vec<GoodsReceiptLine>,
+    pub location: Option<String>,
+    #[serde(with = "crate::dates::datetime")]
+    pub received_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateGoodsReceiptRequest {
+    pub purchase_order_id: String,
+    pub lines: Vec<GoodsReceiptLine>,
+    pub location: Option<String>,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+pub async fn get_supplier(db: &Surreal<Client>, supplier_id: &str) -> Option<Supplier> {
+    let query = "SELECT * FROM type::thing('supplier', $id)";
+    let res = db.query(query).bind(("id", supplier_id)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<Supplier>>().ok()?;
+    result.into_iter().next()
+}
+
+pub async fn get_purchase_order(db: &Surreal<Client>, order_id: &str) -> Option<PurchaseOrder> {
+    let query = "SELECT * FROM type::thing('purchase_order', $id)";
+    let res = db.query(query).bind(("id", order_id)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<PurchaseOrder>>().ok()?;
+    result.into_iter().next()
+}
+
+pub async fn create_supplier(req: web::Json<CreateSupplierRequest>) -> impl Responder {
+    let db = get_db().await;
+    if req
+        .lead_time_days
+        .is_some_and(|days| !(0..=MAX_PLANNING_DAYS).contains(&days))
+    {
+        return HttpResponse::BadRequest().body(format!(
+            "Lead time must be between 0 and {} days",
+            MAX_PLANNING_DAYS
+        ));
+    }
+    let supplier = Supplier {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: req.name.clone(),
+        email: req.email.clone(),
+        phone: req.phone.clone(),
+        address: req.address.clone(),
+        lead_time_days: req.lead_time_days,
+    };
+    if let Err(e) = db
+        .create::<_, Supplier>("supplier")
+        .content(&supplier)
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to create supplier: {}", e));
+    }
+    HttpResponse::Ok().json(supplier)
+}
+
+pub async fn list_suppliers() -> impl Responder {
+    let db = get_db().await;
+    let query = "SELECT * FROM supplier";
+    match db.query(query).await {
+        Ok(res) => {
+            let suppliers = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<Supplier>>().ok())
+                .unwrap_or_default();
+            HttpResponse::Ok().json(suppliers)
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list suppliers: {}", e))
+        }
+    }
+}
+
+pub async fn create_purchase_order(req: web::Json<CreatePurchaseOrderRequest>) -> impl Responder {
+    let db = get_db().await;
+    if get_supplier(db, &req.supplier_id).await.is_none() {
+        return HttpResponse::BadRequest().body("Supplier not found");
+    }
+    if req.lines.is_empty() {
+        return HttpResponse::BadRequest().body("Purchase order has no lines");
+    }
+    for line in &req.lines {
+        if line.quantity <= 0 || line.unit_cost < 0.0 {
+            return HttpResponse::BadRequest()
+                .body("Line quantities must be positive and costs not negative");
+        }
+        let Some(item) = get_item(db, &line.item_id).await else {
+            return HttpResponse::BadRequest().body(format!("Item {} not found", line.item_id));
+        };
+        if item.is_bundle() {
+            return HttpResponse::BadRequest().body(format!(
+                "{} is a bundle; order its components instead",
+                item.name
+            ));
+        }
+    }
+    let order = PurchaseOrder {
+        id: uuid::Uuid::new_v4().to_string(),
+        supplier_id: req.supplier_id.clone(),
+        lines: req
+            .lines
+            .iter()
+            .map(|line| PurchaseOrderLine {
+                received_quantity: 0,
+                ..line.clone()
+            })
+            .collect(),
+        expected_date: req.expected_date,
+        status: PurchaseOrderStatus::Open,
+        created_at: Utc::now(),
+    };
+    if let Err(e) = db
+        .create::<_, PurchaseOrder>("purchase_order")
+        .content(&order)
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to create purchase order: {}", e));
+    }
+    HttpResponse::Ok().json(order)
+}
+
+pub async fn list_purchase_orders() -> impl Responder {
+    let db = get_db().await;
+    let query = "SELECT * FROM purchase_order ORDER BY created_at DESC";
+    match db.query(query).await {
+        Ok(res) => {
+            let orders = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<PurchaseOrder>>().ok())
+                .unwrap_or_default();
+            HttpResponse::Ok().json(orders)
+        }
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to list purchase orders: {}", e)),
+    }
+}
+
+pub async fn get_purchase_order_by_id(path: web::Path<String>) -> impl Responder {
+    let db = get_db().await;
+    match get_purchase_order(db, &path.into_inner()).await {
+        Some(order) => HttpResponse::Ok().json(order),
+        None => HttpResponse::NotFound().body("Purchase order not found"),
+    }
+}
+
+/// Closes an order that will not be delivered in full, e.g. a short shipment.
+pub async fn close_purchase_order(req: web::Json<ClosePurchaseOrderRequest>) -> impl Responder {
+    let db = get_db().await;
+    // Receipts update the order under the same lock
+    let _guard = lock_stock().await;
+    if get_purchase_order(db, &req.purchase_order_id)
+        .await
+        .is_none()
+    {
+        return HttpResponse::NotFound().body("Purchase order not found");
+    }
+    let query = "UPDATE type::thing('purchase_order', $id) SET status = $status";
+    if let Err(e) = db
+        .query(query)
+        .bind(("id", &req.purchase_order_id))
+        .bind(("status", PurchaseOrderStatus::Closed))
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to close purchase order: {}", e));
+    }
+    HttpResponse::Ok().body("Purchase order closed")
+}
+
+pub async fn create_goods_receipt(req: web::Json<CreateGoodsReceiptRequest>) -> impl Responder {
+    let db = get_db().await;
+    let _guard = lock_stock().await;
+    let Some(mut order) = get_purchase_order(db, &req.purchase_order_id).await else {
+        return HttpResponse::NotFound().body("Purchase order not found");
+    };
+    if order.status == PurchaseOrderStatus::Closed {
+        return HttpResponse::BadRequest().body("Purchase order is closed");
+    }
+    if req.lines.is_empty() {
+        return HttpResponse::BadRequest().body("Goods receipt has no lines");
+    }
+
+    // Validate everything up front so a bad line doesn't leave a half-booked receipt
+    let mut serials = Vec::new();
+    for line in &req.lines {
+        if line.quantity <= 0 {
+            return HttpResponse::BadRequest().body("Received quantities must be positive");
+        }
+        let Some(order_line) = order.lines.get(line.line_index) else {
+            return HttpResponse::BadRequest()
+                .body(format!("Purchase order has no line {}", line.line_index));
+        };
+        if order_line.item_id != line.item_id {
+            return HttpResponse::BadRequest().body(format!(
+                "Line {} of the purchase order is not for item {}",
+                line.line_index, line.item_id
+            ));
+        }
+        let already_in_receipt: i32 = req
+            .lines
+            .iter()
+            .filter(|l| l.line_index == line.line_index)
+            .map(|l| l.quantity)
+            .sum();
+        if order_line.received_quantity + already_in_receipt > order_line.quantity {
+            return HttpResponse::BadRequest().body(format!(
+                "Line {} of the purchase order is over-received",
+                line.line_index
+            ));
+        }
+        let Some(item) = get_item(db, &line.item_id).await else {
+            return HttpResponse::BadRequest().body(format!("Item {} not found", line.item_id));
+        };
+        if item.is_bundle() {
+            return HttpResponse::BadRequest().body(format!(
+                "{} is a bundle; receive its components instead",
+                item.name
+            ));
+        }
+        if item.track_lots && line.lot_number.is_none() {
+            return HttpResponse::BadRequest()
+                .body(format!("Item {} needs a lot number", item.name));
+        }
+        if item.track_serials {
+            if line.serials.len() != line.quantity as usize {
+                return HttpResponse::BadRequest()
+                    .body(format!("Item {} needs one serial per unit", item.name));
+            }
+            serials.extend(line.serials.iter().cloned());
+        } else if !line.serials.is_empty() {
+            return HttpResponse::BadRequest()
+                .body(format!("Item {} is not serial tracked", item.name));
+        }
+    }
+    // Checked across the whole receipt so two lines can't share a serial
+    if !serials.is_empty() {
+        if let Err(msg) = check_new_serials(db, &serials).await {
+            return HttpResponse::BadRequest().body(msg);
+        }
+    }
+
+    // The order and receipt are saved before any stock is booked, so a
+    // failure part way can be traced back to the receipt that caused it
+    let original_lines = order.lines.clone();
+    let mut receipt = GoodsReceipt {
+        id: uuid::Uuid::new_v4().to_string(),
+        purchase_order_id: order.id.clone(),
+        lines: req.lines.clone(),
+        location: req.location.clone(),
+        received_at: Utc::now(),
+    };
+    receive_lines(&mut order, &receipt.lines);
+    if let Err(e) = save_receipt(db, &order, &receipt).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to save goods receipt: {}", e));
+    }
+
+    let location = req.location.as_deref();
+    for (index, line) in req.lines.iter().enumerate() {
+        if let Err(e) = book_line(db, &order, line, location, &receipt.id).await {
+            // Cut the receipt back to the lines whose stock is in
+            receipt.lines.truncate(index);
+            order.lines = original_lines;
+            receive_lines(&mut order, &receipt.lines);
+            if let Err(e) = save_receipt(db, &order, &receipt).await {
+                eprintln!("Failed to correct goods receipt {}: {}", receipt.id, e);
+            }
+            return HttpResponse::InternalServerError().body(format!(
+                "Failed to book received stock for line {}: {}; \
+                 only the lines before it were received",
+                line.line_index, e
+            ));
+        }
+    }
+    HttpResponse::Ok().json(receipt)
+}
+
+/// Adds received quantities to the order's lines and works out its status.
+fn receive_lines(order: &mut PurchaseOrder, lines: &[GoodsReceiptLine]) {
+    for line in lines {
+        order.lines[line.line_index].received_quantity += line.quantity;
+    }
+    order.status = if order
+        .lines
+        .iter()
+        .all(|line| line.received_quantity >= line.quantity)
+    {
+        PurchaseOrderStatus::Closed
+    } else if order.lines.iter().any(|line| line.received_quantity > 0) {
+        PurchaseOrderStatus::PartiallyReceived
+    } else {
+        PurchaseOrderStatus::Open
+    };
+}
+
+/// Stores the order's received quantities together with the receipt; a
+/// receipt left with no lines is removed.
+async fn save_receipt(
+    db: &Surreal<Client>,
+    order: &PurchaseOrder,
+    receipt: &GoodsReceipt,
+) -> Result<(), surrealdb::Error> {
+    let query = if receipt.lines.is_empty() {
+        "BEGIN TRANSACTION;
+        UPDATE type::thing('purchase_order', $id) SET lines = $lines, status = $status;
+        DELETE type::thing('goods_receipt', $receipt_id);
+        COMMIT TRANSACTION;"
+    } else {
+        "BEGIN TRANSACTION;
+        UPDATE type::thing('purchase_order', $id) SET lines = $lines, status = $status;
+        UPDATE type::thing('goods_receipt', $receipt_id) CONTENT $receipt;
+        COMMIT TRANSACTION;"
+    };
+    db.query(query)
+        .bind(("id", &order.id))
+        .bind(("lines", &order.lines))
+        .bind(("status", order.status))
+        .bind(("receipt_id", &receipt.id))
+        .bind(("receipt", receipt))
+        .await?;
+    Ok(())
+}
+
+/// Costs one received line and puts its stock, lot or serials in.
+async fn book_line(
+    db: &Surreal<Client>,
+    order: &PurchaseOrder,
+    line: &GoodsReceiptLine,
+    location: Option<&str>,
+    receipt_id: &str,
+) -> Result<(), surrealdb::Error> {
+    let Some(item) = get_item(db, &line.item_id).await else {
+        return Ok(());
+    };
+    let unit_cost = order.lines[line.line_index].unit_cost;
+    record_receipt(db, &item, line.quantity, unit_cost, receipt_id).await?;
+    if item.track_lots {
+        let lot = Lot {
+            id: uuid::Uuid::new_v4().to_string(),
+            item_id: item.id.clone(),
+            lot_number: line.lot_number.clone().unwrap_or_default(),
+            received_quantity: line.quantity,
+            quantity: line.quantity,
+            manufactured_on: line.manufactured_on,
+            expires_on: line.expires_on,
+            blocked: false,
+        };
+        store_lot(db, &lot, location, receipt_id).await
+    } else if item.track_serials {
+        register_serials(db, &item.id, &line.serials, Some(receipt_id), location)
+            .await
+            .map(|_| ())
+    } else {
+        adjust_quantity(db, &item.id, location, line.quantity, "receipt", receipt_id).await
+    }
+}
+
+pub async fn list_goods_receipts() -> impl Responder {
+    let db = get_db().await;
+    let query = "SELECT * FROM goods_receipt ORDER BY received_at DESC";
+    match db.query(query).await {
+        Ok(res) => {
+            let receipts = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<GoodsReceipt>>().ok())
+                .unwrap_or_default();
+            HttpResponse::Ok().json(receipts)
+        }
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to list goods receipts: {}", e)),
+    }
+}
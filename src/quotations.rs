@@ -0,0 +1,452 @@
+use crate::billing::{Bill, BillItem, issue_bill, price_lines};
+use crate::currency::{base_currency, normalize_currency};
+use crate::customers::get_customer_by_name;
+use crate::dates::today;
+use crate::db::DB;
+use crate::inventory::get_item;
+use crate::listing::{ListParams, ListQuery};
+use crate::mail::send_with_attachment;
+use crate::pdf::render_text_pdf;
+use crate::promotions::{AppliedDiscount, Discount};
+use crate::reservations::lock_stock;
+use crate::sales_orders::place_order;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotationStatus {
+    Sent,
+    Accepted,
+    Rejected,
+    Expired,
+}
+
+/// A priced offer with the same lines as a bill. Nothing is reserved or
+/// taken from stock until it is converted.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Quotation {
+    pub id: String,
+    pub customer_name: String,
+    pub items: Vec<BillItem>,
+    pub discount: Option<Discount>,
+    pub coupon_code: Option<String>,
+    pub discounts: Vec<AppliedDiscount>,
+    pub discount_amount: f64,
+    pub tax_amount: f64,
+    pub total_amount: f64,
+    // Every amount on the quotation is in this currency
+    #[serde(default = "base_currency")]
+    pub currency: String,
+    pub date: NaiveDate,
+    pub valid_until: NaiveDate,
+    pub status: QuotationStatus,
+    // Bill or sales order the quotation was turned into
+    pub converted_to: Option<String>,
+    #[serde(with = "crate::dates::datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateQuotationRequest {
+    pub customer_name: String,
+    pub items: Vec<BillItem>,
+    // Defaults to today
+    pub date: Option<NaiveDate>,
+    pub valid_until: NaiveDate,
+    pub discount: Option<Discount>,
+    pub coupon_code: Option<String>,
+    // Defaults to the base currency
+    pub currency: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RespondQuotationRequest {
+    pub quotation_id: String,
+    pub accepted: bool,
+}
+
+#[derive(Deserialize)]
+pub struct EmailQuotationRequest {
+    pub quotation_id: String,
+    // Defaults to the customer's email
+    pub to: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ConvertQuotationRequest {
+    pub quotation_id: String,
+    pub location: Option<String>,
+    pub branch: Option<String>,
+    pub invoice_series_id: Option<String>,
+    // Go ahead even if current prices differ from the quoted ones
+    #[serde(default)]
+    pub accept_price_changes: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ConvertQuotationToOrderRequest {
+    pub quotation_id: String,
+    #[serde(default)]
+    pub accept_price_changes: bool,
+}
+
+#[derive(Deserialize)]
+pub struct QuotationFilters {
+    pub customer_name: Option<String>,
+    pub status: Option<QuotationStatus>,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+/// Marks open quotations past their validity date as expired.
+async fn expire_quotations(db: &Surreal<Client>) -> Result<(), surrealdb::Error> {
+    let query =
+        "UPDATE quotation SET status = $expired WHERE status = $sent AND valid_until < $today";
+    db.query(query)
+        .bind(("expired", QuotationStatus::Expired))
+        .bind(("sent", QuotationStatus::Sent))
+        .bind(("today", today()))
+        .await?;
+    Ok(())
+}
+
+async fn get_quotation(db: &Surreal<Client>, quotation_id: &str) -> Option<Quotation> {
+    expire_quotations(db).await.ok()?;
+    let query = "SELECT * FROM type::thing('quotation', $id)";
+    let res = db.query(query).bind(("id", quotation_id)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<Quotation>>().ok()?;
+    result.into_iter().next()
+}
+
+/// A draft bill carrying the quotation's lines and discounts, for pricing.
+fn to_bill(quotation: &Quotation) -> Bill {
+    let mut bill = Bill::draft(
+        quotation.customer_name.clone(),
+        quotation.date,
+        quotation.items.clone(),
+    );
+    bill.discount = quotation.discount;
+    bill.coupon_code = quotation.coupon_code.clone();
+    bill.currency = quotation.currency.clone();
+    bill
+}
+
+fn render_quotation(quotation: &Quotation, item_names: &[String]) -> Vec<String> {
+    let mut lines = vec![
+        format!("QUOTATION {}", quotation.id),
+        String::new(),
+        format!("Customer:    {}", quotation.customer_name),
+        format!("Date:        {}", quotation.date),
+        format!("Valid until: {}", quotation.valid_until),
+        format!("Currency:    {}", quotation.currency),
+        String::new(),
+        format!(
+            "{:<36} {:>6} {:>11} {:>10} {:>9} {:>12}",
+            "Item", "Qty", "Unit price", "Discount", "Tax", "Amount"
+        ),
+        "-".repeat(89),
+    ];
+    for (line, name) in quotation.items.iter().zip(item_names) {
+        lines.push(format!(
+            "{:<36.36} {:>6} {:>11.2} {:>10.2} {:>9.2} {:>12.2}",
+            name,
+            line.quantity,
+            line.price,
+            line.discount_amount,
+            line.tax_amount,
+            line.net_amount() + line.tax_amount
+        ));
+    }
+    lines.push("-".repeat(89));
+    for discount in &quotation.discounts {
+        lines.push(format!(
+            "{:<76} {:>12.2}",
+            discount.description, -discount.amount
+        ));
+    }
+    lines.push(format!("{:<76} {:>12.2}", "Tax", quotation.tax_amount));
+    lines.push(format!("{:<76} {:>12.2}", "Total", quotation.total_amount));
+    lines
+}
+
+async fn quotation_pdf(db: &Surreal<Client>, quotation: &Quotation) -> Result<Vec<u8>, String> {
+    let mut item_names = Vec::new();
+    for line in &quotation.items {
+        let name = get_item(db, &line.item_id)
+            .await
+            .map_or_else(|| line.item_id.clone(), |item| item.name);
+        item_names.push(name);
+    }
+    let title = format!("Quotation {}", quotation.id);
+    render_text_pdf(&title, &render_quotation(quotation, &item_names))
+        .map_err(|e| format!("Failed to render quotation: {}", e))
+}
+
+pub async fn create_quotation(req: web::Json<CreateQuotationRequest>) -> impl Responder {
+    let db = get_db().await;
+    if req.valid_until < today() {
+        return HttpResponse::BadRequest().body("Validity date is in the past");
+    }
+    let mut bill = Bill::draft(
+        req.customer_name.clone(),
+        req.date.unwrap_or_else(today),
+        req.items.clone(),
+    );
+    bill.discount = req.discount;
+    bill.coupon_code = req.coupon_code.clone();
+    bill.currency = match req.currency.as_deref().map(normalize_currency) {
+        Some(Ok(currency)) => currency,
+        Some(Err(msg)) => return HttpResponse::BadRequest().body(msg),
+        None => base_currency(),
+    };
+    if let Err(response) = price_lines(db, &mut bill).await {
+        return response;
+    }
+    let quotation = Quotation {
+        id: uuid::Uuid::new_v4().to_string(),
+        customer_name: bill.customer_name,
+        items: bill.items,
+        discount: bill.discount,
+        coupon_code: bill.coupon_code,
+        discounts: bill.discounts,
+        discount_amount: bill.discount_amount,
+        tax_amount: bill.tax_amount,
+        total_amount: bill.total_amount,
+        currency: bill.currency,
+        date: bill.date,
+        valid_until: req.valid_until,
+        status: QuotationStatus::Sent,
+        converted_to: None,
+        created_at: Utc::now(),
+    };
+    if let Err(e) = db
+        .create::<_, Quotation>("quotation")
+        .content(&quotation)
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to create quotation: {}", e));
+    }
+    HttpResponse::Ok().json(quotation)
+}
+
+pub async fn list_quotations(
+    params: web::Query<ListParams>,
+    filters: web::Query<QuotationFilters>,
+) -> impl Responder {
+    let db = get_db().await;
+    if let Err(e) = expire_quotations(db).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to expire quotations: {}", e));
+    }
+    let mut query = ListQuery::new(
+        "quotation",
+        &["customer_name"],
+        &["created_at", "valid_until", "total_amount", "customer_name"],
+    );
+    query.filter("customer_name", "=", filters.customer_name.as_ref());
+    query.filter("status", "=", filters.status.as_ref());
+    match query.fetch::<Quotation>(db, &params).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list quotations: {}", e))
+        }
+    }
+}
+
+pub async fn get_quotation_by_id(path: web::Path<String>) -> impl Responder {
+    let db = get_db().await;
+    match get_quotation(db, &path.into_inner()).await {
+        Some(quotation) => HttpResponse::Ok().json(quotation),
+        None => HttpResponse::NotFound().body("Quotation not found"),
+    }
+}
+
+/// Records the customer's answer to an open quotation.
+pub async fn respond_to_quotation(req: web::Json<RespondQuotationRequest>) -> impl Responder {
+    let db = get_db().await;
+    let Some(quotation) = get_quotation(db, &req.quotation_id).await else {
+        return HttpResponse::NotFound().body("Quotation not found");
+    };
+    if quotation.status != QuotationStatus::Sent {
+        return HttpResponse::Conflict().body("Quotation is no longer open");
+    }
+    let status = if req.accepted {
+        QuotationStatus::Accepted
+    } else {
+        QuotationStatus::Rejected
+    };
+    let query = "UPDATE type::thing('quotation', $id) SET status = $status";
+    if let Err(e) = db
+        .query(query)
+        .bind(("id", &quotation.id))
+        .bind(("status", status))
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to update quotation: {}", e));
+    }
+    HttpResponse::Ok().json(Quotation {
+        status,
+        ..quotation
+    })
+}
+
+pub async fn quotation_pdf_by_id(path: web::Path<String>) -> impl Responder {
+    let db = get_db().await;
+    let Some(quotation) = get_quotation(db, &path.into_inner()).await else {
+        return HttpResponse::NotFound().body("Quotation not found");
+    };
+    match quotation_pdf(db, &quotation).await {
+        Ok(pdf) => HttpResponse::Ok()
+            .content_type("application/pdf")
+            .insert_header((
+                "Content-Disposition",
+                format!("inline; filename=\"quotation-{}.pdf\"", quotation.id),
+            ))
+            .body(pdf),
+        Err(msg) => HttpResponse::InternalServerError().body(msg),
+    }
+}
+
+pub async fn email_quotation(req: web::Json<EmailQuotationRequest>) -> impl Responder {
+    let db = get_db().await;
+    let Some(quotation) = get_quotation(db, &req.quotation_id).await else {
+        return HttpResponse::NotFound().body("Quotation not found");
+    };
+    let to = match &req.to {
+        Some(to) => to.clone(),
+        None => match get_customer_by_name(db, &quotation.customer_name)
+            .await
+            .and_then(|customer| customer.email)
+        {
+            Some(email) => email,
+            None => return HttpResponse::BadRequest().body("Customer has no email address"),
+        },
+    };
+    let pdf = match quotation_pdf(db, &quotation).await {
+        Ok(pdf) => pdf,
+        Err(msg) => return HttpResponse::InternalServerError().body(msg),
+    };
+    let subject = format!("Quotation {}", quotation.id);
+    let body = format!(
+        "Dear {},\n\nPlease find our quotation attached. It is valid until {}.\n",
+        quotation.customer_name, quotation.valid_until
+    );
+    if let Err(e) = send_with_attachment(
+        to,
+        subject,
+        body,
+        format!("quotation-{}.pdf", quotation.id),
+        "application/pdf",
+        pdf,
+    )
+    .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to email quotation: {}", e));
+    }
+    HttpResponse::Ok().body("Quotation emailed successfully")
+}
+
+/// Checks a quotation can still be converted and prices it again at today's
+/// prices, refusing if the total moved unless the caller accepts that.
+async fn reprice(
+    db: &Surreal<Client>,
+    quotation_id: &str,
+    accept_price_changes: bool,
+) -> Result<(Quotation, Bill), HttpResponse> {
+    let Some(quotation) = get_quotation(db, quotation_id).await else {
+        return Err(HttpResponse::NotFound().body("Quotation not found"));
+    };
+    if quotation.converted_to.is_some() {
+        return Err(HttpResponse::Conflict().body("Quotation has already been converted"));
+    }
+    if !matches!(
+        quotation.status,
+        QuotationStatus::Sent | QuotationStatus::Accepted
+    ) {
+        return Err(
+            HttpResponse::Conflict().body("Only open or accepted quotations can be converted")
+        );
+    }
+
+    let mut bill = to_bill(&quotation);
+    bill.date = today();
+    price_lines(db, &mut bill).await?;
+    if (bill.total_amount - quotation.total_amount).abs() > 0.005 && !accept_price_changes {
+        return Err(HttpResponse::Conflict().body(format!(
+            "Prices have changed since the quotation: total is now {:.2} instead of {:.2}",
+            bill.total_amount, quotation.total_amount
+        )));
+    }
+    Ok((quotation, bill))
+}
+
+async fn mark_converted(
+    db: &Surreal<Client>,
+    quotation_id: &str,
+    converted_to: &str,
+) -> Result<(), surrealdb::Error> {
+    let query =
+        "UPDATE type::thing('quotation', $id) SET status = $status, converted_to = $converted_to";
+    db.query(query)
+        .bind(("id", quotation_id))
+        .bind(("status", QuotationStatus::Accepted))
+        .bind(("converted_to", converted_to))
+        .await?;
+    Ok(())
+}
+
+/// Turns an open or accepted quotation into an issued bill. Prices are
+/// worked out again and stock is checked as for any new bill.
+pub async fn convert_to_bill(req: web::Json<ConvertQuotationRequest>) -> impl Responder {
+    let db = get_db().await;
+    let _guard = lock_stock().await;
+    let (quotation, mut bill) = match reprice(db, &req.quotation_id, req.accept_price_changes).await
+    {
+        Ok(converted) => converted,
+        Err(response) => return response,
+    };
+    bill.location = req.location.clone();
+    bill.branch = req.branch.clone();
+    bill.invoice_series_id = req.invoice_series_id.clone();
+    let bill = match issue_bill(db, bill).await {
+        Ok(bill) => bill,
+        Err(response) => return response,
+    };
+
+    if let Err(e) = mark_converted(db, &quotation.id, &bill.id).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to update quotation: {}", e));
+    }
+    HttpResponse::Ok().json(bill)
+}
+
+/// Turns a quotation into a sales order, for goods that ship in stages.
+pub async fn convert_to_sales_order(
+    req: web::Json<ConvertQuotationToOrderRequest>,
+) -> impl Responder {
+    let db = get_db().await;
+    let _guard = lock_stock().await;
+    let (quotation, bill) = match reprice(db, &req.quotation_id, req.accept_price_changes).await {
+        Ok(converted) => converted,
+        Err(response) => return response,
+    };
+    let order = match place_order(db, bill, Some(quotation.id.clone())).await {
+        Ok(order) => order,
+        Err(response) => return response,
+    };
+
+    if let Err(e) = mark_converted(db, &quotation.id, &order.id).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to update quotation: {}", e));
+    }
+    HttpResponse::Ok().json(order)
+}
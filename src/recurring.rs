@@ -0,0 +1,569 @@
+use crate::billing::{Bill, BillItem, BillStatus, bill_pdf, issue_bill, price_lines};
+use crate::customers::get_customer_by_name;
+use crate::dates::today;
+use crate::db::DB;
+use crate::listing::{ListParams, ListQuery};
+use crate::mail::send_with_attachment;
+use crate::promotions::Discount;
+use crate::reservations::lock_stock;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Schedule {
+    // Day of the month; short months bill on their last day
+    Monthly { day: u32 },
+    Quarterly { day: u32 },
+    // Standard cron expression with a leading seconds field, e.g.
+    // "0 0 0 1,15 * *" for the 1st and 15th; only the date part is used
+    Cron { expression: String },
+}
+
+impl Schedule {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Schedule::Monthly { day } | Schedule::Quarterly { day } if !(1..=31).contains(day) => {
+                Err("Day of the month must be between 1 and 31".to_string())
+            }
+            Schedule::Cron { expression } => cron::Schedule::from_str(expression)
+                .map(|_| ())
+                .map_err(|e| format!("Invalid cron expression: {}", e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// First billing date strictly after `date`.
+    pub fn next_after(&self, date: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Schedule::Monthly { day } => Some(add_months(date, 1, *day)),
+            Schedule::Quarterly { day } => Some(add_months(date, 3, *day)),
+            Schedule::Cron { expression } => {
+                let schedule = cron::Schedule::from_str(expression).ok()?;
+                let end_of_day = Utc.from_utc_datetime(&date.and_hms_opt(23, 59, 59)?);
+                schedule
+                    .after(&end_of_day)
+                    .next()
+                    .map(|next| next.date_naive())
+            }
+        }
+    }
+}
+
+fn add_months(date: NaiveDate, months: i32, day: u32) -> NaiveDate {
+    let index = date.year() * 12 + date.month0() as i32 + months;
+    let (year, month) = (index.div_euclid(12), index.rem_euclid(12) as u32 + 1);
+    (1..=day)
+        .rev()
+        .find_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+        .unwrap_or(date)
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecurringStatus {
+    Active,
+    Paused,
+    Ended,
+}
+
+/// A bill issued again and again on a schedule, e.g. a monthly maintenance
+/// fee. Prices are worked out afresh for every bill.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RecurringBill {
+    pub id: String,
+    pub customer_name: String,
+    pub items: Vec<BillItem>,
+    pub discount: Option<Discount>,
+    pub branch: Option<String>,
+    pub location: Option<String>,
+    pub invoice_series_id: Option<String>,
+    pub schedule: Schedule,
+    pub next_run: NaiveDate,
+    // No bills are generated after this date
+    pub end_date: Option<NaiveDate>,
+    pub auto_email: bool,
+    // Defaults to the customer's email
+    pub email_to: Option<String>,
+    pub status: RecurringStatus,
+    pub last_bill_id: Option<String>,
+    // Why the last attempt failed; it is retried on the next pass
+    pub last_error: Option<String>,
+    #[serde(with = "crate::dates::datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateRecurringBillRequest {
+    pub customer_name: String,
+    pub items: Vec<BillItem>,
+    pub discount: Option<Discount>,
+    pub branch: Option<String>,
+    pub location: Option<String>,
+    pub invoice_series_id: Option<String>,
+    pub schedule: Schedule,
+    // Date of the first bill
+    pub start_date: NaiveDate,
+    pub end_date: Option<NaiveDate>,
+    #[serde(default)]
+    pub auto_email: bool,
+    pub email_to: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RecurringBillIdRequest {
+    pub recurring_bill_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct SetEndDateRequest {
+    pub recurring_bill_id: String,
+    // None bills indefinitely
+    pub end_date: Option<NaiveDate>,
+}
+
+#[derive(Deserialize)]
+pub struct RecurringBillFilters {
+    pub customer_name: Option<String>,
+    pub status: Option<RecurringStatus>,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+async fn get_recurring_bill(db: &Surreal<Client>, recurring_id: &str) -> Option<RecurringBill> {
+    let query = "SELECT * FROM type::thing('recurring_bill', $id)";
+    let res = db.query(query).bind(("id", recurring_id)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<RecurringBill>>().ok()?;
+    result.into_iter().next()
+}
+
+/// Saves the outcome of a scheduler run. Only the run fields are written,
+/// and only while the template is still active, so a pause, resume or new end
+/// date made in the meantime is kept. Returns false if nothing was saved.
+async fn save_run(
+    db: &Surreal<Client>,
+    recurring: &RecurringBill,
+) -> Result<bool, surrealdb::Error> {
+    let query = "UPDATE type::thing('recurring_bill', $id) SET next_run = $next_run, status = $status, last_bill_id = $last_bill_id, last_error = $last_error WHERE status = $active";
+    let res = db
+        .query(query)
+        .bind(("id", &recurring.id))
+        .bind(("next_run", recurring.next_run))
+        .bind(("status", recurring.status))
+        .bind(("last_bill_id", &recurring.last_bill_id))
+        .bind(("last_error", &recurring.last_error))
+        .bind(("active", RecurringStatus::Active))
+        .await?;
+    Ok(res
+        .get(0)
+        .and_then(|r| r.result::<Vec<serde_json::Value>>().ok())
+        .is_some_and(|rows| !rows.is_empty()))
+}
+
+/// Writes the fields a user action changed, provided the template is still
+/// in the status the action was checked against.
+async fn save_status(
+    db: &Surreal<Client>,
+    recurring: &RecurringBill,
+    expected: &[RecurringStatus],
+) -> Result<bool, surrealdb::Error> {
+    let query = "UPDATE type::thing('recurring_bill', $id) SET next_run = $next_run, end_date = $end_date, status = $status WHERE status INSIDE $expected";
+    let res = db
+        .query(query)
+        .bind(("id", &recurring.id))
+        .bind(("next_run", recurring.next_run))
+        .bind(("end_date", recurring.end_date))
+        .bind(("status", recurring.status))
+        .bind(("expected", expected))
+        .await?;
+    Ok(res
+        .get(0)
+        .and_then(|r| r.result::<Vec<serde_json::Value>>().ok())
+        .is_some_and(|rows| !rows.is_empty()))
+}
+
+/// The bill already issued for a template's run date, if a previous pass got
+/// as far as issuing it but not as far as saving the template.
+async fn existing_bill(
+    db: &Surreal<Client>,
+    recurring: &RecurringBill,
+) -> Result<Option<Bill>, surrealdb::Error> {
+    let query = "SELECT * FROM bill WHERE recurring_bill_id = $recurring_bill_id AND date = $date AND status != $void LIMIT 1";
+    let res = db
+        .query(query)
+        .bind(("recurring_bill_id", &recurring.id))
+        .bind(("date", recurring.next_run))
+        .bind(("void", BillStatus::Void))
+        .await?;
+    Ok(res
+        .get(0)
+        .and_then(|r| r.result::<Vec<Bill>>().ok())
+        .and_then(|bills| bills.into_iter().next()))
+}
+
+/// Ends the template once its next run falls after the end date.
+fn close_if_past_end(recurring: &mut RecurringBill) {
+    if recurring
+        .end_date
+        .is_some_and(|end_date| recurring.next_run > end_date)
+    {
+        recurring.status = RecurringStatus::Ended;
+    }
+}
+
+async fn response_message(response: HttpResponse) -> String {
+    let status = response.status();
+    match actix_web::body::to_bytes(response.into_body()).await {
+        Ok(body) if !body.is_empty() => String::from_utf8_lossy(&body).into_owned(),
+        _ => status.to_string(),
+    }
+}
+
+/// Issues one bill for the template's current run date, the same way
+/// `create_bill` would. A bill already issued for that date is returned
+/// instead of issuing a second one.
+async fn generate_bill(db: &Surreal<Client>, recurring: &RecurringBill) -> Result<Bill, String> {
+    let _guard = lock_stock().await;
+    match existing_bill(db, recurring).await {
+        Ok(Some(bill)) => return Ok(bill),
+        Ok(None) => {}
+        Err(e) => return Err(format!("Failed to check for an existing bill: {}", e)),
+    }
+    let mut bill = Bill::draft(
+        recurring.customer_name.clone(),
+        recurring.next_run,
+        recurring.items.clone(),
+    );
+    bill.discount = recurring.discount;
+    bill.branch = recurring.branch.clone();
+    bill.location = recurring.location.clone();
+    bill.invoice_series_id = recurring.invoice_series_id.clone();
+    bill.recurring_bill_id = Some(recurring.id.clone());
+    if let Err(response) = price_lines(db, &mut bill).await {
+        return Err(response_message(response).await);
+    }
+    match issue_bill(db, bill).await {
+        Ok(bill) => Ok(bill),
+        Err(response) => Err(response_message(response).await),
+    }
+}
+
+async fn email_bill(
+    db: &Surreal<Client>,
+    recurring: &RecurringBill,
+    bill: &Bill,
+) -> Result<(), String> {
+    let to = match &recurring.email_to {
+        Some(to) => to.clone(),
+        None => get_customer_by_name(db, &bill.customer_name)
+            .await
+            .and_then(|customer| customer.email)
+            .ok_or_else(|| "Customer has no email address".to_string())?,
+    };
+    let pdf = bill_pdf(db, bill).await?;
+    let number = bill
+        .invoice_number
+        .clone()
+        .unwrap_or_else(|| bill.id.clone());
+    let body = format!(
+        "Dear {},\n\nPlease find attached invoice {} dated {} for {:.2}.\n",
+        bill.customer_name, number, bill.date, bill.total_amount
+    );
+    send_with_attachment(
+        to,
+        format!("Invoice {}", number),
+        body,
+        format!("invoice-{}.pdf", number.replace('/', "-")),
+        "application/pdf",
+        pdf,
+    )
+    .await
+    .map_err(|e| format!("Failed to email bill: {}", e))
+}
+
+/// Catches a template up to today, one bill per missed run date. The
+/// template is read again before every bill so changes made while it runs
+/// take effect straight away.
+async fn run_recurring_bill(db: &Surreal<Client>, recurring_id: &str) {
+    let today = today();
+    loop {
+        let Some(mut recurring) = get_recurring_bill(db, recurring_id).await else {
+            return;
+        };
+        if recurring.status != RecurringStatus::Active || recurring.next_run > today {
+            return;
+        }
+        let bill = match generate_bill(db, &recurring).await {
+            Ok(bill) => bill,
+            Err(msg) => {
+                eprintln!("Failed to generate recurring bill {}: {msg}", recurring.id);
+                recurring.last_error = Some(msg);
+                if let Err(e) = save_run(db, &recurring).await {
+                    eprintln!("Failed to save recurring bill {}: {e}", recurring.id);
+                }
+                return;
+            }
+        };
+        recurring.last_bill_id = Some(bill.id.clone());
+        recurring.last_error = None;
+        if recurring.auto_email {
+            // The bill stands even if the email does not go out
+            if let Err(msg) = email_bill(db, &recurring, &bill).await {
+                eprintln!("Failed to email recurring bill {}: {msg}", bill.id);
+                recurring.last_error = Some(msg);
+            }
+        }
+        match recurring.schedule.next_after(recurring.next_run) {
+            Some(next_run) => recurring.next_run = next_run,
+            None => recurring.status = RecurringStatus::Ended,
+        }
+        close_if_past_end(&mut recurring);
+        // Saved after every bill so a crash cannot bill the same date twice
+        match save_run(db, &recurring).await {
+            Ok(true) => {}
+            // Paused or ended while the bill was being issued
+            Ok(false) => return,
+            Err(e) => {
+                eprintln!("Failed to save recurring bill {}: {e}", recurring.id);
+                return;
+            }
+        }
+    }
+}
+
+/// Issues the bills of recurring templates that have fallen due.
+pub fn spawn_recurring_scheduler() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(15 * 60));
+        loop {
+            interval.tick().await;
+            let db = get_db().await;
+            let query =
+                "SELECT * FROM recurring_bill WHERE status = $active AND next_run <= $today";
+            let due = match db
+                .query(query)
+                .bind(("active", RecurringStatus::Active))
+                .bind(("today", today()))
+                .await
+            {
+                Ok(res) => res
+                    .get(0)
+                    .and_then(|r| r.result::<Vec<RecurringBill>>().ok())
+                    .unwrap_or_default(),
+                Err(e) => {
+                    eprintln!("Failed to load recurring bills: {e}");
+                    continue;
+                }
+            };
+            for recurring in due {
+                run_recurring_bill(db, &recurring.id).await;
+            }
+        }
+    });
+}
+
+pub async fn create_recurring_bill(req: web::Json<CreateRecurringBillRequest>) -> impl Responder {
+    let db = get_db().await;
+    if req.items.is_empty() {
+        return HttpResponse::BadRequest().body("A recurring bill needs at least one line");
+    }
+    if let Err(msg) = req.schedule.validate() {
+        return HttpResponse::BadRequest().body(msg);
+    }
+    if let Some(Err(msg)) = req.discount.map(|discount| discount.validate()) {
+        return HttpResponse::BadRequest().body(msg);
+    }
+    // Past dates would be billed all at once on the next scheduler pass
+    if req.start_date < today() {
+        return HttpResponse::BadRequest().body("Start date cannot be in the past");
+    }
+    if req
+        .end_date
+        .is_some_and(|end_date| end_date < req.start_date)
+    {
+        return HttpResponse::BadRequest().body("End date is before the start date");
+    }
+    let recurring = RecurringBill {
+        id: uuid::Uuid::new_v4().to_string(),
+        customer_name: req.customer_name.clone(),
+        items: req.items.clone(),
+        discount: req.discount,
+        branch: req.branch.clone(),
+        location: req.location.clone(),
+        invoice_series_id: req.invoice_series_id.clone(),
+        schedule: req.schedule.clone(),
+        next_run: req.start_date,
+        end_date: req.end_date,
+        auto_email: req.auto_email,
+        email_to: req.email_to.clone(),
+        status: RecurringStatus::Active,
+        last_bill_id: None,
+        last_error: None,
+        created_at: Utc::now(),
+    };
+    match db
+        .create::<_, RecurringBill>("recurring_bill")
+        .content(&recurring)
+        .await
+    {
+        Ok(_) => HttpResponse::Ok().json(recurring),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to create recurring bill: {}", e)),
+    }
+}
+
+pub async fn list_recurring_bills(
+    params: web::Query<ListParams>,
+    filters: web::Query<RecurringBillFilters>,
+) -> impl Responder {
+    let db = get_db().await;
+    let mut query = ListQuery::new(
+        "recurring_bill",
+        &["customer_name"],
+        &["next_run", "created_at", "customer_name"],
+    );
+    query.filter("customer_name", "=", filters.customer_name.as_ref());
+    query.filter("status", "=", filters.status.as_ref());
+    match query.fetch::<RecurringBill>(db, &params).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to list recurring bills: {}", e)),
+    }
+}
+
+pub async fn get_recurring_bill_by_id(path: web::Path<String>) -> impl Responder {
+    let db = get_db().await;
+    match get_recurring_bill(db, &path.into_inner()).await {
+        Some(recurring) => HttpResponse::Ok().json(recurring),
+        None => HttpResponse::NotFound().body("Recurring bill not found"),
+    }
+}
+
+pub async fn pause_recurring_bill(req: web::Json<RecurringBillIdRequest>) -> impl Responder {
+    let db = get_db().await;
+    let Some(mut recurring) = get_recurring_bill(db, &req.recurring_bill_id).await else {
+        return HttpResponse::NotFound().body("Recurring bill not found");
+    };
+    if recurring.status != RecurringStatus::Active {
+        return HttpResponse::Conflict().body("Recurring bill is not active");
+    }
+    recurring.status = RecurringStatus::Paused;
+    match save_status(db, &recurring, &[RecurringStatus::Active]).await {
+        Ok(true) => HttpResponse::Ok().json(recurring),
+        Ok(false) => HttpResponse::Conflict().body("Recurring bill changed, try again"),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to pause recurring bill: {}", e)),
+    }
+}
+
+/// Resumes billing from the next run date on or after today; dates missed
+/// while paused are not billed.
+pub async fn resume_recurring_bill(req: web::Json<RecurringBillIdRequest>) -> impl Responder {
+    let db = get_db().await;
+    let Some(mut recurring) = get_recurring_bill(db, &req.recurring_bill_id).await else {
+        return HttpResponse::NotFound().body("Recurring bill not found");
+    };
+    if recurring.status != RecurringStatus::Paused {
+        return HttpResponse::Conflict().body("Recurring bill is not paused");
+    }
+    let today = today();
+    while recurring.next_run < today {
+        match recurring.schedule.next_after(recurring.next_run) {
+            Some(next_run) => recurring.next_run = next_run,
+            None => break,
+        }
+    }
+    recurring.status = RecurringStatus::Active;
+    close_if_past_end(&mut recurring);
+    match save_status(db, &recurring, &[RecurringStatus::Paused]).await {
+        Ok(true) => HttpResponse::Ok().json(recurring),
+        Ok(false) => HttpResponse::Conflict().body("Recurring bill changed, try again"),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to resume recurring bill: {}", e)),
+    }
+}
+
+pub async fn set_end_date(req: web::Json<SetEndDateRequest>) -> impl Responder {
+    let db = get_db().await;
+    let Some(mut recurring) = get_recurring_bill(db, &req.recurring_bill_id).await else {
+        return HttpResponse::NotFound().body("Recurring bill not found");
+    };
+    if recurring.status == RecurringStatus::Ended {
+        return HttpResponse::Conflict().body("Recurring bill has already ended");
+    }
+    recurring.end_date = req.end_date;
+    close_if_past_end(&mut recurring);
+    match save_status(
+        db,
+        &recurring,
+        &[RecurringStatus::Active, RecurringStatus::Paused],
+    )
+    .await
+    {
+        Ok(true) => HttpResponse::Ok().json(recurring),
+        Ok(false) => HttpResponse::Conflict().body("Recurring bill changed, try again"),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to update recurring bill: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn add_months_falls_back_to_end_of_short_months() {
+        assert_eq!(add_months(date("2026-01-31"), 1, 31), date("2026-02-28"));
+        assert_eq!(add_months(date("2028-01-31"), 1, 31), date("2028-02-29"));
+        assert_eq!(add_months(date("2026-03-15"), 1, 31), date("2026-04-30"));
+    }
+
+    #[test]
+    fn add_months_crosses_years() {
+        assert_eq!(add_months(date("2026-12-10"), 1, 10), date("2027-01-10"));
+        assert_eq!(add_months(date("2026-11-15"), 3, 15), date("2027-02-15"));
+    }
+
+    #[test]
+    fn monthly_and_quarterly_keep_their_day() {
+        let monthly = Schedule::Monthly { day: 31 };
+        assert_eq!(
+            monthly.next_after(date("2026-01-31")),
+            Some(date("2026-02-28"))
+        );
+        assert_eq!(
+            monthly.next_after(date("2026-02-28")),
+            Some(date("2026-03-31"))
+        );
+        let quarterly = Schedule::Quarterly { day: 1 };
+        assert_eq!(
+            quarterly.next_after(date("2026-10-01")),
+            Some(date("2027-01-01"))
+        );
+    }
+
+    #[test]
+    fn cron_is_strictly_after_the_date() {
+        let schedule = Schedule::Cron {
+            expression: "0 0 0 1,15 * *".to_string(),
+        };
+        assert_eq!(
+            schedule.next_after(date("2026-01-01")),
+            Some(date("2026-01-15"))
+        );
+        assert_eq!(
+            schedule.next_after(date("2026-01-15")),
+            Some(date("2026-02-01"))
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use crate::db::DB;
+use crate::inventory::{get_item, get_location_quantity};
+use crate::mail::send_message;
+use actix_web::{HttpResponse, Responder, web};
+use serde::{Deserialize, Serialize};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+/// Minimum stock for an item, either overall or at a single location.
+/// `alerted` is set once purchasing has been emailed and cleared when stock
+/// climbs back above the minimum, so each dip only sends one alert.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReorderRule {
+    pub id: String,
+    pub item_id: String,
+    pub location: Option<String>,
+    pub min_quantity: i32,
+    pub reorder_quantity: i32,
+    pub alerted: bool,
+}
+
+#[derive(Deserialize)]
+pub struct SetReorderRuleRequest {
+    pub item_id: String,
+    pub location: Option<String>,
+    pub min_quantity: i32,
+    pub reorder_quantity: i32,
+}
+
+#[derive(Serialize)]
+pub struct LowStockEntry {
+    pub item_id: String,
+    pub item_name: String,
+    pub location: Option<String>,
+    pub quantity: i32,
+    pub min_quantity: i32,
+    pub reorder_quantity: i32,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+async fn get_rules(
+    db: &Surreal<Client>,
+    item_id: Option<&str>,
+) -> Result<Vec<ReorderRule>, surrealdb::Error> {
+    let res = match item_id {
+        Some(item_id) => {
+            db.query("SELECT * FROM reorder_rule WHERE item_id = $item_id")
+                .bind(("item_id", item_id))
+                .await?
+        }
+        None => db.query("SELECT * FROM reorder_rule").await?,
+    };
+    Ok(res
+        .get(0)
+        .and_then(|r| r.result::<Vec<ReorderRule>>().ok())
+        .unwrap_or_default())
+}
+
+async fn current_quantity(db: &Surreal<Client>, rule: &ReorderRule) -> Option<i32> {
+    match &rule.location {
+        Some(location) => get_location_quantity(db, &rule.item_id, location).await,
+        None => get_item(db, &rule.item_id).await.map(|item| item.quantity),
+    }
+}
+
+/// Runs after every stock change of an item. Emails purchasing when a rule's
+/// minimum is reached for the first time since it was last replenished.
+pub async fn check_reorder_point(item_id: &str, location: Option<&str>) {
+    let db = get_db().await;
+    let rules = match get_rules(db, Some(item_id)).await {
+        Ok(rules) => rules,
+        Err(e) => {
+            eprintln!("Failed to load reorder rules: {e}");
+            return;
+        }
+    };
+    for rule in rules {
+        // Location rules only care about stock changes at their own location
+        if rule.location.is_some() && rule.location.as_deref() != location {
+            continue;
+        }
+        let Some(quantity) = current_quantity(db, &rule).await else {
+            continue;
+        };
+        if quantity > rule.min_quantity {
+            if rule.alerted {
+                let _ = db
+                    .query("UPDATE type::thing('reorder_rule', $id) SET alerted = false")
+                    .bind(("id", &rule.id))
+                    .await;
+            }
+            continue;
+        }
+        // Only the check that flips the flag sends the email
+        let query =
+            "UPDATE type::thing('reorder_rule', $id) SET alerted = true WHERE alerted = false";
+        let flipped = match db.query(query).bind(("id", &rule.id)).await {
+            Ok(res) => res
+                .get(0)
+                .and_then(|r| r.result::<Vec<ReorderRule>>().ok())
+                .is_some_and(|updated| !updated.is_empty()),
+            Err(_) => false,
+        };
+        if !flipped {
+            continue;
+        }
+        // A lost alert would not be sent again until stock recovers, so the
+        // flag is cleared for the next stock change to try again
+        if let Err(e) = send_alert(db, &rule, quantity).await {
+            eprintln!("Failed to send low stock alert: {e}");
+            let query = "UPDATE type::thing('reorder_rule', $id) SET alerted = false";
+            if let Err(e) = db.query(query).bind(("id", &rule.id)).await {
+                eprintln!("Failed to reset reorder alert {}: {e}", rule.id);
+            }
+        }
+    }
+}
+
+async fn send_alert(db: &Surreal<Client>, rule: &ReorderRule, quantity: i32) -> Result<(), String> {
+    let to = std::env::var("PURCHASING_EMAIL").map_err(|_| "PURCHASING_EMAIL not set")?;
+    let name = get_item(db, &rule.item_id)
+        .await
+        .map_or_else(|| rule.item_id.clone(), |item| item.name);
+    let location = rule
+        .location
+        .as_deref()
+        .map_or_else(String::new, |location| format!(" at {}", location));
+    let subject = format!("Low stock: {}{}", name, location);
+    let body = format!(
+        "{}{} is down to {} units (reorder point {}). Suggested reorder quantity: {}.",
+        name, location, quantity, rule.min_quantity, rule.reorder_quantity
+    );
+    send_message(to, subject, body)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+pub async fn set_reorder_rule(req: web::Json<SetReorderRuleRequest>) -> impl Responder {
+    let db = get_db().await;
+    if req.min_quantity < 0 || req.reorder_quantity <= 0 {
+        return HttpResponse::BadRequest()
+            .body("Minimum must not be negative and reorder quantity must be positive");
+    }
+    if get_item(db, &req.item_id).await.is_none() {
+        return HttpResponse::NotFound().body("Item not found");
+    }
+    let existing = match get_rules(db, Some(&req.item_id)).await {
+        Ok(rules) => rules.into_iter().find(|rule| rule.location == req.location),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to load reorder rules: {}", e));
+        }
+    };
+    let rule = ReorderRule {
+        id: existing
+            .as_ref()
+            .map_or_else(|| uuid::Uuid::new_v4().to_string(), |rule| rule.id.clone()),
+        item_id: req.item_id.clone(),
+        location: req.location.clone(),
+        min_quantity: req.min_quantity,
+        reorder_quantity: req.reorder_quantity,
+        alerted: existing.is_some_and(|rule| rule.alerted),
+    };
+    let query = "UPDATE type::thing('reorder_rule', $id) CONTENT $rule";
+    if let Err(e) = db
+        .query(query)
+        .bind(("id", &rule.id))
+        .bind(("rule", &rule))
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to save reorder rule: {}", e));
+    }
+    HttpResponse::Ok().json(rule)
+}
+
+pub async fn low_stock() -> impl Responder {
+    let db = get_db().await;
+    let rules = match get_rules(db, None).await {
+        Ok(rules) => rules,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to load reorder rules: {}", e));
+        }
+    };
+    let mut entries = Vec::new();
+    for rule in rules {
+        let Some(item) = get_item(db, &rule.item_id).await else {
+            continue;
+        };
+        let quantity = match &rule.location {
+            Some(location) => get_location_quantity(db, &item.id, location)
+                .await
+                .unwrap_or(0),
+            None => item.quantity,
+        };
+        if quantity <= rule.min_quantity {
+            entries.push(LowStockEntry {
+                item_id: item.id,
+                item_name: item.name,
+                location: rule.location,
+                quantity,
+                min_quantity: rule.min_quantity,
+                reorder_quantity: rule.reorder_quantity,
+            });
+        }
+    }
+    HttpResponse::Ok().json(entries)
+}
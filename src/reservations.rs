@@ -0,0 +1,291 @@
+use crate::db::DB;
+use crate::inventory::get_item;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::sql::Datetime;
+use tokio::sync::{Mutex, MutexGuard};
+
+// Serialises "check availability, then take stock" sequences so two tills
+// can't both sell the last unit.
+static STOCK_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+const DEFAULT_HOLD_MINUTES: i64 = 30;
+// 30 days; longer holds would keep stock out of sale indefinitely
+const MAX_HOLD_MINUTES: i64 = 30 * 24 * 60;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReservationStatus {
+    Active,
+    Consumed,
+    Released,
+    Expired,
+}
+
+/// Stock held for a draft bill or sales order until it is billed, released
+/// or the hold expires.
+#[derive(Serialize, Deserialize)]
+pub struct Reservation {
+    pub id: String,
+    pub item_id: String,
+    pub quantity: i32,
+    pub reference: String,
+    pub status: ReservationStatus,
+    #[serde(with = "crate::dates::datetime")]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "crate::dates::datetime")]
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateReservationRequest {
+    pub item_id: String,
+    pub quantity: i32,
+    pub reference: String,
+    pub hold_minutes: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ReleaseReservationRequest {
+    pub reservation_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct ListReservationsQuery {
+    pub reference: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReservedTotal {
+    item_id: String,
+    quantity: i32,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+pub async fn lock_stock() -> MutexGuard<'static, ()> {
+    STOCK_LOCK.lock().await
+}
+
+/// Units of an item held by live reservations, leaving out the ones made
+/// under `exclude_reference` (the caller's own holds).
+pub async fn reserved_quantity(
+    db: &Surreal<Client>,
+    item_id: &str,
+    exclude_reference: Option<&str>,
+) -> Result<i32, surrealdb::Error> {
+    let query = "SELECT * FROM reservation WHERE item_id = $item_id AND status = $status AND expires_at > $now";
+    let res = db
+        .query(query)
+        .bind(("item_id", item_id))
+        .bind(("status", ReservationStatus::Active))
+        .bind(("now", Datetime::from(Utc::now())))
+        .await?;
+    let reservations = res
+        .get(0)
+        .and_then(|r| r.result::<Vec<Reservation>>().ok())
+        .unwrap_or_default();
+    Ok(reservations
+        .iter()
+        .filter(|r| Some(r.reference.as_str()) != exclude_reference)
+        .map(|r| r.quantity)
+        .sum())
+}
+
+/// Live reserved quantities for every item, keyed by item id.
+pub async fn reserved_by_item(
+    db: &Surreal<Client>,
+) -> Result<HashMap<String, i32>, surrealdb::Error> {
+    let query = "SELECT item_id, math::sum(quantity) AS quantity FROM reservation WHERE status = $status AND expires_at > $now GROUP BY item_id";
+    let res = db
+        .query(query)
+        .bind(("status", ReservationStatus::Active))
+        .bind(("now", Datetime::from(Utc::now())))
+        .await?;
+    Ok(res
+        .get(0)
+        .and_then(|r| r.result::<Vec<ReservedTotal>>().ok())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|total| (total.item_id, total.quantity))
+        .collect())
+}
+
+/// Marks every live reservation made under `reference` as consumed.
+pub async fn consume_reservations(
+    db: &Surreal<Client>,
+    reference: &str,
+) -> Result<(), surrealdb::Error> {
+    let query = "UPDATE reservation SET status = $consumed WHERE reference = $reference AND status = $active";
+    db.query(query)
+        .bind(("consumed", ReservationStatus::Consumed))
+        .bind(("reference", reference))
+        .bind(("active", ReservationStatus::Active))
+        .await?;
+    Ok(())
+}
+
+/// Puts holds consumed by a bill that then failed to save back in place.
+pub async fn restore_reservations(
+    db: &Surreal<Client>,
+    reference: &str,
+) -> Result<(), surrealdb::Error> {
+    let query = "UPDATE reservation SET status = $active WHERE reference = $reference AND status = $consumed";
+    db.query(query)
+        .bind(("active", ReservationStatus::Active))
+        .bind(("reference", reference))
+        .bind(("consumed", ReservationStatus::Consumed))
+        .await?;
+    Ok(())
+}
+
+/// Lets go of every live hold under a reference, e.g. when its draft is voided.
+pub async fn release_reservations(
+    db: &Surreal<Client>,
+    reference: &str,
+) -> Result<(), surrealdb::Error> {
+    let query = "UPDATE reservation SET status = $released WHERE reference = $reference AND status = $active";
+    db.query(query)
+        .bind(("released", ReservationStatus::Released))
+        .bind(("reference", reference))
+        .bind(("active", ReservationStatus::Active))
+        .await?;
+    Ok(())
+}
+
+/// Releases holds whose time is up, e.g. carts and drafts that were abandoned.
+pub fn spawn_expiry_sweeper() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let db = get_db().await;
+            let query = "UPDATE reservation SET status = $expired WHERE status = $active AND expires_at <= $now";
+            if let Err(e) = db
+                .query(query)
+                .bind(("expired", ReservationStatus::Expired))
+                .bind(("active", ReservationStatus::Active))
+                .bind(("now", Datetime::from(Utc::now())))
+                .await
+            {
+                eprintln!("Failed to expire reservations: {e}");
+            }
+        }
+    });
+}
+
+pub async fn create_reservation(req: web::Json<CreateReservationRequest>) -> impl Responder {
+    let db = get_db().await;
+    if req.quantity <= 0 {
+        return HttpResponse::BadRequest().body("Quantity must be positive");
+    }
+    let hold_minutes = req.hold_minutes.unwrap_or(DEFAULT_HOLD_MINUTES);
+    if hold_minutes <= 0 {
+        return HttpResponse::BadRequest().body("Hold time must be positive");
+    }
+    if hold_minutes > MAX_HOLD_MINUTES {
+        return HttpResponse::BadRequest().body(format!(
+            "Hold time can be at most {} minutes",
+            MAX_HOLD_MINUTES
+        ));
+    }
+
+    let _guard = lock_stock().await;
+    let Some(item) = get_item(db, &req.item_id).await else {
+        return HttpResponse::NotFound().body("Item not found");
+    };
+    let reserved = match reserved_quantity(db, &item.id, None).await {
+        Ok(reserved) => reserved,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to check reservations: {}", e));
+        }
+    };
+    if item.quantity - reserved < req.quantity {
+        return HttpResponse::Conflict().body(format!(
+            "Only {} units of {} are available",
+            (item.quantity - reserved).max(0),
+            item.name
+        ));
+    }
+    let now = Utc::now();
+    let reservation = Reservation {
+        id: uuid::Uuid::new_v4().to_string(),
+        item_id: item.id,
+        quantity: req.quantity,
+        reference: req.reference.clone(),
+        status: ReservationStatus::Active,
+        created_at: now,
+        expires_at: now + Duration::minutes(hold_minutes),
+    };
+    if let Err(e) = db
+        .create::<_, Reservation>("reservation")
+        .content(&reservation)
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to create reservation: {}", e));
+    }
+    HttpResponse::Ok().json(reservation)
+}
+
+pub async fn release_reservation(req: web::Json<ReleaseReservationRequest>) -> impl Responder {
+    let db = get_db().await;
+    let query =
+        "UPDATE type::thing('reservation', $id) SET status = $released WHERE status = $active";
+    match db
+        .query(query)
+        .bind(("id", &req.reservation_id))
+        .bind(("released", ReservationStatus::Released))
+        .bind(("active", ReservationStatus::Active))
+        .await
+    {
+        Ok(res) => {
+            let released = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<Reservation>>().ok())
+                .unwrap_or_default();
+            if released.is_empty() {
+                return HttpResponse::NotFound().body("No active reservation found");
+            }
+            HttpResponse::Ok().body("Reservation released")
+        }
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to release reservation: {}", e)),
+    }
+}
+
+pub async fn list_reservations(query: web::Query<ListReservationsQuery>) -> impl Responder {
+    let db = get_db().await;
+    let res = match &query.reference {
+        Some(reference) => {
+            db.query("SELECT * FROM reservation WHERE reference = $reference")
+                .bind(("reference", reference))
+                .await
+        }
+        None => {
+            db.query("SELECT * FROM reservation WHERE status = $status")
+                .bind(("status", ReservationStatus::Active))
+                .await
+        }
+    };
+    match res {
+        Ok(res) => {
+            let reservations = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<Reservation>>().ok())
+                .unwrap_or_default();
+            HttpResponse::Ok().json(reservations)
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list reservations: {}", e))
+        }
+    }
+}
@@ -0,0 +1,674 @@
+use crate::billing::{
+    Bill, BillItem, issue_delivered_bill, issue_stock, line_tax, price_lines,
+    release_delivery_notes, return_bill_stock,
+};
+use crate::currency::{base_currency, normalize_currency, rate_on};
+use crate::dates::today;
+use crate::db::DB;
+use crate::inventory::{BundleComponent, get_item};
+use crate::listing::{ListParams, ListQuery};
+use crate::lots::LotAllocation;
+use crate::promotions::{AppliedDiscount, Discount, claim_coupon, release_coupon, round_cents};
+use crate::reservations::{lock_stock, reserved_by_item};
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SalesOrderStatus {
+    Open,
+    PartiallyDelivered,
+    Delivered,
+    Cancelled,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SalesOrderLine {
+    // Priced for the full ordered quantity
+    #[serde(flatten)]
+    pub item: BillItem,
+    #[serde(default)]
+    pub delivered_quantity: i32,
+}
+
+impl SalesOrderLine {
+    /// Units still to ship; anything not in stock is on backorder.
+    pub fn outstanding(&self) -> i32 {
+        self.item.quantity - self.delivered_quantity
+    }
+}
+
+/// Prices are agreed when the order is placed; stock only leaves with
+/// delivery notes, and delivery notes are invoiced later.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SalesOrder {
+    pub id: String,
+    pub customer_name: String,
+    pub lines: Vec<SalesOrderLine>,
+    pub discount: Option<Discount>,
+    pub coupon_code: Option<String>,
+    pub discounts: Vec<AppliedDiscount>,
+    pub discount_amount: f64,
+    pub tax_amount: f64,
+    pub total_amount: f64,
+    // Every amount on the order is in this currency
+    #[serde(default = "base_currency")]
+    pub currency: String,
+    pub date: NaiveDate,
+    pub status: SalesOrderStatus,
+    pub quotation_id: Option<String>,
+    #[serde(with = "crate::dates::datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DeliveryLine {
+    // Position of the line on the sales order
+    pub line_index: usize,
+    pub item_id: String,
+    pub quantity: i32,
+    #[serde(default)]
+    pub serials: Vec<String>,
+    #[serde(default)]
+    pub lots: Vec<LotAllocation>,
+    #[serde(default)]
+    pub components: Vec<BundleComponent>,
+    #[serde(default)]
+    pub cogs: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeliveryNote {
+    pub id: String,
+    pub sales_order_id: String,
+    pub customer_name: String,
+    pub lines: Vec<DeliveryLine>,
+    pub location: Option<String>,
+    #[serde(with = "crate::dates::datetime")]
+    pub delivered_at: DateTime<Utc>,
+    pub invoiced_bill_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateSalesOrderRequest {
+    pub customer_name: String,
+    pub items: Vec<BillItem>,
+    // Defaults to today
+    pub date: Option<NaiveDate>,
+    pub discount: Option<Discount>,
+    pub coupon_code: Option<String>,
+    // Defaults to the base currency
+    pub currency: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DeliverLineRequest {
+    pub line_index: usize,
+    pub quantity: i32,
+    #[serde(default)]
+    pub serials: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateDeliveryNoteRequest {
+    pub sales_order_id: String,
+    pub location: Option<String>,
+    pub lines: Vec<DeliverLineRequest>,
+}
+
+#[derive(Deserialize)]
+pub struct InvoiceDeliveriesRequest {
+    pub delivery_note_ids: Vec<String>,
+    pub date: Option<NaiveDate>,
+    pub branch: Option<String>,
+    pub invoice_series_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CancelSalesOrderRequest {
+    pub sales_order_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct SalesOrderFilters {
+    pub customer_name: Option<String>,
+    pub status: Option<SalesOrderStatus>,
+}
+
+#[derive(Deserialize)]
+pub struct DeliveryNoteFilters {
+    pub sales_order_id: Option<String>,
+    pub customer_name: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Backorder {
+    pub sales_order_id: String,
+    pub customer_name: String,
+    pub line_index: usize,
+    pub item_id: String,
+    pub item_name: String,
+    pub ordered: i32,
+    pub delivered: i32,
+    pub outstanding: i32,
+    // Free stock that could ship now
+    pub available: i32,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+async fn get_sales_order(db: &Surreal<Client>, order_id: &str) -> Option<SalesOrder> {
+    let query = "SELECT * FROM type::thing('sales_order', $id)";
+    let res = db.query(query).bind(("id", order_id)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<SalesOrder>>().ok()?;
+    result.into_iter().next()
+}
+
+async fn get_delivery_note(db: &Surreal<Client>, note_id: &str) -> Option<DeliveryNote> {
+    let query = "SELECT * FROM type::thing('delivery_note', $id)";
+    let res = db.query(query).bind(("id", note_id)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<DeliveryNote>>().ok()?;
+    result.into_iter().next()
+}
+
+/// Marks a note as invoiced on `bill_id`, unless another bill got it first.
+async fn claim_delivery_note(
+    db: &Surreal<Client>,
+    note_id: &str,
+    bill_id: &str,
+) -> Result<bool, surrealdb::Error> {
+    let query = "UPDATE type::thing('delivery_note', $id) SET invoiced_bill_id = $bill_id WHERE invoiced_bill_id = NONE";
+    let res = db
+        .query(query)
+        .bind(("id", note_id))
+        .bind(("bill_id", bill_id))
+        .await?;
+    Ok(res
+        .get(0)
+        .and_then(|r| r.result::<Vec<serde_json::Value>>().ok())
+        .is_some_and(|rows| !rows.is_empty()))
+}
+
+async fn save_sales_order(
+    db: &Surreal<Client>,
+    order: &SalesOrder,
+) -> Result<(), surrealdb::Error> {
+    let query = "UPDATE type::thing('sales_order', $id) CONTENT $order";
+    db.query(query)
+        .bind(("id", &order.id))
+        .bind(("order", order))
+        .await?;
+    Ok(())
+}
+
+/// Stores a priced draft bill as a new order. Any coupon is used up now,
+/// since the order fixes the prices.
+pub async fn place_order(
+    db: &Surreal<Client>,
+    bill: Bill,
+    quotation_id: Option<String>,
+) -> Result<SalesOrder, HttpResponse> {
+    if let Some(code) = &bill.coupon_code {
+        match claim_coupon(db, code).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(
+                    HttpResponse::Conflict().body(format!("Coupon {} has been used up", code))
+                );
+            }
+            Err(e) => {
+                return Err(HttpResponse::InternalServerError()
+                    .body(format!("Failed to use coupon: {}", e)));
+            }
+        }
+    }
+    let order = SalesOrder {
+        id: uuid::Uuid::new_v4().to_string(),
+        customer_name: bill.customer_name,
+        lines: bill
+            .items
+            .into_iter()
+            .map(|item| SalesOrderLine {
+                item,
+                delivered_quantity: 0,
+            })
+            .collect(),
+        discount: bill.discount,
+        coupon_code: bill.coupon_code,
+        discounts: bill.discounts,
+        discount_amount: bill.discount_amount,
+        tax_amount: bill.tax_amount,
+        total_amount: bill.total_amount,
+        currency: bill.currency,
+        date: bill.date,
+        status: SalesOrderStatus::Open,
+        quotation_id,
+        created_at: Utc::now(),
+    };
+    if let Err(e) = db
+        .create::<_, SalesOrder>("sales_order")
+        .content(&order)
+        .await
+    {
+        if let Some(code) = &order.coupon_code {
+            let _ = release_coupon(db, code).await;
+        }
+        return Err(HttpResponse::InternalServerError()
+            .body(format!("Failed to create sales order: {}", e)));
+    }
+    Ok(order)
+}
+
+pub async fn create_sales_order(req: web::Json<CreateSalesOrderRequest>) -> impl Responder {
+    let db = get_db().await;
+    let mut bill = Bill::draft(
+        req.customer_name.clone(),
+        req.date.unwrap_or_else(today),
+        req.items.clone(),
+    );
+    bill.discount = req.discount;
+    bill.coupon_code = req.coupon_code.clone();
+    bill.currency = match req.currency.as_deref().map(normalize_currency) {
+        Some(Ok(currency)) => currency,
+        Some(Err(msg)) => return HttpResponse::BadRequest().body(msg),
+        None => base_currency(),
+    };
+    if let Err(response) = price_lines(db, &mut bill).await {
+        return response;
+    }
+    match place_order(db, bill, None).await {
+        Ok(order) => HttpResponse::Ok().json(order),
+        Err(response) => response,
+    }
+}
+
+pub async fn list_sales_orders(
+    params: web::Query<ListParams>,
+    filters: web::Query<SalesOrderFilters>,
+) -> impl Responder {
+    let db = get_db().await;
+    let mut query = ListQuery::new(
+        "sales_order",
+        &["customer_name"],
+        &["created_at", "date", "total_amount", "customer_name"],
+    );
+    query.filter("customer_name", "=", filters.customer_name.as_ref());
+    query.filter("status", "=", filters.status.as_ref());
+    match query.fetch::<SalesOrder>(db, &params).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list sales orders: {}", e))
+        }
+    }
+}
+
+pub async fn get_sales_order_by_id(path: web::Path<String>) -> impl Responder {
+    let db = get_db().await;
+    match get_sales_order(db, &path.into_inner()).await {
+        Some(order) => HttpResponse::Ok().json(order),
+        None => HttpResponse::NotFound().body("Sales order not found"),
+    }
+}
+
+/// Drops whatever has not shipped yet. Delivered goods can still be invoiced.
+pub async fn cancel_sales_order(req: web::Json<CancelSalesOrderRequest>) -> impl Responder {
+    let db = get_db().await;
+    let _guard = lock_stock().await;
+    let Some(mut order) = get_sales_order(db, &req.sales_order_id).await else {
+        return HttpResponse::NotFound().body("Sales order not found");
+    };
+    if matches!(
+        order.status,
+        SalesOrderStatus::Delivered | SalesOrderStatus::Cancelled
+    ) {
+        return HttpResponse::Conflict().body("Sales order is already closed");
+    }
+    if order.status == SalesOrderStatus::Open {
+        if let Some(code) = &order.coupon_code {
+            if let Err(e) = release_coupon(db, code).await {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Failed to release coupon: {}", e));
+            }
+        }
+    }
+    order.status = SalesOrderStatus::Cancelled;
+    if let Err(e) = save_sales_order(db, &order).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to cancel sales order: {}", e));
+    }
+    HttpResponse::Ok().json(order)
+}
+
+/// Ships part or all of what is outstanding on an order and takes the stock.
+pub async fn create_delivery_note(req: web::Json<CreateDeliveryNoteRequest>) -> impl Responder {
+    let db = get_db().await;
+    if req.lines.is_empty() {
+        return HttpResponse::BadRequest().body("A delivery note needs at least one line");
+    }
+    let _guard = lock_stock().await;
+    let Some(mut order) = get_sales_order(db, &req.sales_order_id).await else {
+        return HttpResponse::NotFound().body("Sales order not found");
+    };
+    if !matches!(
+        order.status,
+        SalesOrderStatus::Open | SalesOrderStatus::PartiallyDelivered
+    ) {
+        return HttpResponse::Conflict().body("Sales order is closed");
+    }
+
+    let mut shipping: HashMap<usize, i32> = HashMap::new();
+    let mut items = Vec::new();
+    for line in &req.lines {
+        let Some(order_line) = order.lines.get(line.line_index) else {
+            return HttpResponse::BadRequest()
+                .body(format!("Sales order has no line {}", line.line_index));
+        };
+        let shipped = shipping.entry(line.line_index).or_insert(0);
+        *shipped += line.quantity;
+        if line.quantity <= 0 || *shipped > order_line.outstanding() {
+            return HttpResponse::BadRequest().body(format!(
+                "Line {} has {} units outstanding",
+                line.line_index,
+                order_line.outstanding()
+            ));
+        }
+        // The order line's components are sized for the whole ordered quantity
+        let ordered = order_line.item.quantity;
+        items.push(BillItem {
+            quantity: line.quantity,
+            serials: line.serials.clone(),
+            lots: Vec::new(),
+            components: order_line
+                .item
+                .components
+                .iter()
+                .map(|component| BundleComponent {
+                    item_id: component.item_id.clone(),
+                    quantity: component.quantity / ordered * line.quantity,
+                })
+                .collect(),
+            cogs: 0.0,
+            ..order_line.item.clone()
+        });
+    }
+
+    // The stock side of a delivery is exactly that of a bill, so reuse it
+    let mut shipment = Bill::draft(order.customer_name.clone(), today(), items);
+    shipment.location = req.location.clone();
+    if let Err(response) = issue_stock(db, &mut shipment).await {
+        return response;
+    }
+
+    let note = DeliveryNote {
+        id: shipment.id.clone(),
+        sales_order_id: order.id.clone(),
+        customer_name: order.customer_name.clone(),
+        lines: req
+            .lines
+            .iter()
+            .zip(shipment.items.iter().cloned())
+            .map(|(line, item)| DeliveryLine {
+                line_index: line.line_index,
+                item_id: item.item_id,
+                quantity: item.quantity,
+                serials: item.serials,
+                lots: item.lots,
+                components: item.components,
+                cogs: item.cogs,
+            })
+            .collect(),
+        location: req.location.clone(),
+        delivered_at: Utc::now(),
+        invoiced_bill_id: None,
+    };
+    for (line_index, quantity) in shipping {
+        order.lines[line_index].delivered_quantity += quantity;
+    }
+    order.status = if order.lines.iter().all(|line| line.outstanding() == 0) {
+        SalesOrderStatus::Delivered
+    } else {
+        SalesOrderStatus::PartiallyDelivered
+    };
+    let query = "BEGIN TRANSACTION;
+        CREATE type::thing('delivery_note', $note_id) CONTENT $note;
+        UPDATE type::thing('sales_order', $order_id) CONTENT $order;
+        COMMIT TRANSACTION;";
+    if let Err(e) = db
+        .query(query)
+        .bind(("note_id", &note.id))
+        .bind(("note", &note))
+        .bind(("order_id", &order.id))
+        .bind(("order", &order))
+        .await
+    {
+        // Nothing was stored, so put back what was taken for it
+        if let Err(e) = return_bill_stock(db, &shipment, &shipment.id).await {
+            eprintln!(
+                "Failed to return stock for delivery note {}: {}",
+                note.id, e
+            );
+        }
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to create delivery note: {}", e));
+    }
+    HttpResponse::Ok().json(note)
+}
+
+pub async fn list_delivery_notes(
+    params: web::Query<ListParams>,
+    filters: web::Query<DeliveryNoteFilters>,
+) -> impl Responder {
+    let db = get_db().await;
+    let mut query = ListQuery::new(
+        "delivery_note",
+        &["customer_name"],
+        &["delivered_at", "customer_name"],
+    );
+    query.filter("sales_order_id", "=", filters.sales_order_id.as_ref());
+    query.filter("customer_name", "=", filters.customer_name.as_ref());
+    match query.fetch::<DeliveryNote>(db, &params).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to list delivery notes: {}", e)),
+    }
+}
+
+/// Bills one or more delivery notes of the same customer at the prices
+/// agreed on their sales orders.
+pub async fn invoice_delivery_notes(req: web::Json<InvoiceDeliveriesRequest>) -> impl Responder {
+    let db = get_db().await;
+    if req.delivery_note_ids.is_empty() {
+        return HttpResponse::BadRequest().body("No delivery notes given");
+    }
+    let _guard = lock_stock().await;
+    let mut notes = Vec::new();
+    let mut seen = HashSet::new();
+    for note_id in &req.delivery_note_ids {
+        if !seen.insert(note_id) {
+            return HttpResponse::BadRequest().body(format!(
+                "Delivery note {} is listed more than once",
+                note_id
+            ));
+        }
+        let Some(note) = get_delivery_note(db, note_id).await else {
+            return HttpResponse::NotFound().body(format!("Delivery note {} not found", note_id));
+        };
+        if note.invoiced_bill_id.is_some() {
+            return HttpResponse::Conflict().body(format!(
+                "Delivery note {} has already been invoiced",
+                note_id
+            ));
+        }
+        if notes
+            .first()
+            .is_some_and(|first: &DeliveryNote| first.customer_name != note.customer_name)
+        {
+            return HttpResponse::BadRequest()
+                .body("Delivery notes on one bill must be for the same customer");
+        }
+        notes.push(note);
+    }
+
+    let mut orders: HashMap<String, SalesOrder> = HashMap::new();
+    let mut items = Vec::new();
+    let mut discounts = Vec::new();
+    for note in &notes {
+        if !orders.contains_key(&note.sales_order_id) {
+            let Some(order) = get_sales_order(db, &note.sales_order_id).await else {
+                return HttpResponse::NotFound()
+                    .body(format!("Sales order {} not found", note.sales_order_id));
+            };
+            if orders
+                .values()
+                .next()
+                .is_some_and(|first| first.currency != order.currency)
+            {
+                return HttpResponse::BadRequest()
+                    .body("Delivery notes on one bill must be for orders in the same currency");
+            }
+            orders.insert(order.id.clone(), order);
+        }
+        let order = &orders[&note.sales_order_id];
+        for line in &note.lines {
+            let ordered = &order.lines[line.line_index].item;
+            // The order's discounts are shared out over what ships
+            let discount_amount = round_cents(
+                ordered.discount_amount * line.quantity as f64 / ordered.quantity as f64,
+            );
+            let net = ordered.price * line.quantity as f64 - discount_amount;
+            if discount_amount > 0.0 {
+                discounts.push(AppliedDiscount {
+                    description: format!("Sales order {} discounts", order.id),
+                    promotion_id: None,
+                    coupon_code: None,
+                    line_index: Some(items.len()),
+                    amount: discount_amount,
+                });
+            }
+            items.push(BillItem {
+                quantity: line.quantity,
+                discount: None,
+                discount_amount,
+                tax_amount: line_tax(net, ordered.tax_rate),
+                lots: line.lots.clone(),
+                serials: line.serials.clone(),
+                components: line.components.clone(),
+                cogs: line.cogs,
+                ..ordered.clone()
+            });
+        }
+    }
+
+    let date = req.date.unwrap_or_else(today);
+    let mut bill = Bill::draft(notes[0].customer_name.clone(), date, items);
+    bill.currency = orders[&notes[0].sales_order_id].currency.clone();
+    bill.exchange_rate = match rate_on(db, &bill.currency, date).await {
+        Ok(Some(rate)) => rate,
+        Ok(None) => {
+            return HttpResponse::BadRequest().body(format!(
+                "No exchange rate for {} on {}",
+                bill.currency, date
+            ));
+        }
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to load exchange rate: {}", e));
+        }
+    };
+    bill.branch = req.branch.clone();
+    bill.invoice_series_id = req.invoice_series_id.clone();
+    bill.delivery_note_ids = req.delivery_note_ids.clone();
+    bill.discounts = discounts;
+    bill.discount_amount = bill.discounts.iter().map(|d| d.amount).sum();
+    bill.tax_amount = bill.items.iter().map(|item| item.tax_amount).sum();
+    bill.total_amount = bill
+        .items
+        .iter()
+        .map(|item| item.net_amount() + item.tax_amount)
+        .sum();
+    bill.convert_totals();
+
+    // Claim the notes before issuing so no other bill can invoice them too
+    for note in &notes {
+        let failure = match claim_delivery_note(db, &note.id, &bill.id).await {
+            Ok(true) => continue,
+            Ok(false) => HttpResponse::Conflict().body(format!(
+                "Delivery note {} has already been invoiced",
+                note.id
+            )),
+            Err(e) => HttpResponse::InternalServerError()
+                .body(format!("Failed to update delivery note: {}", e)),
+        };
+        let _ = release_delivery_notes(db, &bill.id).await;
+        return failure;
+    }
+    match issue_delivered_bill(db, bill.clone()).await {
+        Ok(bill) => HttpResponse::Ok().json(bill),
+        Err(response) => {
+            if let Err(e) = release_delivery_notes(db, &bill.id).await {
+                eprintln!("Failed to release delivery notes of bill {}: {e}", bill.id);
+            }
+            response
+        }
+    }
+}
+
+/// Order lines still waiting to ship, oldest order first.
+pub async fn list_backorders() -> impl Responder {
+    let db = get_db().await;
+    let query = "SELECT * FROM sales_order WHERE status IN $statuses ORDER BY created_at ASC";
+    let orders = match db
+        .query(query)
+        .bind((
+            "statuses",
+            [SalesOrderStatus::Open, SalesOrderStatus::PartiallyDelivered],
+        ))
+        .await
+    {
+        Ok(res) => res
+            .get(0)
+            .and_then(|r| r.result::<Vec<SalesOrder>>().ok())
+            .unwrap_or_default(),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to list sales orders: {}", e));
+        }
+    };
+    let reserved = match reserved_by_item(db).await {
+        Ok(reserved) => reserved,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to load reservations: {}", e));
+        }
+    };
+
+    let mut backorders = Vec::new();
+    for order in orders {
+        for (line_index, line) in order.lines.iter().enumerate() {
+            if line.outstanding() <= 0 {
+                continue;
+            }
+            let Some(item) = get_item(db, &line.item.item_id).await else {
+                continue;
+            };
+            backorders.push(Backorder {
+                sales_order_id: order.id.clone(),
+                customer_name: order.customer_name.clone(),
+                line_index,
+                item_id: item.id.clone(),
+                available: (item.quantity - reserved.get(&item.id).copied().unwrap_or(0)).max(0),
+                item_name: item.name,
+                ordered: line.item.quantity,
+                delivered: line.delivered_quantity,
+                outstanding: line.outstanding(),
+            });
+        }
+    }
+    HttpResponse::Ok().json(backorders)
+}
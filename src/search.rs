@@ -0,0 +1,234 @@
+use crate::db::DB;
+use crate::inventory::InventoryItem;
+use actix_web::{HttpResponse, Responder, web};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+use tokio::sync::RwLock;
+
+// Items changed outside this process show up after at most this long
+const REFRESH_AFTER: Duration = Duration::from_secs(60);
+const DEFAULT_LIMIT: usize = 10;
+
+const SKU_WEIGHT: f64 = 4.0;
+const NAME_WEIGHT: f64 = 2.0;
+const DESCRIPTION_WEIGHT: f64 = 1.0;
+
+static INDEX: Lazy<RwLock<SearchIndex>> = Lazy::new(|| RwLock::new(SearchIndex::default()));
+
+struct IndexedItem {
+    name: String,
+    sku: Option<String>,
+    price: f64,
+    // (term, field weight)
+    terms: Vec<(String, f64)>,
+}
+
+#[derive(Default)]
+struct SearchIndex {
+    items: HashMap<String, IndexedItem>,
+    built_at: Option<Instant>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct SearchHit {
+    pub item_id: String,
+    pub name: String,
+    pub sku: Option<String>,
+    pub price: f64,
+    pub score: f64,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// How well one query token matches one indexed term: exact beats prefix
+/// beats a near miss of the whole term, which beats a near miss of its start.
+/// Longer tokens are allowed more typos.
+fn token_score(token: &str, term: &str) -> f64 {
+    if token == term {
+        return 1.0;
+    }
+    if term.starts_with(token) {
+        return 0.8;
+    }
+    let token_len = token.chars().count();
+    let allowed = match token_len {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    };
+    if allowed == 0 {
+        return 0.0;
+    }
+    let term_len = term.chars().count();
+    if token_len.abs_diff(term_len) <= allowed {
+        let distance = levenshtein(token, term);
+        if distance <= allowed {
+            return 0.6 - 0.15 * distance as f64;
+        }
+    }
+    // A misspelt start of a longer term, e.g. "choclat" for "chocolate"
+    let longest = (token_len + allowed).min(term_len.saturating_sub(1));
+    let distance = (token_len - allowed..=longest)
+        .map(|len| levenshtein(token, &term.chars().take(len).collect::<String>()))
+        .min();
+    match distance {
+        Some(distance) if distance <= allowed => 0.5 - 0.15 * distance as f64,
+        _ => 0.0,
+    }
+}
+
+impl IndexedItem {
+    fn from_item(item: &InventoryItem) -> Self {
+        let mut terms = Vec::new();
+        if let Some(sku) = &item.sku {
+            terms.push((sku.to_lowercase(), SKU_WEIGHT));
+            terms.extend(tokenize(sku).into_iter().map(|t| (t, SKU_WEIGHT)));
+        }
+        terms.extend(tokenize(&item.name).into_iter().map(|t| (t, NAME_WEIGHT)));
+        if let Some(description) = &item.description {
+            terms.extend(
+                tokenize(description)
+                    .into_iter()
+                    .map(|t| (t, DESCRIPTION_WEIGHT)),
+            );
+        }
+        IndexedItem {
+            name: item.name.clone(),
+            sku: item.sku.clone(),
+            price: item.price,
+            terms,
+        }
+    }
+
+    /// Every query token has to match something; the item's score is the sum
+    /// of each token's best weighted match.
+    fn score(&self, tokens: &[String]) -> Option<f64> {
+        let mut total = 0.0;
+        for token in tokens {
+            let best = self
+                .terms
+                .iter()
+                .map(|(term, weight)| token_score(token, term) * weight)
+                .fold(0.0, f64::max);
+            if best == 0.0 {
+                return None;
+            }
+            total += best;
+        }
+        Some(total)
+    }
+}
+
+async fn rebuild(db: &Surreal<Client>) -> Result<(), surrealdb::Error> {
+    let res = db.query("SELECT * FROM inventory").await?;
+    let items = res
+        .get(0)
+        .and_then(|r| r.result::<Vec<InventoryItem>>().ok())
+        .unwrap_or_default();
+    let mut index = INDEX.write().await;
+    index.items = items
+        .iter()
+        .map(|item| (item.id.clone(), IndexedItem::from_item(item)))
+        .collect();
+    index.built_at = Some(Instant::now());
+    Ok(())
+}
+
+/// Adds or refreshes one item so it is searchable straight away.
+pub async fn index_item(item: &InventoryItem) {
+    let mut index = INDEX.write().await;
+    index
+        .items
+        .insert(item.id.clone(), IndexedItem::from_item(item));
+}
+
+pub async fn remove_item(item_id: &str) {
+    INDEX.write().await.items.remove(item_id);
+}
+
+pub async fn search_items(query: web::Query<SearchQuery>) -> impl Responder {
+    let tokens = tokenize(&query.q);
+    if tokens.is_empty() {
+        return HttpResponse::Ok().json(Vec::<SearchHit>::new());
+    }
+    let stale = INDEX
+        .read()
+        .await
+        .built_at
+        .is_none_or(|built_at| built_at.elapsed() > REFRESH_AFTER);
+    if stale {
+        let db = get_db().await;
+        if let Err(e) = rebuild(db).await {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to build search index: {}", e));
+        }
+    }
+
+    let index = INDEX.read().await;
+    let mut hits: Vec<SearchHit> = index
+        .items
+        .iter()
+        .filter_map(|(item_id, item)| {
+            item.score(&tokens).map(|score| SearchHit {
+                item_id: item_id.clone(),
+                name: item.name.clone(),
+                sku: item.sku.clone(),
+                price: item.price,
+                score,
+            })
+        })
+        .collect();
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.name.cmp(&b.name)));
+    hits.truncate(query.limit.unwrap_or(DEFAULT_LIMIT));
+    HttpResponse::Ok().json(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn misspelt_start_of_a_longer_word_matches() {
+        assert!(token_score("choclat", "chocolate") > 0.0);
+        assert!(token_score("choclat", "chocolate") < token_score("chocolat", "chocolate"));
+    }
+
+    #[test]
+    fn short_tokens_need_an_exact_or_prefix_match() {
+        assert_eq!(token_score("mlk", "milk"), 0.0);
+        assert_eq!(token_score("mil", "milk"), 0.8);
+    }
+}
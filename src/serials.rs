@@ -0,0 +1,338 @@
+use crate::billing::get_bill;
+use crate::costing::record_receipt;
+use crate::db::DB;
+use crate::inventory::{InventoryItem, adjust_quantity, book_damaged, get_item};
+use crate::reservations::lock_stock;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SerialStatus {
+    InStock,
+    Sold,
+    Returned,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SerialEvent {
+    pub event: String, // "received", "sold" or "returned"
+    #[serde(with = "crate::dates::datetime")]
+    pub at: DateTime<Utc>,
+    pub reference: Option<String>,
+    pub customer_name: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SerialNumber {
+    pub id: String, // the serial number itself
+    pub item_id: String,
+    pub status: SerialStatus,
+    pub bill_id: Option<String>,
+    pub customer_name: Option<String>,
+    pub history: Vec<SerialEvent>,
+}
+
+#[derive(Deserialize)]
+pub struct ReceiveSerialsRequest {
+    pub item_id: String,
+    pub serials: Vec<String>,
+    pub reference: Option<String>,
+    pub location: Option<String>,
+    pub unit_cost: Option<f64>,
+}
+
+#[derive(Deserialize)]
+pub struct ReturnSerialRequest {
+    pub serial: String,
+    pub reference: Option<String>,
+    pub restock: bool,
+    pub location: Option<String>,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+pub async fn get_serial(db: &Surreal<Client>, serial: &str) -> Option<SerialNumber> {
+    let query = "SELECT * FROM type::thing('serial', $serial)";
+    let res = db.query(query).bind(("serial", serial)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<SerialNumber>>().ok()?;
+    result.into_iter().next()
+}
+
+/// Checks that the serials on a bill line can be sold for the given item.
+/// Returns a description of the first problem found.
+pub async fn validate_for_sale(
+    db: &Surreal<Client>,
+    item_id: &str,
+    quantity: i32,
+    serials: &[String],
+) -> Result<(), String> {
+    if serials.len() != quantity as usize {
+        return Err(format!(
+            "Item {} needs exactly {} serial numbers",
+            item_id, quantity
+        ));
+    }
+    let unique: HashSet<&String> = serials.iter().collect();
+    if unique.len() != serials.len() {
+        return Err("Duplicate serial numbers on bill line".to_string());
+    }
+    for serial in serials {
+        match get_serial(db, serial).await {
+            Some(s) if s.item_id != item_id => {
+                return Err(format!("Serial {} belongs to a different item", serial));
+            }
+            Some(s) if s.status != SerialStatus::InStock => {
+                return Err(format!("Serial {} is not in stock", serial));
+            }
+            Some(_) => {}
+            None => return Err(format!("Serial {} not found", serial)),
+        }
+    }
+    Ok(())
+}
+
+async fn push_event(
+    db: &Surreal<Client>,
+    serial: &str,
+    status: SerialStatus,
+    bill_id: Option<&str>,
+    customer_name: Option<&str>,
+    event: SerialEvent,
+) -> Result<(), surrealdb::Error> {
+    let query = "UPDATE type::thing('serial', $serial) SET status = $status, bill_id = $bill_id, customer_name = $customer_name, history += $event";
+    db.query(query)
+        .bind(("serial", serial))
+        .bind(("status", status))
+        .bind(("bill_id", bill_id))
+        .bind(("customer_name", customer_name))
+        .bind(("event", event))
+        .await?;
+    Ok(())
+}
+
+pub async fn mark_sold(
+    db: &Surreal<Client>,
+    serials: &[String],
+    bill_id: &str,
+    customer_name: &str,
+) -> Result<(), surrealdb::Error> {
+    for serial in serials {
+        let event = SerialEvent {
+            event: "sold".to_string(),
+            at: Utc::now(),
+            reference: Some(bill_id.to_string()),
+            customer_name: Some(customer_name.to_string()),
+        };
+        push_event(
+            db,
+            serial,
+            SerialStatus::Sold,
+            Some(bill_id),
+            Some(customer_name),
+            event,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Flags a serial as back from its customer: in stock again when restocked,
+/// otherwise returned. Stock and cost are booked by `return_serials`.
+async fn record_return(
+    db: &Surreal<Client>,
+    serial: &SerialNumber,
+    reference: &str,
+    restock: bool,
+) -> Result<(), surrealdb::Error> {
+    let event = SerialEvent {
+        event: "returned".to_string(),
+        at: Utc::now(),
+        reference: Some(reference.to_string()),
+        customer_name: serial.customer_name.clone(),
+    };
+    let status = if restock {
+        SerialStatus::InStock
+    } else {
+        SerialStatus::Returned
+    };
+    push_event(db, &serial.id, status, None, None, event).await
+}
+
+/// Takes sold serials of one item back. Restocked units are costed in at
+/// `unit_cost` and become sellable at `location`; the rest are booked as
+/// damaged. Credit notes, voids and standalone serial returns all come
+/// through here.
+pub async fn return_serials(
+    db: &Surreal<Client>,
+    item: &InventoryItem,
+    serials: &[SerialNumber],
+    unit_cost: f64,
+    restock: bool,
+    location: Option<&str>,
+    reference: &str,
+) -> Result<(), surrealdb::Error> {
+    let quantity = serials.len() as i32;
+    if restock {
+        record_receipt(db, item, quantity, unit_cost, reference).await?;
+        adjust_quantity(db, &item.id, location, quantity, "return", reference).await?;
+    } else {
+        book_damaged(db, &item.id, quantity, reference).await?;
+    }
+    for serial in serials {
+        record_return(db, serial, reference, restock).await?;
+    }
+    Ok(())
+}
+
+/// What a sold serial cost when it left, from the bill it was sold on;
+/// falls back to the item's average cost.
+async fn sold_unit_cost(db: &Surreal<Client>, serial: &SerialNumber, item: &InventoryItem) -> f64 {
+    let Some(bill_id) = &serial.bill_id else {
+        return item.average_cost;
+    };
+    get_bill(db, bill_id)
+        .await
+        .and_then(|bill| {
+            bill.items
+                .into_iter()
+                .find(|line| line.serials.contains(&serial.id))
+        })
+        .filter(|line| line.quantity > 0)
+        .map_or(item.average_cost, |line| line.cogs / line.quantity as f64)
+}
+
+/// Checks that serials about to be received are unique and not yet known.
+pub async fn check_new_serials(db: &Surreal<Client>, serials: &[String]) -> Result<(), String> {
+    if serials.is_empty() {
+        return Err("No serial numbers given".to_string());
+    }
+    let unique: HashSet<&String> = serials.iter().collect();
+    if unique.len() != serials.len() {
+        return Err("Duplicate serial numbers in request".to_string());
+    }
+    for serial in serials {
+        if get_serial(db, serial).await.is_some() {
+            return Err(format!("Serial {} is already registered", serial));
+        }
+    }
+    Ok(())
+}
+
+/// Registers received serials as in stock and books them into the item's quantity.
+pub async fn register_serials(
+    db: &Surreal<Client>,
+    item_id: &str,
+    serials: &[String],
+    reference: Option<&str>,
+    location: Option<&str>,
+) -> Result<Vec<SerialNumber>, surrealdb::Error> {
+    let mut received = Vec::new();
+    for serial in serials {
+        let record = SerialNumber {
+            id: serial.clone(),
+            item_id: item_id.to_string(),
+            status: SerialStatus::InStock,
+            bill_id: None,
+            customer_name: None,
+            history: vec![SerialEvent {
+                event: "received".to_string(),
+                at: Utc::now(),
+                reference: reference.map(str::to_string),
+                customer_name: None,
+            }],
+        };
+        db.create::<_, SerialNumber>("serial")
+            .content(&record)
+            .await?;
+        received.push(record);
+    }
+    adjust_quantity(
+        db,
+        item_id,
+        location,
+        received.len() as i32,
+        "receipt",
+        reference.unwrap_or_default(),
+    )
+    .await?;
+    Ok(received)
+}
+
+pub async fn receive_serials(req: web::Json<ReceiveSerialsRequest>) -> impl Responder {
+    let db = get_db().await;
+    let _guard = lock_stock().await;
+    let item = match get_item(db, &req.item_id).await {
+        Some(item) if item.track_serials => item,
+        Some(_) => return HttpResponse::BadRequest().body("Item is not serial-tracked"),
+        None => return HttpResponse::NotFound().body("Item not found"),
+    };
+    if let Err(msg) = check_new_serials(db, &req.serials).await {
+        return HttpResponse::BadRequest().body(msg);
+    }
+    let unit_cost = req.unit_cost.unwrap_or(item.average_cost);
+    let reference = req.reference.as_deref().unwrap_or("serial receipt");
+    if let Err(e) = record_receipt(db, &item, req.serials.len() as i32, unit_cost, reference).await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to record serial cost: {}", e));
+    }
+    match register_serials(
+        db,
+        &req.item_id,
+        &req.serials,
+        req.reference.as_deref(),
+        req.location.as_deref(),
+    )
+    .await
+    {
+        Ok(received) => HttpResponse::Ok().json(received),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to register serials: {}", e))
+        }
+    }
+}
+
+pub async fn return_serial(req: web::Json<ReturnSerialRequest>) -> impl Responder {
+    let db = get_db().await;
+    let _guard = lock_stock().await;
+    let Some(serial) = get_serial(db, &req.serial).await else {
+        return HttpResponse::NotFound().body("Serial not found");
+    };
+    if serial.status != SerialStatus::Sold {
+        return HttpResponse::BadRequest().body("Only sold serials can be returned");
+    }
+    let Some(item) = get_item(db, &serial.item_id).await else {
+        return HttpResponse::NotFound().body("Item not found");
+    };
+    let unit_cost = sold_unit_cost(db, &serial, &item).await;
+    let reference = req.reference.clone().unwrap_or_else(|| serial.id.clone());
+    if let Err(e) = return_serials(
+        db,
+        &item,
+        std::slice::from_ref(&serial),
+        unit_cost,
+        req.restock,
+        req.location.as_deref(),
+        &reference,
+    )
+    .await
+    {
+        return HttpResponse::InternalServerError().body(format!("Failed to record return: {}", e));
+    }
+    HttpResponse::Ok().body("Return recorded successfully")
+}
+
+pub async fn trace_serial(path: web::Path<String>) -> impl Responder {
+    let db = get_db().await;
+    match get_serial(db, &path.into_inner()).await {
+        Some(serial) => HttpResponse::Ok().json(serial),
+        None => HttpResponse::NotFound().body("Serial not found"),
+    }
+}
@@ -0,0 +1,323 @@
+use crate::auth::verify_master_password;
+use crate::costing::{record_issue, record_receipt};
+use crate::dates::today;
+use crate::db::DB;
+use crate::inventory::{
+    InventoryItem, adjust_quantity, damaged_location, get_item, get_location_quantity,
+};
+use crate::ledger::post_entry;
+use crate::reservations::lock_stock;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StockCountStatus {
+    Open,
+    Posted,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StockCountLine {
+    pub item_id: String,
+    pub item_name: String,
+    pub expected_quantity: i32,
+    pub counted_quantity: Option<i32>,
+    pub variance: Option<i32>,
+    pub counted_by: Option<String>,
+}
+
+/// A counting session. Without a category or location it is a full count;
+/// otherwise it is a cycle count limited to that category and/or location.
+#[derive(Serialize, Deserialize)]
+pub struct StockCount {
+    pub id: String,
+    pub category: Option<String>,
+    pub location: Option<String>,
+    pub status: StockCountStatus,
+    pub lines: Vec<StockCountLine>,
+    #[serde(with = "crate::dates::datetime")]
+    pub created_at: DateTime<Utc>,
+    pub approved_by: Option<String>,
+    #[serde(default, with = "crate::dates::datetime::option")]
+    pub posted_at: Option<DateTime<Utc>>,
+    pub write_off_value: Option<f64>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateStockCountRequest {
+    pub category: Option<String>,
+    pub location: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CountedQuantity {
+    pub item_id: String,
+    pub counted_quantity: i32,
+}
+
+#[derive(Deserialize)]
+pub struct SubmitCountsRequest {
+    pub stock_count_id: String,
+    pub counted_by: String,
+    pub counts: Vec<CountedQuantity>,
+}
+
+#[derive(Deserialize)]
+pub struct PostStockCountRequest {
+    pub stock_count_id: String,
+    pub approved_by: String,
+    pub master_password: String,
+}
+
+async fn get_db() -> &'static Surreal<Client> {
+    DB.get().expect("DB not initialized")
+}
+
+async fn get_stock_count(db: &Surreal<Client>, count_id: &str) -> Option<StockCount> {
+    let query = "SELECT * FROM type::thing('stock_count', $id)";
+    let res = db.query(query).bind(("id", count_id)).await.ok()?;
+    let result = res.get(0)?.result::<Vec<StockCount>>().ok()?;
+    result.into_iter().next()
+}
+
+async fn save_stock_count(
+    db: &Surreal<Client>,
+    count: &StockCount,
+) -> Result<(), surrealdb::Error> {
+    let query = "UPDATE type::thing('stock_count', $id) CONTENT $count";
+    db.query(query)
+        .bind(("id", &count.id))
+        .bind(("count", count))
+        .await?;
+    Ok(())
+}
+
+/// Quantity the system currently believes is on hand for the count's scope.
+async fn book_quantity(db: &Surreal<Client>, item: &InventoryItem, location: Option<&str>) -> i32 {
+    match location {
+        Some(location) => get_location_quantity(db, &item.id, location)
+            .await
+            .unwrap_or(0),
+        None => item.quantity,
+    }
+}
+
+pub async fn create_stock_count(req: web::Json<CreateStockCountRequest>) -> impl Responder {
+    let db = get_db().await;
+    if req.location.as_deref() == Some(damaged_location().as_str()) {
+        return HttpResponse::BadRequest().body("Damaged goods are not part of sellable stock");
+    }
+    let res = match &req.category {
+        Some(category) => {
+            db.query("SELECT * FROM inventory WHERE category = $category")
+                .bind(("category", category))
+                .await
+        }
+        None => db.query("SELECT * FROM inventory").await,
+    };
+    let items = match res {
+        Ok(res) => res
+            .get(0)
+            .and_then(|r| r.result::<Vec<InventoryItem>>().ok())
+            .unwrap_or_default(),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to list items: {}", e));
+        }
+    };
+
+    let mut lines = Vec::new();
+    // Lot and serial stock is adjusted per lot or serial, and bundles hold no
+    // stock, so a quantity count can't cover them
+    for item in items
+        .into_iter()
+        .filter(|item| !(item.track_lots || item.track_serials || item.is_bundle()))
+    {
+        let expected_quantity = book_quantity(db, &item, req.location.as_deref()).await;
+        lines.push(StockCountLine {
+            item_id: item.id,
+            item_name: item.name,
+            expected_quantity,
+            counted_quantity: None,
+            variance: None,
+            counted_by: None,
+        });
+    }
+    let count = StockCount {
+        id: uuid::Uuid::new_v4().to_string(),
+        category: req.category.clone(),
+        location: req.location.clone(),
+        status: StockCountStatus::Open,
+        lines,
+        created_at: Utc::now(),
+        approved_by: None,
+        posted_at: None,
+        write_off_value: None,
+    };
+    if let Err(e) = db
+        .create::<_, StockCount>("stock_count")
+        .content(&count)
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to create stock count: {}", e));
+    }
+    HttpResponse::Ok().json(count)
+}
+
+/// Records counted quantities. Counters may submit in several batches and
+/// recount an item. The book quantity is frozen when an item is counted, so
+/// stock that moves before posting is not mistaken for a variance.
+pub async fn submit_counts(req: web::Json<SubmitCountsRequest>) -> impl Responder {
+    let db = get_db().await;
+    let _guard = lock_stock().await;
+    let Some(mut count) = get_stock_count(db, &req.stock_count_id).await else {
+        return HttpResponse::NotFound().body("Stock count not found");
+    };
+    if count.status != StockCountStatus::Open {
+        return HttpResponse::BadRequest().body("Stock count has already been posted");
+    }
+    for counted in &req.counts {
+        if counted.counted_quantity < 0 {
+            return HttpResponse::BadRequest().body("Counted quantities cannot be negative");
+        }
+        let Some(line) = count
+            .lines
+            .iter_mut()
+            .find(|line| line.item_id == counted.item_id)
+        else {
+            return HttpResponse::BadRequest().body(format!(
+                "Item {} is not part of this count",
+                counted.item_id
+            ));
+        };
+        let Some(item) = get_item(db, &counted.item_id).await else {
+            return HttpResponse::BadRequest().body(format!("Item {} not found", counted.item_id));
+        };
+        line.expected_quantity = book_quantity(db, &item, count.location.as_deref()).await;
+        line.counted_quantity = Some(counted.counted_quantity);
+        line.variance = Some(counted.counted_quantity - line.expected_quantity);
+        line.counted_by = Some(req.counted_by.clone());
+    }
+    if let Err(e) = save_stock_count(db, &count).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to save stock count: {}", e));
+    }
+    HttpResponse::Ok().json(count)
+}
+
+pub async fn get_stock_count_by_id(path: web::Path<String>) -> impl Responder {
+    let db = get_db().await;
+    match get_stock_count(db, &path.into_inner()).await {
+        Some(count) => HttpResponse::Ok().json(count),
+        None => HttpResponse::NotFound().body("Stock count not found"),
+    }
+}
+
+pub async fn list_stock_counts() -> impl Responder {
+    let db = get_db().await;
+    let query = "SELECT * FROM stock_count ORDER BY created_at DESC";
+    match db.query(query).await {
+        Ok(res) => {
+            let counts = res
+                .get(0)
+                .and_then(|r| r.result::<Vec<StockCount>>().ok())
+                .unwrap_or_default();
+            HttpResponse::Ok().json(counts)
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list stock counts: {}", e))
+        }
+    }
+}
+
+/// Approves a count: every counted variance becomes a stock movement on top
+/// of the current stock and the net value lost (or found) is written off in
+/// the ledger.
+pub async fn post_stock_count(req: web::Json<PostStockCountRequest>) -> impl Responder {
+    if !verify_master_password(&req.master_password) {
+        return HttpResponse::Unauthorized().body("Invalid master password");
+    }
+    let db = get_db().await;
+    let _guard = lock_stock().await;
+    let Some(mut count) = get_stock_count(db, &req.stock_count_id).await else {
+        return HttpResponse::NotFound().body("Stock count not found");
+    };
+    if count.status != StockCountStatus::Open {
+        return HttpResponse::BadRequest().body("Stock count has already been posted");
+    }
+
+    let mut write_off_value = 0.0;
+    for line in &count.lines {
+        // Taken against the book quantity when the item was counted; sales
+        // and receipts since then stay on top of the adjusted stock
+        let Some(variance) = line.variance.filter(|variance| *variance != 0) else {
+            continue;
+        };
+        let Some(item) = get_item(db, &line.item_id).await else {
+            continue;
+        };
+        let costed = if variance < 0 {
+            record_issue(db, &item, -variance, &count.id).await
+        } else {
+            record_receipt(db, &item, variance, item.average_cost, &count.id)
+                .await
+                .map(|_| -(variance as f64 * item.average_cost))
+        };
+        match costed {
+            Ok(value) => write_off_value += value,
+            Err(e) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Failed to cost adjustment: {}", e));
+            }
+        }
+        if let Err(e) = adjust_quantity(
+            db,
+            &line.item_id,
+            count.location.as_deref(),
+            variance,
+            "stock_count",
+            &count.id,
+        )
+        .await
+        {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to adjust stock: {}", e));
+        }
+    }
+
+    if write_off_value.abs() > f64::EPSILON {
+        // A net loss is a debit write-off; a net surplus is credited back
+        let entry_type = if write_off_value > 0.0 {
+            "debit"
+        } else {
+            "credit"
+        };
+        if let Err(e) = post_entry(
+            db,
+            format!("Stock count {} write-off", count.id),
+            write_off_value.abs(),
+            today(),
+            entry_type,
+        )
+        .await
+        {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to post write-off: {}", e));
+        }
+    }
+
+    count.status = StockCountStatus::Posted;
+    count.approved_by = Some(req.approved_by.clone());
+    count.posted_at = Some(Utc::now());
+    count.write_off_value = Some(write_off_value);
+    if let Err(e) = save_stock_count(db, &count).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to save stock count: {}", e));
+    }
+    HttpResponse::Ok().json(count)
+}
@@ -0,0 +1,143 @@
+use async_trait::async_trait;
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::config::{Credentials, Region};
+use aws_sdk_s3::primitives::ByteStream;
+use once_cell::sync::OnceCell;
+use std::error::Error;
+use std::path::PathBuf;
+
+pub type StorageError = Box<dyn Error + Send + Sync>;
+
+static STORAGE: OnceCell<Box<dyn Storage>> = OnceCell::new();
+
+/// Where attachment bytes live. Keys are relative paths like `<id>/<file name>`.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, key: &str, data: &[u8], content_type: &str) -> Result<(), StorageError>;
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError>;
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+}
+
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalStorage { root: root.into() }
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: &[u8], _content_type: &str) -> Result<(), StorageError> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, data).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        Ok(tokio::fs::read(self.root.join(key)).await?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        tokio::fs::remove_file(self.root.join(key)).await?;
+        Ok(())
+    }
+}
+
+/// Any S3-compatible store (AWS, MinIO, ...). Path-style addressing is used
+/// so self-hosted endpoints work without wildcard DNS.
+pub struct S3Storage {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+}
+
+impl S3Storage {
+    /// Builds the client from the `S3_*` settings and checks the bucket can
+    /// be reached.
+    pub async fn from_env() -> Result<Self, StorageError> {
+        let bucket = std::env::var("S3_BUCKET").map_err(|_| "S3_BUCKET is not set")?;
+        let region = std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+        let mut loader =
+            aws_config::defaults(BehaviorVersion::latest()).region(Region::new(region));
+        // Without explicit keys the usual AWS credential chain applies
+        if let (Ok(access_key), Ok(secret_key)) = (
+            std::env::var("S3_ACCESS_KEY"),
+            std::env::var("S3_SECRET_KEY"),
+        ) {
+            loader = loader
+                .credentials_provider(Credentials::new(access_key, secret_key, None, None, "env"));
+        }
+        let mut config =
+            aws_sdk_s3::config::Builder::from(&loader.load().await).force_path_style(true);
+        if let Ok(endpoint) = std::env::var("S3_ENDPOINT") {
+            config = config.endpoint_url(endpoint);
+        }
+        let client = aws_sdk_s3::Client::from_conf(config.build());
+        client.head_bucket().bucket(&bucket).send().await?;
+        Ok(S3Storage { client, bucket })
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, data: &[u8], content_type: &str) -> Result<(), StorageError> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from(data.to_vec()))
+            .content_type(content_type)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        let object = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await?;
+        Ok(object.body.collect().await?.into_bytes().to_vec())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+/// Sets up the configured store: `ATTACHMENT_STORAGE=s3` uses the `S3_*`
+/// settings, anything else writes under `ATTACHMENT_DIR` (default
+/// `./attachments`). Called once at startup so bad settings stop the server
+/// there rather than failing the first upload.
+pub async fn init_storage() -> Result<(), StorageError> {
+    let storage: Box<dyn Storage> = match std::env::var("ATTACHMENT_STORAGE").as_deref() {
+        Ok("s3") => Box::new(S3Storage::from_env().await?),
+        _ => {
+            let root =
+                std::env::var("ATTACHMENT_DIR").unwrap_or_else(|_| "attachments".to_string());
+            Box::new(LocalStorage::new(root))
+        }
+    };
+    STORAGE
+        .set(storage)
+        .map_err(|_| "Storage is already initialized")?;
+    Ok(())
+}
+
+pub fn get_storage() -> &'static dyn Storage {
+    STORAGE.get().expect("Storage not initialized").as_ref()
+}